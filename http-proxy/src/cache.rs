@@ -0,0 +1,755 @@
+//! Shared response cache.
+//!
+//! Responses to `GET` requests are kept in memory for as long as their
+//! `Cache-Control` or `Expires` headers allow, see
+//! https://www.rfc-editor.org/rfc/rfc9111. Stale responses with an `ETag` or
+//! `Last-Modified` validator are revalidated with the upstream, responses
+//! with `Vary` are stored once per combination of the listed request headers.
+//! The least recently used responses are evicted once the cache is full.
+//! There is no disk store, cached responses are lost when the proxy exits.
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::Stream;
+use lru_cache::LruCache;
+use ntex::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use ntex::http::{error::PayloadError, Method, RequestHead, StatusCode, Uri};
+use ntex::util::{Bytes, BytesMut};
+use ntex::web::HttpResponse;
+use serde::Serialize;
+
+/// Statuses that may be stored without explicit freshness information.
+const CACHEABLE: &[StatusCode] = &[
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::MULTIPLE_CHOICES,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::NOT_FOUND,
+    StatusCode::GONE,
+];
+
+/// Headers of a cached response sent with `304 Not Modified`.
+const NOT_MODIFIED: &[HeaderName] = &[
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::DATE,
+    header::ETAG,
+    header::EXPIRES,
+    header::VARY,
+];
+
+/// `Cache-Control` directives, lowercase names with optional values.
+fn directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|d| {
+            let mut parts = d.splitn(2, '=');
+            let name = parts.next()?.trim().to_ascii_lowercase();
+            let value = parts.next().map(|v| v.trim().trim_matches('"').to_owned());
+            (!name.is_empty()).then_some((name, value))
+        })
+        .collect()
+}
+
+fn has_directive(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(n, _)| n == name)
+}
+
+fn seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives
+        .iter()
+        .find(|(n, _)| n == name)
+        .and_then(|(_, v)| v.as_ref()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+fn date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+/// What a request allows the cache to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestPolicy {
+    /// Request bypasses the cache.
+    Bypass,
+    /// Stored response may be used while fresh.
+    Use,
+    /// Stored response must be revalidated first.
+    Revalidate,
+}
+
+impl RequestPolicy {
+    pub fn new(head: &RequestHead) -> Self {
+        let headers = &head.headers;
+        let directives = directives(headers);
+        if head.method != Method::GET
+            || head.upgrade()
+            || headers.contains_key(header::AUTHORIZATION)
+            || has_directive(&directives, "no-store")
+        {
+            RequestPolicy::Bypass
+        } else if has_directive(&directives, "no-cache")
+            || seconds(&directives, "max-age") == Some(Duration::ZERO)
+            || headers
+                .get(header::PRAGMA)
+                .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+        {
+            RequestPolicy::Revalidate
+        } else {
+            RequestPolicy::Use
+        }
+    }
+}
+
+/// Freshness lifetime of a response, `None` if it must not be stored.
+pub fn lifetime(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    let directives = directives(headers);
+    if has_directive(&directives, "no-store")
+        || has_directive(&directives, "private")
+        || headers.contains_key(header::SET_COOKIE)
+        || headers
+            .get_all(header::VARY)
+            .any(|v| v.as_bytes().trim_ascii() == b"*")
+    {
+        return None;
+    }
+
+    let lifetime = if has_directive(&directives, "no-cache") {
+        Some(Duration::ZERO)
+    } else if let Some(secs) = seconds(&directives, "s-maxage") {
+        Some(secs)
+    } else if let Some(secs) = seconds(&directives, "max-age") {
+        Some(secs)
+    } else if headers.contains_key(header::EXPIRES) {
+        let now = date(headers, header::DATE).unwrap_or_else(SystemTime::now);
+        // invalid or past dates mean already expired
+        Some(
+            date(headers, header::EXPIRES)
+                .and_then(|expires| expires.duration_since(now).ok())
+                .unwrap_or_default(),
+        )
+    } else {
+        None
+    }?;
+
+    // responses that are stale right away are useful only with a validator
+    if (lifetime.is_zero()
+        && !headers.contains_key(header::ETAG)
+        && !headers.contains_key(header::LAST_MODIFIED))
+        || !CACHEABLE.contains(&status)
+    {
+        None
+    } else {
+        Some(lifetime)
+    }
+}
+
+/// Stored response.
+#[derive(Debug)]
+pub struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Values of request headers listed in `Vary`
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    stored: Instant,
+    /// Age of the response when it was stored
+    initial_age: Duration,
+    lifetime: Duration,
+}
+
+impl Entry {
+    fn new(status: StatusCode, mut headers: HeaderMap, req: &HeaderMap) -> Self {
+        // length is set again when the response is served
+        headers.remove(header::CONTENT_LENGTH);
+        let vary = headers
+            .get_all(header::VARY)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+            .map(|name| {
+                let value = req.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+        let lifetime = lifetime(status, &headers).unwrap_or_default();
+
+        Entry {
+            status,
+            vary,
+            lifetime,
+            body: Bytes::new(),
+            stored: Instant::now(),
+            initial_age: age(&headers),
+            headers,
+        }
+    }
+
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored.elapsed()
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.lifetime
+    }
+
+    fn matches(&self, req: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req.get(name) == value.as_ref())
+    }
+
+    /// Approximate memory used by the entry.
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(n, v)| n.as_str().len() + v.len())
+                .sum::<usize>()
+    }
+
+    /// Add validators of the entry to an upstream request.
+    pub fn add_validators(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            headers.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(modified) = self.headers.get(header::LAST_MODIFIED) {
+            headers.insert(header::IF_MODIFIED_SINCE, modified.clone());
+        }
+    }
+
+    /// Entry updated with headers of a `304 Not Modified` response.
+    pub fn refresh(&self, not_modified: &HeaderMap) -> Entry {
+        let mut headers = self.headers.clone();
+        for name in not_modified.keys() {
+            if name != header::CONTENT_LENGTH {
+                headers.remove(name);
+                for value in not_modified.get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        Entry {
+            status: self.status,
+            body: self.body.clone(),
+            vary: self.vary.clone(),
+            stored: Instant::now(),
+            initial_age: age(not_modified),
+            lifetime: lifetime(self.status, &headers).unwrap_or_default(),
+            headers,
+        }
+    }
+
+    /// Response for a request with `req` headers.
+    pub fn response(&self, req: &HeaderMap) -> HttpResponse {
+        let etag = self.etag();
+        let not_modified = req
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || Some(weak(tag)) == etag)
+            });
+
+        let mut res = if not_modified {
+            let mut res = HttpResponse::NotModified();
+            for name in NOT_MODIFIED {
+                for value in self.headers.get_all(name) {
+                    res.header(name.clone(), value.clone());
+                }
+            }
+            res
+        } else {
+            let mut res = HttpResponse::build(self.status);
+            for (name, value) in &self.headers {
+                res.header(name.clone(), value.clone());
+            }
+            res
+        };
+        res.set_header(header::AGE, self.age().as_secs());
+
+        if not_modified {
+            res.finish()
+        } else {
+            res.body(self.body.clone())
+        }
+    }
+
+    fn etag(&self) -> Option<&str> {
+        let etag = self.headers.get(header::ETAG)?.to_str().ok()?;
+        Some(weak(etag))
+    }
+}
+
+/// Entity tag for weak comparison.
+fn weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn age(headers: &HeaderMap) -> Duration {
+    headers
+        .get(header::AGE)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default()
+}
+
+/// Cache counters served by the admin endpoint.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    /// Requests served from the cache without contacting the upstream.
+    pub hits: u64,
+    /// Requests the cache had no response for.
+    pub misses: u64,
+    /// Stale responses the upstream confirmed as not modified.
+    pub revalidated: u64,
+    pub stored: u64,
+    pub evicted: u64,
+    pub entries: usize,
+    pub size: usize,
+    pub max_size: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    revalidated: AtomicU64,
+    stored: AtomicU64,
+    evicted: AtomicU64,
+}
+
+struct Inner {
+    /// Responses by request, one per `Vary` variant
+    entries: LruCache<String, Vec<Arc<Entry>>>,
+    size: usize,
+}
+
+/// In-memory LRU cache shared by all workers.
+pub struct Cache {
+    inner: Mutex<Inner>,
+    max_size: usize,
+    counters: Counters,
+}
+
+/// Result of a cache lookup.
+pub enum Lookup {
+    /// Response may be served as is.
+    Fresh(Arc<Entry>),
+    /// Response has to be revalidated with the upstream.
+    Stale(Arc<Entry>),
+    Miss,
+}
+
+impl Cache {
+    /// Cache holding up to `max_size` bytes of responses.
+    pub fn new(max_size: usize) -> Self {
+        Cache {
+            max_size,
+            inner: Mutex::new(Inner {
+                entries: LruCache::new(usize::MAX),
+                size: 0,
+            }),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Cache key of a request.
+    pub fn key(host: Option<&str>, uri: &Uri) -> String {
+        format!("{}{}", host.unwrap_or_default(), uri)
+    }
+
+    /// Find stored response for a request.
+    pub fn lookup(&self, key: &str, req: &HeaderMap, policy: RequestPolicy) -> Lookup {
+        let entry = {
+            let mut inner = self.inner.lock().unwrap();
+            inner
+                .entries
+                .get_mut(key)
+                .and_then(|variants| variants.iter().find(|e| e.matches(req)).cloned())
+        };
+
+        match entry {
+            Some(entry) if entry.is_fresh() && policy == RequestPolicy::Use => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                Lookup::Fresh(entry)
+            }
+            Some(entry) => Lookup::Stale(entry),
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                Lookup::Miss
+            }
+        }
+    }
+
+    /// Store entry refreshed by a `304 Not Modified` upstream response.
+    pub fn revalidated(&self, key: String, entry: Entry) -> Arc<Entry> {
+        self.counters.revalidated.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(entry);
+        self.insert(key, entry.clone());
+        entry
+    }
+
+    /// Stale entry could not be revalidated, the upstream sent a new response.
+    ///
+    /// The stale entry is dropped, so its validators are not sent again. The
+    /// new response takes its place once stored, if it may be.
+    pub fn replaced(&self, key: &str, stale: &Arc<Entry>) {
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { entries, size } = &mut *inner;
+        let Some(variants) = entries.get_mut(key) else {
+            return;
+        };
+        variants.retain(|e| {
+            if Arc::ptr_eq(e, stale) {
+                *size -= e.size();
+                false
+            } else {
+                true
+            }
+        });
+        if variants.is_empty() {
+            entries.remove(key);
+        }
+    }
+
+    fn insert(&self, key: String, entry: Arc<Entry>) {
+        let size = entry.size();
+        if size > self.max_size {
+            return;
+        }
+        self.counters.stored.fetch_add(1, Ordering::Relaxed);
+
+        let mut inner = self.inner.lock().unwrap();
+        let mut variants = inner.entries.remove(&key).unwrap_or_default();
+        variants.retain(|e| {
+            if e.vary == entry.vary {
+                inner.size -= e.size();
+                false
+            } else {
+                true
+            }
+        });
+        variants.push(entry);
+        inner.size += size;
+        inner.entries.insert(key, variants);
+
+        while inner.size > self.max_size {
+            match inner.entries.remove_lru() {
+                Some((_, variants)) => {
+                    self.counters
+                        .evicted
+                        .fetch_add(variants.len() as u64, Ordering::Relaxed);
+                    inner.size -= variants.iter().map(|e| e.size()).sum::<usize>();
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Pass response body through, storing it once complete.
+    pub fn store<S>(
+        self: &Arc<Self>,
+        key: String,
+        req: &HeaderMap,
+        status: StatusCode,
+        headers: HeaderMap,
+        stream: S,
+    ) -> Store<S> {
+        Store {
+            stream,
+            key,
+            cache: self.clone(),
+            body: Some(BytesMut::new()),
+            entry: Some(Entry::new(status, headers, req)),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = self.inner.lock().unwrap();
+        Stats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            revalidated: self.counters.revalidated.load(Ordering::Relaxed),
+            stored: self.counters.stored.load(Ordering::Relaxed),
+            evicted: self.counters.evicted.load(Ordering::Relaxed),
+            entries: inner.entries.iter().map(|(_, v)| v.len()).sum(),
+            size: inner.size,
+            max_size: self.max_size,
+        }
+    }
+}
+
+/// Response body stream that stores the body in the cache once complete.
+///
+/// Bodies bigger than the whole cache and failed transfers are not stored.
+pub struct Store<S> {
+    stream: S,
+    key: String,
+    cache: Arc<Cache>,
+    body: Option<BytesMut>,
+    entry: Option<Entry>,
+}
+
+impl<S> Stream for Store<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let item = Pin::new(&mut this.stream).poll_next(cx);
+        match item {
+            Poll::Ready(Some(Ok(ref chunk))) => {
+                if let Some(body) = this.body.as_mut() {
+                    if body.len() + chunk.len() > this.cache.max_size {
+                        this.body = None;
+                    } else {
+                        body.extend_from_slice(chunk);
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.body = None,
+            Poll::Ready(None) => {
+                if let (Some(body), Some(mut entry)) =
+                    (this.body.take(), this.entry.take())
+                {
+                    entry.body = body.freeze();
+                    this.cache
+                        .insert(std::mem::take(&mut this.key), Arc::new(entry));
+                }
+            }
+            Poll::Pending => (),
+        }
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, StreamExt};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    async fn store(cache: &Arc<Cache>, key: &str, req: &HeaderMap, res: HeaderMap) {
+        let body = stream::iter(vec![Ok(Bytes::from_static(b"hello"))]);
+        let mut store = cache.store(key.to_owned(), req, StatusCode::OK, res, body);
+        while store.next().await.is_some() {}
+    }
+
+    #[test]
+    fn test_request_policy() {
+        let policy = |method, pairs| {
+            let mut head = RequestHead::default();
+            head.method = method;
+            head.headers = headers(pairs);
+            RequestPolicy::new(&head)
+        };
+        let get = |pairs| policy(Method::GET, pairs);
+        assert_eq!(get(&[]), RequestPolicy::Use);
+        assert_eq!(get(&[("cache-control", "no-store")]), RequestPolicy::Bypass);
+        assert_eq!(
+            get(&[("authorization", "Basic Zm9v")]),
+            RequestPolicy::Bypass
+        );
+        assert_eq!(
+            get(&[("cache-control", "no-cache")]),
+            RequestPolicy::Revalidate
+        );
+        assert_eq!(
+            get(&[("cache-control", "max-age=0")]),
+            RequestPolicy::Revalidate
+        );
+        assert_eq!(get(&[("pragma", "no-cache")]), RequestPolicy::Revalidate);
+        assert_eq!(policy(Method::POST, &[]), RequestPolicy::Bypass);
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let ok = |pairs| lifetime(StatusCode::OK, &headers(pairs));
+        let secs = |s| Some(Duration::from_secs(s));
+
+        assert_eq!(ok(&[]), None);
+        assert_eq!(ok(&[("cache-control", "public, max-age=60")]), secs(60));
+        assert_eq!(
+            ok(&[("cache-control", "max-age=60, s-maxage=10")]),
+            secs(10)
+        );
+        assert_eq!(ok(&[("cache-control", "max-age=60, private")]), None);
+        assert_eq!(ok(&[("cache-control", "no-store")]), None);
+        assert_eq!(ok(&[("cache-control", "max-age=60"), ("vary", "*")]), None);
+        assert_eq!(
+            ok(&[("cache-control", "max-age=60"), ("set-cookie", "a=b")]),
+            None
+        );
+        assert_eq!(
+            ok(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "Sun, 06 Nov 1994 08:51:37 GMT"),
+            ]),
+            secs(120)
+        );
+        assert_eq!(
+            ok(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "0"),
+                ("etag", "\"v1\""),
+            ]),
+            secs(0)
+        );
+        // stale right away, but can be revalidated
+        assert_eq!(
+            ok(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]),
+            secs(0)
+        );
+        assert_eq!(ok(&[("cache-control", "no-cache")]), None);
+
+        let headers = headers(&[("cache-control", "max-age=60")]);
+        assert_eq!(lifetime(StatusCode::NOT_FOUND, &headers), secs(60));
+        assert_eq!(lifetime(StatusCode::BAD_GATEWAY, &headers), None);
+    }
+
+    #[ntex::test]
+    async fn test_lookup() {
+        let cache = Arc::new(Cache::new(1024));
+        let req = HeaderMap::new();
+        let fresh = headers(&[("cache-control", "max-age=60"), ("etag", "\"v1\"")]);
+
+        assert!(matches!(
+            cache.lookup("a", &req, RequestPolicy::Use),
+            Lookup::Miss
+        ));
+        store(&cache, "a", &req, fresh).await;
+        let entry = match cache.lookup("a", &req, RequestPolicy::Use) {
+            Lookup::Fresh(entry) => entry,
+            _ => panic!("entry is not fresh"),
+        };
+        assert!(matches!(
+            cache.lookup("a", &req, RequestPolicy::Revalidate),
+            Lookup::Stale(_)
+        ));
+
+        // client already has the response
+        let res = entry.response(&headers(&[("if-none-match", "W/\"v0\", \"v1\"")]));
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"v1\"");
+        let res = entry.response(&req);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::AGE).unwrap(), "0");
+
+        // revalidation updates headers and restarts freshness
+        let stale = headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]);
+        store(&cache, "b", &req, stale).await;
+        let entry = match cache.lookup("b", &req, RequestPolicy::Use) {
+            Lookup::Stale(entry) => entry,
+            _ => panic!("entry is not stale"),
+        };
+        let mut validators = HeaderMap::new();
+        entry.add_validators(&mut validators);
+        assert_eq!(validators.get(header::IF_NONE_MATCH).unwrap(), "\"v1\"");
+
+        let refreshed = entry.refresh(&headers(&[("cache-control", "max-age=60")]));
+        cache.revalidated("b".to_owned(), refreshed);
+        assert!(matches!(
+            cache.lookup("b", &req, RequestPolicy::Use),
+            Lookup::Fresh(_)
+        ));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.revalidated), (2, 1, 1));
+        assert_eq!((stats.stored, stats.entries), (3, 2));
+    }
+
+    #[ntex::test]
+    async fn test_vary() {
+        let cache = Arc::new(Cache::new(1024));
+        let res =
+            || headers(&[("cache-control", "max-age=60"), ("vary", "accept-language")]);
+        let en = headers(&[("accept-language", "en")]);
+        let de = headers(&[("accept-language", "de")]);
+
+        store(&cache, "a", &en, res()).await;
+        assert!(matches!(
+            cache.lookup("a", &en, RequestPolicy::Use),
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.lookup("a", &de, RequestPolicy::Use),
+            Lookup::Miss
+        ));
+
+        store(&cache, "a", &de, res()).await;
+        store(&cache, "a", &de, res()).await;
+        assert!(matches!(
+            cache.lookup("a", &de, RequestPolicy::Use),
+            Lookup::Fresh(_)
+        ));
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[ntex::test]
+    async fn test_eviction() {
+        let res = || headers(&[("cache-control", "max-age=60")]);
+        let req = HeaderMap::new();
+        // room for two entries
+        let size = Entry::new(StatusCode::OK, res(), &req).size() + 5;
+        let cache = Arc::new(Cache::new(size * 2));
+
+        store(&cache, "a", &req, res()).await;
+        store(&cache, "b", &req, res()).await;
+        // "a" becomes most recently used
+        assert!(matches!(
+            cache.lookup("a", &req, RequestPolicy::Use),
+            Lookup::Fresh(_)
+        ));
+        store(&cache, "c", &req, res()).await;
+
+        assert!(matches!(
+            cache.lookup("b", &req, RequestPolicy::Use),
+            Lookup::Miss
+        ));
+        assert!(matches!(
+            cache.lookup("a", &req, RequestPolicy::Use),
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.lookup("c", &req, RequestPolicy::Use),
+            Lookup::Fresh(_)
+        ));
+        let stats = cache.stats();
+        assert_eq!((stats.evicted, stats.entries, stats.size), (1, 2, size * 2));
+
+        // too big to be stored at all
+        let body = stream::iter(vec![Ok(Bytes::from(vec![0; size * 2 + 1]))]);
+        let mut store = cache.store("d".to_owned(), &req, StatusCode::OK, res(), body);
+        while store.next().await.is_some() {}
+        assert!(matches!(
+            cache.lookup("d", &req, RequestPolicy::Use),
+            Lookup::Miss
+        ));
+    }
+}
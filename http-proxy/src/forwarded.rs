@@ -0,0 +1,553 @@
+//! RFC 7239 `Forwarded` header support.
+//!
+//! The proxy collects the forwarding chain the request arrived with (either
+//! from `Forwarded` or from the legacy `X-Forwarded-*` headers), drops every
+//! hop that was not added by a trusted proxy, appends an element describing
+//! its own hop and writes the result back as both header flavours.
+//!
+//! See: https://www.rfc-editor.org/rfc/rfc7239
+use std::net::{IpAddr, SocketAddr};
+use std::{fmt, str::FromStr};
+
+use ntex::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Single `forwarded-element`, one per proxy hop.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Element {
+    /// The user-agent facing interface of the proxy that added this element.
+    pub by: Option<String>,
+    /// The node making the request to the proxy.
+    pub for_: Option<String>,
+    /// The `Host` request header as received by the proxy.
+    pub host: Option<String>,
+    /// The protocol used to make the request.
+    pub proto: Option<String>,
+}
+
+impl Element {
+    /// Ip address of the `for` node, if it is not obfuscated or `unknown`.
+    pub fn for_ip(&self) -> Option<IpAddr> {
+        self.for_.as_deref().and_then(node_ip)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by.is_none()
+            && self.for_.is_none()
+            && self.host.is_none()
+            && self.proto.is_none()
+    }
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pairs = [
+            ("for", &self.for_),
+            ("proto", &self.proto),
+            ("host", &self.host),
+            ("by", &self.by),
+        ];
+        let mut sep = "";
+        for (name, value) in pairs {
+            if let Some(value) = value {
+                write!(f, "{}{}=", sep, name)?;
+                write_value(f, value)?;
+                sep = ";";
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format node identifier, IPv6 addresses are enclosed in square brackets.
+pub fn node(addr: SocketAddr, with_port: bool) -> String {
+    match (addr.ip(), with_port) {
+        (IpAddr::V4(ip), false) => ip.to_string(),
+        (IpAddr::V6(ip), false) => format!("[{}]", ip),
+        (_, true) => addr.to_string(),
+    }
+}
+
+/// Extract ip address from node identifier (`192.0.2.1`, `192.0.2.1:80`,
+/// `[2001:db8::1]`, `[2001:db8::1]:80` or a bare IPv6 address as found in
+/// `X-Forwarded-For`).
+fn node_ip(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn write_value(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    if is_token(value) {
+        f.write_str(value)
+    } else {
+        f.write_str("\"")?;
+        for c in value.chars() {
+            if c == '"' || c == '\\' {
+                f.write_str("\\")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        f.write_str("\"")
+    }
+}
+
+/// Split `s` by `sep`, ignoring separators inside quoted strings.
+fn split_quoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && c == sep {
+            parts.push(&s[start..idx]);
+            start = idx + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> Option<String> {
+    match value.strip_prefix('"') {
+        Some(rest) => {
+            let rest = rest.strip_suffix('"')?;
+            let mut result = String::with_capacity(rest.len());
+            let mut chars = rest.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    result.push(chars.next()?);
+                } else {
+                    result.push(c);
+                }
+            }
+            Some(result)
+        }
+        None if is_token(value) => Some(value.to_owned()),
+        None => None,
+    }
+}
+
+/// Parse the value of a `Forwarded` header.
+///
+/// Malformed elements are skipped, unknown parameters are ignored.
+pub fn parse(value: &str) -> Vec<Element> {
+    let mut chain = Vec::new();
+
+    'elements: for part in split_quoted(value, ',') {
+        let mut elem = Element::default();
+
+        for pair in split_quoted(part, ';') {
+            let pair = pair.trim();
+            if pair.is_empty() {
+                continue;
+            }
+            let Some((name, value)) = pair.split_once('=') else {
+                continue 'elements;
+            };
+            let Some(value) = unquote(value.trim()) else {
+                continue 'elements;
+            };
+            let slot = match name.trim().to_ascii_lowercase().as_str() {
+                "by" => &mut elem.by,
+                "for" => &mut elem.for_,
+                "host" => &mut elem.host,
+                "proto" => &mut elem.proto,
+                _ => continue,
+            };
+            *slot = Some(value);
+        }
+        if !elem.is_empty() {
+            chain.push(elem);
+        }
+    }
+    chain
+}
+
+/// Collect the forwarding chain from request headers.
+///
+/// `Forwarded` takes precedence. Without it the chain is reconstructed from
+/// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`, the latter
+/// two describe the original request and are attached to the first hop.
+pub fn from_headers(headers: &HeaderMap) -> Vec<Element> {
+    let forwarded = join_values(headers, header::FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return parse(&forwarded);
+    }
+
+    let mut chain: Vec<_> = join_values(headers, X_FORWARDED_FOR)
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| Element {
+            for_: Some(s.to_owned()),
+            ..Default::default()
+        })
+        .collect();
+
+    let first = |name| {
+        join_values(headers, name)
+            .split(',')
+            .next()
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+    };
+    let proto = first(X_FORWARDED_PROTO);
+    let host = first(X_FORWARDED_HOST);
+    if proto.is_some() || host.is_some() {
+        if chain.is_empty() {
+            chain.push(Element::default());
+        }
+        chain[0].proto = proto;
+        chain[0].host = host;
+    }
+    chain
+}
+
+fn join_values(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Replace all forwarding headers with the ones describing `chain`.
+pub fn apply(headers: &mut HeaderMap, chain: &[Element]) {
+    for name in [
+        header::FORWARDED.as_str(),
+        X_FORWARDED_FOR,
+        X_FORWARDED_PROTO,
+        X_FORWARDED_HOST,
+    ] {
+        headers.remove(name);
+    }
+
+    let forwarded = chain
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let xff = chain
+        .iter()
+        .map(|e| e.for_.as_deref().unwrap_or("unknown"))
+        .map(|node| {
+            // X-Forwarded-For carries bare addresses, without brackets or ports
+            node_ip(node).map_or_else(|| node.to_owned(), |ip| ip.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut insert = |name: &'static str, value: &str| {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    };
+    if !forwarded.is_empty() {
+        insert("forwarded", &forwarded);
+        insert(X_FORWARDED_FOR, &xff);
+    }
+    if let Some(first) = chain.first() {
+        if let Some(ref proto) = first.proto {
+            insert(X_FORWARDED_PROTO, proto);
+        }
+        if let Some(ref host) = first.host {
+            insert(X_FORWARDED_HOST, host);
+        }
+    }
+}
+
+/// Ip network in CIDR notation, a plain address is a network with a full
+/// length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(ip)) => {
+                self.contains(IpAddr::V6(ip.to_ipv6_mapped()))
+            }
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let full = usize::from(prefix / 8);
+    let rest = prefix % 8;
+    if a[..full] != b[..full] {
+        return false;
+    }
+    if rest == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest);
+    a[full] & mask == b[full] & mask
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid ip address: {:?}", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid network prefix: {:?}", prefix))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+/// How the proxy treats forwarding information it receives.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardedConfig {
+    /// Proxies allowed to pass forwarding information to us.
+    pub trusted: Vec<IpNet>,
+    /// Identifier of this proxy, used as the `by` parameter.
+    pub by: Option<String>,
+}
+
+impl ForwardedConfig {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+
+    /// Build the chain to send upstream.
+    ///
+    /// Incoming elements are kept only while they were appended by trusted
+    /// proxies: the chain is walked from the closest hop (our peer) backwards
+    /// and cut at the first untrusted node, everything older than that could
+    /// have been forged.
+    pub fn chain(
+        &self,
+        headers: &HeaderMap,
+        peer: Option<SocketAddr>,
+        proto: &str,
+    ) -> Vec<Element> {
+        let mut chain = match peer {
+            Some(peer) if self.is_trusted(peer.ip()) => {
+                let mut incoming = from_headers(headers);
+                let keep_from = incoming
+                    .iter()
+                    .rposition(|e| !e.for_ip().is_some_and(|ip| self.is_trusted(ip)))
+                    .unwrap_or(0);
+                incoming.drain(..keep_from);
+                incoming
+            }
+            _ => Vec::new(),
+        };
+
+        chain.push(Element {
+            by: self.by.clone(),
+            for_: Some(peer.map_or_else(|| "unknown".to_owned(), |p| node(p, false))),
+            host: headers
+                .get(header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(ToOwned::to_owned),
+            proto: Some(proto.to_owned()),
+        });
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(items: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in items {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        map
+    }
+
+    #[test]
+    fn test_parse() {
+        let chain = parse(
+            r#"for="_gazonk", For="[2001:db8:cafe::17]:4711";proto=https, for=192.0.2.60;proto=http;by=203.0.113.43"#,
+        );
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0].for_.as_deref(), Some("_gazonk"));
+        assert_eq!(chain[0].for_ip(), None);
+        assert_eq!(chain[1].for_.as_deref(), Some("[2001:db8:cafe::17]:4711"));
+        assert_eq!(
+            chain[1].for_ip(),
+            Some("2001:db8:cafe::17".parse().unwrap())
+        );
+        assert_eq!(chain[1].proto.as_deref(), Some("https"));
+        assert_eq!(chain[2].by.as_deref(), Some("203.0.113.43"));
+
+        // malformed elements are dropped
+        let chain = parse(r#"for=192.0.2.1:80, for, for=192.0.2.2"#);
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].for_.as_deref(), Some("192.0.2.2"));
+    }
+
+    #[test]
+    fn test_display() {
+        let elem = Element {
+            by: Some("[2001:db8::1]:8080".to_owned()),
+            for_: Some("192.0.2.60".to_owned()),
+            host: Some("example.com".to_owned()),
+            proto: Some("http".to_owned()),
+        };
+        let s = elem.to_string();
+        assert_eq!(
+            s,
+            r#"for=192.0.2.60;proto=http;host=example.com;by="[2001:db8::1]:8080""#
+        );
+        assert_eq!(parse(&s), vec![elem]);
+    }
+
+    #[test]
+    fn test_x_forwarded() {
+        let chain = from_headers(&headers(&[
+            (X_FORWARDED_FOR, "192.0.2.1, 2001:db8::1"),
+            (X_FORWARDED_PROTO, "https"),
+            (X_FORWARDED_HOST, "example.com"),
+        ]));
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].proto.as_deref(), Some("https"));
+        assert_eq!(chain[0].host.as_deref(), Some("example.com"));
+        assert_eq!(chain[1].for_ip(), Some("2001:db8::1".parse().unwrap()));
+
+        // `Forwarded` wins over legacy headers
+        let chain = from_headers(&headers(&[
+            ("forwarded", "for=192.0.2.9"),
+            (X_FORWARDED_FOR, "192.0.2.1"),
+        ]));
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].for_.as_deref(), Some("192.0.2.9"));
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.1.2.3".parse().unwrap()));
+
+        let net: IpNet = "2001:db8::/33".parse().unwrap();
+        assert!(net.contains("2001:db8:7fff::1".parse().unwrap()));
+        assert!(!net.contains("2001:db8:8000::1".parse().unwrap()));
+
+        let net: IpNet = "192.0.2.1".parse().unwrap();
+        assert!(net.contains("192.0.2.1".parse().unwrap()));
+        assert!(!net.contains("192.0.2.2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let cfg = ForwardedConfig {
+            trusted: vec!["10.0.0.0/8".parse().unwrap()],
+            by: Some("proxy".to_owned()),
+        };
+        let hdrs = headers(&[("forwarded", "for=192.0.2.1"), ("host", "example.com")]);
+        let chain = cfg.chain(&hdrs, Some("192.0.2.200:1234".parse().unwrap()), "http");
+        assert_eq!(
+            chain,
+            vec![Element {
+                by: Some("proxy".to_owned()),
+                for_: Some("192.0.2.200".to_owned()),
+                host: Some("example.com".to_owned()),
+                proto: Some("http".to_owned()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_trusted_chain() {
+        let cfg = ForwardedConfig {
+            trusted: vec!["10.0.0.0/8".parse().unwrap()],
+            by: None,
+        };
+        // 198.51.100.1 is forged by the client, 192.0.2.1 is the client as
+        // seen by the trusted 10.0.0.2 proxy which in turn talks to us
+        let hdrs =
+            headers(&[("forwarded", "for=198.51.100.1, for=192.0.2.1, for=10.0.0.1")]);
+        let chain = cfg.chain(&hdrs, Some("10.0.0.2:1234".parse().unwrap()), "https");
+        let nodes: Vec<_> = chain.iter().map(|e| e.for_.as_deref().unwrap()).collect();
+        assert_eq!(nodes, ["192.0.2.1", "10.0.0.1", "10.0.0.2"]);
+
+        let mut hdrs = HeaderMap::new();
+        apply(&mut hdrs, &chain);
+        assert_eq!(
+            hdrs.get("forwarded").unwrap(),
+            "for=192.0.2.1, for=10.0.0.1, for=10.0.0.2;proto=https"
+        );
+        assert_eq!(
+            hdrs.get(X_FORWARDED_FOR).unwrap(),
+            "192.0.2.1, 10.0.0.1, 10.0.0.2"
+        );
+        assert!(hdrs.get(X_FORWARDED_PROTO).is_none());
+    }
+
+    #[test]
+    fn test_ipv6_peer() {
+        let cfg = ForwardedConfig::default();
+        let chain = cfg.chain(
+            &HeaderMap::new(),
+            Some("[2001:db8::1]:4711".parse().unwrap()),
+            "http",
+        );
+        let mut hdrs = HeaderMap::new();
+        apply(&mut hdrs, &chain);
+        assert_eq!(
+            hdrs.get("forwarded").unwrap(),
+            r#"for="[2001:db8::1]";proto=http"#
+        );
+        assert_eq!(hdrs.get(X_FORWARDED_FOR).unwrap(), "2001:db8::1");
+        assert_eq!(hdrs.get(X_FORWARDED_PROTO).unwrap(), "http");
+    }
+}
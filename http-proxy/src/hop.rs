@@ -0,0 +1,98 @@
+//! Hop-by-hop headers handling.
+//!
+//! Hop-by-hop headers are meaningful only for a single transport-level
+//! connection and must not be retransmitted by proxies, see
+//! https://www.rfc-editor.org/rfc/rfc7230#section-6.1
+use ntex::http::header::{self, HeaderMap};
+
+/// Headers that are always hop-by-hop.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Whether header is hop-by-hop regardless of `Connection`.
+pub fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+}
+
+/// Lowercase names listed in `Connection`.
+pub fn connection_options(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONNECTION)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Remove hop-by-hop headers, including every header listed in `Connection`.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = connection_options(headers);
+
+    for name in listed
+        .iter()
+        .map(String::as_str)
+        .chain(HOP_BY_HOP.iter().copied())
+    {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::http::header::{HeaderName, HeaderValue};
+
+    #[test]
+    fn test_remove_hop_by_hop() {
+        for name in HOP_BY_HOP {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static("value"),
+            );
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+            remove_hop_by_hop(&mut headers);
+
+            assert!(!headers.contains_key(*name), "{} is not removed", name);
+            assert!(headers.contains_key(header::CONTENT_TYPE));
+        }
+    }
+
+    #[test]
+    fn test_connection_listed() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, X-Foo"),
+        );
+        headers.append(header::CONNECTION, HeaderValue::from_static("x-bar"));
+        headers.insert(
+            HeaderName::from_static("x-foo"),
+            HeaderValue::from_static("1"),
+        );
+        headers.insert(
+            HeaderName::from_static("x-bar"),
+            HeaderValue::from_static("2"),
+        );
+        headers.insert(
+            HeaderName::from_static("x-baz"),
+            HeaderValue::from_static("3"),
+        );
+        remove_hop_by_hop(&mut headers);
+
+        assert!(!headers.contains_key(header::CONNECTION));
+        assert!(!headers.contains_key("x-foo"));
+        assert!(!headers.contains_key("x-bar"));
+        assert!(headers.contains_key("x-baz"));
+    }
+}
@@ -1,44 +1,303 @@
-use std::net::ToSocketAddrs;
+use std::net::SocketAddr;
+use std::{collections::HashMap, error, path::Path, rc::Rc, sync::Arc, time::Duration};
 
-use clap::{value_t, Arg};
+use clap::{value_t, values_t, Arg};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use ntex::client::error::{ClientError, ConnectError};
 use ntex::client::Client;
+use ntex::http::body::{Body, BodyStream, SizedStream};
+use ntex::http::{error::PayloadError, header, HeaderMap, Method, Payload, StatusCode};
+use ntex::io::IoConfig;
+use ntex::time::sleep;
 use ntex::util::Bytes;
 use ntex::web::{self, middleware, App, Error, HttpRequest, HttpResponse};
-use url::Url;
+use ntex::{rt, SharedCfg};
+
+mod cache;
+mod forwarded;
+mod hop;
+mod routing;
+mod tunnel;
+mod upstream;
+use self::cache::{Cache, Lookup, RequestPolicy};
+use self::forwarded::{ForwardedConfig, IpNet};
+use self::routing::{Policy, Router};
+use self::upstream::{Breaker, HealthCheck, Strategy, Upstream};
+
+/// Value of `Content-Length` header, if any.
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Largest chunk written with chunked transfer encoding.
+///
+/// The h1 encoder corrupts the size line of chunks that do not fit its write
+/// page (4 KiB), so bigger chunks are sliced before encoding.
+const MAX_CHUNK_SIZE: usize = 4096;
+
+/// Pipe payload stream into a message body without buffering it.
+///
+/// Bodies of known size keep their `Content-Length`, others are sent chunked.
+fn stream_body<S>(size: Option<u64>, stream: S) -> Body
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static,
+{
+    match size {
+        Some(size) => Body::from_message(SizedStream::new(
+            size,
+            stream.map_err(|e| Rc::new(e) as Rc<dyn error::Error>),
+        )),
+        None => Body::from_message(BodyStream::new(
+            stream
+                .map_ok(|mut chunk| {
+                    let mut pieces: Vec<Result<_, PayloadError>> = Vec::new();
+                    while chunk.len() > MAX_CHUNK_SIZE {
+                        pieces.push(Ok(chunk.split_to(MAX_CHUNK_SIZE)));
+                    }
+                    pieces.push(Ok(chunk));
+                    stream::iter(pieces)
+                })
+                .try_flatten(),
+        )),
+    }
+}
+
+/// Build http client with the given connect timeout.
+///
+/// The response timeout is set per request, so transfers of any size and
+/// duration can pass through.
+async fn client(connect_timeout: Duration) -> Client {
+    let cfg: SharedCfg = SharedCfg::new("PROXY")
+        .add(IoConfig::new().set_connect_timeout(connect_timeout))
+        .into();
+    Client::builder()
+        .disable_timeout()
+        .build(cfg)
+        .await
+        .unwrap()
+}
+
+/// Http clients for every connect timeout used by the router.
+struct Clients(HashMap<Duration, Client>);
+
+impl Clients {
+    async fn new(router: &Router) -> Self {
+        let mut clients = HashMap::new();
+        for timeout in router.connect_timeouts() {
+            clients.insert(timeout, client(timeout).await);
+        }
+        Clients(clients)
+    }
+
+    fn get(&self, connect_timeout: Duration) -> &Client {
+        &self.0[&connect_timeout]
+    }
+}
+
+/// Whether upstream response status is worth another attempt, like a
+/// transport error.
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Whether request can be sent again after a failed attempt.
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET
+            | Method::HEAD
+            | Method::OPTIONS
+            | Method::TRACE
+            | Method::PUT
+            | Method::DELETE
+    )
+}
+
+/// Response for a request that could not be sent to the upstream.
+fn upstream_error(err: &ClientError) -> HttpResponse {
+    match err {
+        ClientError::Timeout | ClientError::Connect(ConnectError::Timeout) => {
+            HttpResponse::GatewayTimeout().finish()
+        }
+        _ => HttpResponse::BadGateway().finish(),
+    }
+}
+
+/// Response for an upstream group without available backends.
+fn unavailable(upstream: &Upstream) -> HttpResponse {
+    let mut res = HttpResponse::ServiceUnavailable();
+    // all circuits are open, tell the client when the first one closes
+    if let Some(after) = upstream.retry_after() {
+        res.header(
+            header::RETRY_AFTER,
+            after.as_secs_f64().ceil().max(1.0) as u64,
+        );
+    }
+    res.finish()
+}
 
 async fn forward(
     req: HttpRequest,
-    body: Bytes,
-    url: web::types::State<Url>,
-    client: web::types::State<Client>,
+    payload: web::types::Payload,
+    router: web::types::State<Arc<Router>>,
+    clients: web::types::State<Clients>,
+    fwd: web::types::State<ForwardedConfig>,
+    cache: web::types::State<Option<Arc<Cache>>>,
 ) -> Result<HttpResponse, Error> {
-    let mut new_url = url.get_ref().clone();
-    new_url.set_path(req.uri().path());
-    new_url.set_query(req.uri().query());
-
-    // TODO: This forwarded implementation is incomplete as it only handles the inofficial
-    // X-Forwarded-For header but not the official Forwarded one.
-    let forwarded_req = client
-        .request_from(new_url.as_str(), req.head())
-        .no_decompress();
-    let forwarded_req = if let Some(addr) = req.head().peer_addr() {
-        forwarded_req.header("x-forwarded-for", format!("{}", addr.ip()))
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host());
+
+    let cache_policy = RequestPolicy::new(req.head());
+    let cache = cache
+        .as_ref()
+        .filter(|_| cache_policy != RequestPolicy::Bypass);
+    let key = Cache::key(host, req.uri());
+    let mut stale = None;
+    if let Some(cache) = cache {
+        match cache.lookup(&key, req.headers(), cache_policy) {
+            Lookup::Fresh(entry) => return Ok(entry.response(req.headers())),
+            Lookup::Stale(entry) => stale = Some(entry),
+            Lookup::Miss => (),
+        }
+    }
+
+    let target = router.route(host, req.uri().path());
+    let upstream_url = |conn: &upstream::Connection| {
+        let mut url = conn.backend().url().clone();
+        url.set_path(&target.path);
+        url.set_query(req.uri().query());
+        url
+    };
+
+    // Rewrite `Forwarded` and `X-Forwarded-*` headers, the proxy itself
+    // accepts plain http connections only
+    let chain = fwd.chain(req.headers(), req.head().peer_addr(), "http");
+
+    let policy = target.policy;
+    if tunnel::is_upgrade(req.head()) {
+        let Some(conn) = target.upstream.select(req.headers()) else {
+            return Ok(unavailable(target.upstream));
+        };
+        let mut headers = req.headers().clone();
+        hop::remove_hop_by_hop(&mut headers);
+        forwarded::apply(&mut headers, &chain);
+        let url = upstream_url(&conn);
+        return tunnel::start(&req, &url, headers, target.upstream, conn, policy).await;
+    }
+
+    let client = clients.get(policy.connect_timeout);
+    let mut payload = match payload.into_inner() {
+        Payload::None => None,
+        payload => Some(payload),
+    };
+    // a streamed request body cannot be replayed
+    let retries = if payload.is_none() && is_idempotent(req.method()) {
+        policy.retries
     } else {
-        forwarded_req
+        0
     };
 
-    let res = forwarded_req.send_body(body).await.map_err(Error::from)?;
+    let mut attempt = 0;
+    let (conn, mut res) = loop {
+        let Some(conn) = target.upstream.select(req.headers()) else {
+            return Ok(unavailable(target.upstream));
+        };
+
+        let mut forwarded_req = client
+            .request_from(upstream_url(&conn).as_str(), req.head())
+            .timeout(policy.read_timeout)
+            .no_decompress();
+        hop::remove_hop_by_hop(forwarded_req.headers_mut());
+        forwarded::apply(forwarded_req.headers_mut(), &chain);
+        if let Some(ref entry) = stale {
+            entry.add_validators(forwarded_req.headers_mut());
+        }
+
+        let body = match payload.take() {
+            Some(payload) => stream_body(content_length(req.headers()), payload),
+            None => Body::None,
+        };
+        match forwarded_req.send_body(body).await {
+            Ok(res) => {
+                let status = res.status();
+                target.upstream.report(&conn, !status.is_server_error());
+                // the last attempt's answer goes to the client as is
+                if attempt == retries || !is_retryable(status) {
+                    break (conn, res);
+                }
+            }
+            Err(err) => {
+                target.upstream.report(&conn, false);
+                if attempt == retries {
+                    println!(
+                        "Upstream {} request failed: {}",
+                        conn.backend().url(),
+                        err
+                    );
+                    return Ok(upstream_error(&err));
+                }
+            }
+        }
+        // the failed backend is released before the next one is selected
+        drop(conn);
+        sleep(policy.retry_backoff * 2u32.saturating_pow(attempt as u32)).await;
+        attempt += 1;
+    };
+    hop::remove_hop_by_hop(res.headers_mut());
+
+    if let (Some(cache), Some(entry)) = (cache, stale) {
+        if res.status() == StatusCode::NOT_MODIFIED {
+            let entry = cache.revalidated(key, entry.refresh(res.headers()));
+            return Ok(entry.response(req.headers()));
+        }
+        cache.replaced(&key, &entry);
+    }
 
     let mut client_resp = HttpResponse::build(res.status());
-    // Remove `Connection` as per
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
-    for (header_name, header_value) in
-        res.headers().iter().filter(|(h, _)| *h != "connection")
-    {
+    for (header_name, header_value) in res.headers() {
         client_resp.header(header_name.clone(), header_value.clone());
     }
 
-    Ok(client_resp.body(res.body().await?))
+    // the request is in-flight until the whole response body is sent
+    let body = match res.status() {
+        StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED => Body::None,
+        status => {
+            let size = content_length(res.headers());
+            let headers = res.headers().clone();
+            let stream = res.inspect(move |_| {
+                let _ = &conn;
+            });
+            match cache.filter(|_| cache::lifetime(status, &headers).is_some()) {
+                Some(cache) => stream_body(
+                    size,
+                    cache.store(key, req.headers(), status, headers, stream),
+                ),
+                None => stream_body(size, stream),
+            }
+        }
+    };
+    Ok(client_resp.body(body))
+}
+
+/// Cache counters, served on the admin address
+async fn cache_stats(
+    cache: web::types::State<Option<Arc<Cache>>>,
+) -> Result<web::types::Json<cache::Stats>, Error> {
+    match cache.as_ref() {
+        Some(cache) => Ok(web::types::Json(cache.stats())),
+        None => Err(web::error::ErrorNotFound("cache is disabled").into()),
+    }
 }
 
 #[ntex::main]
@@ -72,6 +331,120 @@ async fn main() -> std::io::Result<()> {
                 .index(4)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream")
+                .help("Additional upstream server to balance requests over")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("ADDR:PORT"),
+        )
+        .arg(
+            Arg::with_name("balance")
+                .long("balance")
+                .help(
+                    "Load balancing strategy: round-robin, least-conn or hash:<header>",
+                )
+                .takes_value(true)
+                .default_value("round-robin")
+                .value_name("STRATEGY"),
+        )
+        .arg(
+            Arg::with_name("health_check")
+                .long("health-check")
+                .help("Periodically GET this path on every upstream, eject failing ones")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("health_interval")
+                .long("health-interval")
+                .help("Seconds between health checks")
+                .takes_value(true)
+                .default_value("5")
+                .value_name("SECS"),
+        )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .long("connect-timeout")
+                .help("Seconds allowed to connect to an upstream")
+                .takes_value(true)
+                .default_value("5")
+                .value_name("SECS"),
+        )
+        .arg(
+            Arg::with_name("read_timeout")
+                .long("read-timeout")
+                .help("Seconds allowed for an upstream to start responding")
+                .takes_value(true)
+                .default_value("30")
+                .value_name("SECS"),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .help("Retry failed idempotent requests on another upstream")
+                .takes_value(true)
+                .default_value("2")
+                .value_name("N"),
+        )
+        .arg(
+            Arg::with_name("breaker_failures")
+                .long("breaker-failures")
+                .help("Consecutive failures that open the circuit of an upstream")
+                .takes_value(true)
+                .default_value("5")
+                .value_name("N"),
+        )
+        .arg(
+            Arg::with_name("breaker_cooldown")
+                .long("breaker-cooldown")
+                .help("Seconds before an open circuit lets a request through")
+                .takes_value(true)
+                .default_value("10")
+                .value_name("SECS"),
+        )
+        .arg(
+            Arg::with_name("routes")
+                .long("routes")
+                .help("Routing table, TOML or JSON file")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("cache_size")
+                .long("cache-size")
+                .help(
+                    "Cache responses in memory, up to this many megabytes, \
+                     the cache is not persisted to disk",
+                )
+                .takes_value(true)
+                .value_name("MB"),
+        )
+        .arg(
+            Arg::with_name("admin")
+                .long("admin")
+                .help("Serve cache statistics on this address")
+                .takes_value(true)
+                .value_name("ADDR:PORT"),
+        )
+        .arg(
+            Arg::with_name("trusted_proxy")
+                .long("trusted-proxy")
+                .help("Accept forwarding headers from this address or network")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("CIDR"),
+        )
+        .arg(
+            Arg::with_name("forwarded_by")
+                .long("forwarded-by")
+                .help("Identifier used as `by` in the Forwarded header")
+                .takes_value(true)
+                .value_name("NODE"),
+        )
         .get_matches();
 
     let listen_addr = matches.value_of("listen_addr").unwrap();
@@ -81,20 +454,120 @@ async fn main() -> std::io::Result<()> {
     let forwarded_port =
         value_t!(matches, "forward_port", u16).unwrap_or_else(|e| e.exit());
 
-    let forward_url = Url::parse(&format!(
-        "http://{}",
-        (forwarded_addr, forwarded_port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap()
-    ))
-    .unwrap();
+    let mut upstreams = vec![format!("{}:{}", forwarded_addr, forwarded_port)];
+    upstreams.extend(
+        matches
+            .values_of("upstream")
+            .into_iter()
+            .flatten()
+            .map(ToOwned::to_owned),
+    );
+    let upstream_urls = upstreams
+        .iter()
+        .map(|addr| {
+            upstream::resolve(addr).unwrap_or_else(|e| {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+            })
+        })
+        .collect();
+    let strategy = value_t!(matches, "balance", Strategy).unwrap_or_else(|e| e.exit());
+    let seconds = |name| {
+        let secs = value_t!(matches, name, f64).unwrap_or_else(|e| e.exit());
+        Duration::try_from_secs_f64(secs).unwrap_or_else(|e| {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue)
+                .exit()
+        })
+    };
+    let breaker = Breaker {
+        failures: value_t!(matches, "breaker_failures", usize)
+            .unwrap_or_else(|e| e.exit()),
+        cooldown: seconds("breaker_cooldown"),
+    };
+    let upstream = Arc::new(Upstream::new(upstream_urls, strategy).breaker(breaker));
+
+    let policy = Policy {
+        connect_timeout: seconds("connect_timeout"),
+        read_timeout: seconds("read_timeout"),
+        retries: value_t!(matches, "retries", usize).unwrap_or_else(|e| e.exit()),
+        ..Default::default()
+    };
+
+    if let Some(path) = matches.value_of("health_check") {
+        let health = HealthCheck {
+            path: path.to_owned(),
+            interval: Duration::from_secs(
+                value_t!(matches, "health_interval", u64).unwrap_or_else(|e| e.exit()),
+            ),
+            ..Default::default()
+        };
+        let client = client(health.timeout).await;
+        rt::spawn(health.run(upstream.clone(), client));
+    }
+
+    // requests not matched by the routing table go to the upstreams above
+    let router = match matches.value_of("routes") {
+        Some(path) => {
+            Router::load(Path::new(path), upstream, policy).unwrap_or_else(|e| {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+            })
+        }
+        None => Router::new(upstream, policy),
+    };
+    for (upstream, health) in router.health_checks() {
+        let client = client(health.timeout).await;
+        rt::spawn(health.clone().run(upstream.clone(), client));
+    }
+    let router = Arc::new(router);
+
+    let forwarded = ForwardedConfig {
+        trusted: values_t!(matches, "trusted_proxy", IpNet).unwrap_or_else(|e| {
+            if e.kind == clap::ErrorKind::ArgumentNotFound {
+                Vec::new()
+            } else {
+                e.exit()
+            }
+        }),
+        // defaults to the listen address, if it is an ip address
+        by: matches
+            .value_of("forwarded_by")
+            .map(ToOwned::to_owned)
+            .or_else(|| {
+                listen_addr
+                    .parse()
+                    .ok()
+                    .map(|ip| forwarded::node(SocketAddr::new(ip, listen_port), true))
+            }),
+    };
+
+    let cache = match matches.value_of("cache_size") {
+        Some(_) => {
+            let size =
+                value_t!(matches, "cache_size", usize).unwrap_or_else(|e| e.exit());
+            Some(Arc::new(Cache::new(size * 1024 * 1024)))
+        }
+        None => None,
+    };
+
+    if let Some(addr) = matches.value_of("admin") {
+        let cache = cache.clone();
+        let admin = web::server(async move || {
+            App::new()
+                .state(cache.clone())
+                .service(web::resource("/cache").to(cache_stats))
+        })
+        .bind(addr)?
+        .workers(1)
+        .disable_signals()
+        .run();
+        rt::spawn(admin);
+    }
 
     web::server(async move || {
         App::new()
-            .state(Client::new())
-            .state(forward_url.clone())
+            .state(Clients::new(&router).await)
+            .state(router.clone())
+            .state(forwarded.clone())
+            .state(cache.clone())
             .middleware(middleware::Logger::default())
             .default_service(web::route().to(forward))
     })
@@ -103,3 +576,886 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use ntex::http::{header, StatusCode};
+    use ntex::service::{fn_factory_with_config, fn_service};
+    use ntex::web::test;
+    use ntex::ws;
+    use url::Url;
+
+    /// Upstream server used by tests
+    async fn upstream() -> test::TestServer {
+        test::server(async || {
+            App::new()
+                .service(web::resource("/hop").to(hop_by_hop))
+                .service(web::resource("/echo").to(echo_body))
+                .service(web::resource("/ws").to(ws_echo))
+                .service(
+                    web::resource("/private")
+                        .to(|| async { HttpResponse::Forbidden().finish() }),
+                )
+                .default_service(web::route().to(echo_forwarded))
+        })
+        .await
+    }
+
+    /// Sends back forwarding headers in the body
+    async fn echo_forwarded(req: HttpRequest) -> HttpResponse {
+        let hdr = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-")
+                .to_owned()
+        };
+        HttpResponse::Ok().body(format!(
+            "{}|{}",
+            hdr("forwarded"),
+            hdr(forwarded::X_FORWARDED_FOR)
+        ))
+    }
+
+    /// Sends request payload back in the chunks it was received in.
+    ///
+    /// The whole payload is read first, the client does not read the response
+    /// before the request is sent, so echoing while reading stalls both sides
+    /// once socket buffers are full.
+    async fn echo_body(req: HttpRequest, payload: web::types::Payload) -> HttpResponse {
+        let chunks: Vec<Bytes> = match payload.try_collect().await {
+            Ok(chunks) => chunks,
+            Err(_) => return HttpResponse::BadRequest().finish(),
+        };
+        let mut resp = HttpResponse::Ok();
+        if let Some(len) = req.headers().get(header::CONTENT_LENGTH) {
+            resp.header(header::CONTENT_LENGTH, len.clone());
+        }
+        let chunks = stream::iter(chunks.into_iter().map(Ok::<_, PayloadError>));
+        resp.body(stream_body(content_length(req.headers()), chunks))
+    }
+
+    /// Websocket service echoing binary frames, accepts `echo` subprotocol
+    async fn ws_echo(req: HttpRequest) -> Result<HttpResponse, Error> {
+        let protocol = web::ws::subprotocols(&req)
+            .find(|p| *p == "echo")
+            .map(String::from);
+        web::ws::start(
+            req,
+            protocol,
+            fn_factory_with_config(async |_| {
+                Ok::<_, Error>(fn_service(async |frame| {
+                    Ok::<_, std::io::Error>(match frame {
+                        ws::Frame::Binary(data) => Some(ws::Message::Binary(data)),
+                        _ => None,
+                    })
+                }))
+            }),
+        )
+        .await
+    }
+
+    /// Sends back names of received headers, with hop-by-hop response headers
+    ///
+    /// `Connection` is managed by the http dispatcher itself, headers listed in
+    /// it are covered by unit tests only
+    async fn hop_by_hop(req: HttpRequest) -> HttpResponse {
+        let mut names: Vec<_> = req.headers().keys().map(|h| h.as_str()).collect();
+        names.sort_unstable();
+
+        HttpResponse::Ok()
+            .header("keep-alive", "timeout=5")
+            .header(header::PROXY_AUTHENTICATE, "Basic")
+            .header(header::TRAILER, "x-checksum")
+            .header("x-public", "1")
+            .body(names.join(","))
+    }
+
+    /// Proxy server in front of `upstream`
+    async fn proxy(
+        upstream: &test::TestServer,
+        cfg: ForwardedConfig,
+    ) -> test::TestServer {
+        let upstream = Upstream::new(vec![url(upstream)], Strategy::RoundRobin);
+        proxy_to(Arc::new(upstream), cfg).await
+    }
+
+    /// Proxy server balancing over `upstream`
+    async fn proxy_to(
+        upstream: Arc<Upstream>,
+        cfg: ForwardedConfig,
+    ) -> test::TestServer {
+        proxy_with(Arc::new(Router::new(upstream, Policy::default())), cfg).await
+    }
+
+    /// Proxy server with routing table
+    async fn proxy_with(router: Arc<Router>, cfg: ForwardedConfig) -> test::TestServer {
+        proxy_cached(router, cfg, None).await
+    }
+
+    /// Proxy server with routing table and response cache
+    async fn proxy_cached(
+        router: Arc<Router>,
+        cfg: ForwardedConfig,
+        cache: Option<Arc<Cache>>,
+    ) -> test::TestServer {
+        test::server(async move || {
+            App::new()
+                .state(Clients::new(&router).await)
+                .state(router.clone())
+                .state(cfg.clone())
+                .state(cache.clone())
+                .default_service(web::route().to(forward))
+        })
+        .await
+    }
+
+    fn url(srv: &test::TestServer) -> Url {
+        Url::parse(&format!("http://{}", srv.addr())).unwrap()
+    }
+
+    /// Upstream server that responds with its name, `/health` fails while
+    /// `healthy` is unset
+    async fn named(name: &'static str, healthy: Arc<AtomicBool>) -> test::TestServer {
+        test::server(async move || {
+            let healthy = healthy.clone();
+            App::new()
+                .service(web::resource("/health").to(move || {
+                    let healthy = healthy.load(Ordering::Relaxed);
+                    async move {
+                        if healthy {
+                            HttpResponse::Ok().finish()
+                        } else {
+                            HttpResponse::InternalServerError().finish()
+                        }
+                    }
+                }))
+                .default_service(web::route().to(move || async move { name }))
+        })
+        .await
+    }
+
+    async fn names(srv: &test::TestServer, count: usize) -> Vec<String> {
+        let mut names = Vec::new();
+        for _ in 0..count {
+            let resp = srv.get("/").send().await.unwrap();
+            let status = resp.status();
+            let body = srv.load_body(resp).await.unwrap();
+            names.push(if status.is_success() {
+                String::from_utf8(body.to_vec()).unwrap()
+            } else {
+                status.as_u16().to_string()
+            });
+        }
+        names
+    }
+
+    #[ntex::test]
+    async fn test_forwarded() {
+        let srv = upstream().await;
+
+        // trusted peer, incoming chain is preserved
+        let cfg = ForwardedConfig {
+            trusted: vec!["127.0.0.1".parse().unwrap()],
+            by: Some("_proxy".to_owned()),
+        };
+        let proxy_srv = proxy(&srv, cfg).await;
+        let resp = proxy_srv
+            .get("/")
+            .set_header(header::HOST, "example.com")
+            .header("forwarded", "for=192.0.2.1;proto=https")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            proxy_srv.load_body(resp).await.unwrap(),
+            "for=192.0.2.1;proto=https, for=127.0.0.1;proto=http;host=example.com;by=_proxy\
+             |192.0.2.1, 127.0.0.1"
+        );
+
+        // untrusted peer, forged headers are dropped
+        let proxy_srv = proxy(&srv, ForwardedConfig::default()).await;
+        let resp = proxy_srv
+            .get("/")
+            .set_header(header::HOST, "example.com")
+            .header("forwarded", "for=10.0.0.1")
+            .header(forwarded::X_FORWARDED_FOR, "10.0.0.1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            proxy_srv.load_body(resp).await.unwrap(),
+            "for=127.0.0.1;proto=http;host=example.com|127.0.0.1"
+        );
+    }
+
+    #[ntex::test]
+    async fn test_hop_by_hop() {
+        let srv = upstream().await;
+        let proxy_srv = proxy(&srv, ForwardedConfig::default()).await;
+
+        // ntex client manages `Connection` on its own, so use raw socket
+        let addr = proxy_srv.addr();
+        let response = std::thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(
+                    b"GET /hop HTTP/1.1\r\n\
+                      host: localhost\r\n\
+                      connection: close, x-foo\r\n\
+                      x-foo: 1\r\n\
+                      keep-alive: timeout=5\r\n\
+                      proxy-authorization: Basic Zm9vOmJhcg==\r\n\
+                      te: trailers\r\n\
+                      x-bar: 1\r\n\r\n",
+                )
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response.to_lowercase()
+        })
+        .join()
+        .unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+        assert!(head.starts_with("http/1.1 200 ok"));
+        assert!(head.contains("\r\nx-public: 1"));
+        for name in ["keep-alive", "proxy-authenticate", "trailer"] {
+            assert!(
+                !head.contains(&format!("\r\n{}:", name)),
+                "{} is forwarded",
+                name
+            );
+        }
+
+        let names: Vec<_> = body.split(',').collect();
+        assert!(names.contains(&"x-bar"));
+        for name in ["x-foo", "keep-alive", "proxy-authorization", "te"] {
+            assert!(!names.contains(&name), "{} is forwarded", name);
+        }
+    }
+
+    #[ntex::test]
+    async fn test_streaming() {
+        let srv = upstream().await;
+        let proxy_srv = proxy(&srv, ForwardedConfig::default()).await;
+
+        // well above default payload limits of both client and server
+        let data: Bytes = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        // chunked body stays chunked
+        let chunks: Vec<Result<_, PayloadError>> = data
+            .chunks(MAX_CHUNK_SIZE)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        let resp = proxy_srv
+            .post("/echo")
+            .send_stream(stream::iter(chunks))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(content_length(resp.headers()), None);
+        let body = resp.body().limit(data.len()).await.unwrap();
+        assert!(body == data);
+
+        // sized body keeps its length
+        let resp = proxy_srv
+            .post("/echo")
+            .send_body(data.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(content_length(resp.headers()), Some(data.len() as u64));
+        let body = resp.body().limit(data.len()).await.unwrap();
+        assert!(body == data);
+    }
+
+    #[ntex::test]
+    async fn test_load_balancing() {
+        let a = named("a", Arc::new(AtomicBool::new(true))).await;
+        let b = named("b", Arc::new(AtomicBool::new(true))).await;
+
+        let upstream = Upstream::new(vec![url(&a), url(&b)], Strategy::RoundRobin);
+        let srv = proxy_to(Arc::new(upstream), ForwardedConfig::default()).await;
+        assert_eq!(names(&srv, 4).await, ["a", "b", "a", "b"]);
+
+        let strategy = "hash:x-user".parse().unwrap();
+        let upstream = Arc::new(Upstream::new(vec![url(&a), url(&b)], strategy));
+        let srv = proxy_to(upstream.clone(), ForwardedConfig::default()).await;
+        for user in ["alice", "bob", "carol"] {
+            let mut hdrs = HeaderMap::new();
+            hdrs.insert(
+                header::HeaderName::from_static("x-user"),
+                header::HeaderValue::from_static(user),
+            );
+            let conn = upstream.select(&hdrs).unwrap();
+            let expected = if *conn.backend().url() == url(&a) {
+                "a"
+            } else {
+                "b"
+            };
+            drop(conn);
+
+            for _ in 0..3 {
+                let resp = srv.get("/").header("x-user", user).send().await.unwrap();
+                assert_eq!(srv.load_body(resp).await.unwrap(), expected);
+            }
+        }
+        assert!(upstream.backends().iter().all(|b| b.active() == 0));
+    }
+
+    #[ntex::test]
+    async fn test_health_check() {
+        let a_healthy = Arc::new(AtomicBool::new(true));
+        let b_healthy = Arc::new(AtomicBool::new(true));
+        let a = named("a", a_healthy.clone()).await;
+        let b = named("b", b_healthy.clone()).await;
+
+        let upstream =
+            Arc::new(Upstream::new(vec![url(&a), url(&b)], Strategy::RoundRobin));
+        let health = HealthCheck {
+            path: "/health".to_owned(),
+            interval: Duration::from_millis(25),
+            fails: 2,
+            passes: 1,
+            ..Default::default()
+        };
+        let client = client(health.timeout).await;
+        rt::spawn(health.run(upstream.clone(), client));
+        let srv = proxy_to(upstream.clone(), ForwardedConfig::default()).await;
+
+        // failing backend is ejected
+        b_healthy.store(false, Ordering::Relaxed);
+        ntex::time::sleep(Duration::from_millis(200)).await;
+        assert!(!upstream.backends()[1].is_healthy());
+        assert_eq!(names(&srv, 3).await, ["a", "a", "a"]);
+
+        // no healthy backends left
+        a_healthy.store(false, Ordering::Relaxed);
+        ntex::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(names(&srv, 1).await, ["503"]);
+
+        // and reinstated once it recovers
+        b_healthy.store(true, Ordering::Relaxed);
+        ntex::time::sleep(Duration::from_millis(200)).await;
+        assert!(upstream.backends()[1].is_healthy());
+        assert_eq!(names(&srv, 2).await, ["b", "b"]);
+    }
+
+    #[ntex::test]
+    async fn test_upstream_failures() {
+        let slow = test::server(async || {
+            App::new().default_service(web::route().to(async || {
+                ntex::time::sleep(Duration::from_millis(500)).await;
+                HttpResponse::Ok().finish()
+            }))
+        })
+        .await;
+        let a = named("a", Arc::new(AtomicBool::new(true))).await;
+        let status = async |status| {
+            test::server(async move || {
+                App::new().default_service(
+                    web::route().to(move || async move { HttpResponse::new(status) }),
+                )
+            })
+            .await
+        };
+        let broken = status(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let overloaded = status(StatusCode::SERVICE_UNAVAILABLE).await;
+        // nothing listens on this port
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap()
+        };
+        let proxy = async |urls, policy, breaker| {
+            let upstream = Upstream::new(urls, Strategy::RoundRobin).breaker(breaker);
+            let router = Router::new(Arc::new(upstream), policy);
+            proxy_with(Arc::new(router), ForwardedConfig::default()).await
+        };
+        let policy = Policy {
+            read_timeout: Duration::from_millis(100),
+            retries: 0,
+            retry_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        // slow upstream times out, unreachable one is a bad gateway
+        let srv = proxy(vec![url(&slow)], policy, Breaker::default()).await;
+        assert_eq!(names(&srv, 1).await, ["504"]);
+        let srv = proxy(vec![dead.clone()], policy, Breaker::default()).await;
+        assert_eq!(names(&srv, 1).await, ["502"]);
+
+        // failed idempotent requests are retried on the next backend
+        let retrying = Policy {
+            retries: 1,
+            ..policy
+        };
+        let srv = proxy(vec![dead.clone(), url(&a)], retrying, Breaker::default()).await;
+        assert_eq!(names(&srv, 4).await, ["a", "a", "a", "a"]);
+        // but requests with body are not
+        let resp = srv.post("/").send_body("data").await.unwrap();
+        let resp = match resp.status() {
+            StatusCode::OK => srv.post("/").send_body("data").await.unwrap(),
+            _ => resp,
+        };
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        // gateway errors of the upstream are retried too
+        let srv = proxy(
+            vec![url(&overloaded), url(&a)],
+            retrying,
+            Breaker::default(),
+        )
+        .await;
+        assert_eq!(names(&srv, 4).await, ["a", "a", "a", "a"]);
+        // the last attempt's answer is returned
+        let srv = proxy(vec![url(&overloaded)], retrying, Breaker::default()).await;
+        assert_eq!(names(&srv, 1).await, ["503"]);
+
+        // circuit opens after consecutive failures
+        let breaker = Breaker {
+            failures: 2,
+            cooldown: Duration::from_secs(60),
+        };
+        let srv = proxy(vec![dead], policy, breaker).await;
+        assert_eq!(names(&srv, 2).await, ["502", "502"]);
+        let resp = srv.get("/").send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "60");
+
+        // server errors count as failures
+        let srv = proxy(vec![url(&broken)], policy, breaker).await;
+        assert_eq!(names(&srv, 2).await, ["500", "500"]);
+        let resp = srv.get("/").send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "60");
+    }
+
+    #[ntex::test]
+    async fn test_routing() {
+        let default = named("default", Arc::new(AtomicBool::new(true))).await;
+        let api = test::server(async || {
+            App::new().default_service(
+                web::route()
+                    .to(|req: HttpRequest| async move { format!("api {}", req.uri()) }),
+            )
+        })
+        .await;
+        let admin = named("admin", Arc::new(AtomicBool::new(true))).await;
+
+        let path = std::env::temp_dir()
+            .join(format!("http-proxy-routes-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "[upstreams.api]\nservers = [\"{}\"]\n\
+                 [upstreams.admin]\nservers = [\"{}\"]\n\
+                 [[routes]]\nprefix = \"/api\"\nrewrite = \"/v2\"\nupstream = \"api\"\n\
+                 [[routes]]\nhost = \"admin.local\"\nupstream = \"admin\"\n",
+                api.addr(),
+                admin.addr()
+            ),
+        )
+        .unwrap();
+        let upstream = Upstream::new(vec![url(&default)], Strategy::RoundRobin);
+        let router = Router::load(&path, Arc::new(upstream), Policy::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let srv = proxy_with(Arc::new(router), ForwardedConfig::default()).await;
+
+        let get = |path: &'static str, host: &'static str| {
+            let req = srv.get(path).set_header(header::HOST, host);
+            async { srv.load_body(req.send().await.unwrap()).await.unwrap() }
+        };
+        assert_eq!(
+            get("/api/users?id=1", "localhost").await,
+            "api /v2/users?id=1"
+        );
+        assert_eq!(get("/api", "localhost").await, "api /v2");
+        assert_eq!(get("/apix", "localhost").await, "default");
+        assert_eq!(get("/api/users", "admin.local:8080").await, "admin");
+        assert_eq!(get("/", "localhost").await, "default");
+    }
+
+    #[ntex::test]
+    async fn test_websocket() {
+        let srv = upstream().await;
+        let upstream = Arc::new(Upstream::new(vec![url(&srv)], Strategy::RoundRobin));
+        let proxy_srv = proxy_to(upstream.clone(), ForwardedConfig::default()).await;
+
+        let con = ws::WsClient::builder(format!("http://{}/ws", proxy_srv.addr()))
+            .protocols(["echo"])
+            .build(SharedCfg::default())
+            .await
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        // subprotocol is negotiated with the upstream
+        assert_eq!(
+            con.response()
+                .header(header::SEC_WEBSOCKET_PROTOCOL)
+                .unwrap(),
+            "echo"
+        );
+        assert_eq!(upstream.backends()[0].active(), 1);
+
+        let sink = con.sink();
+        let mut rx = con.seal().receiver();
+        // frames are kept below the write page size, ntex corrupts bigger ones
+        let frames: Vec<Bytes> = (0..64)
+            .map(|n| (0..4000).map(|i| ((i + n) % 251) as u8).collect())
+            .collect();
+        for data in &frames {
+            sink.send(ws::Message::Binary(data.clone())).await.unwrap();
+        }
+        for data in &frames {
+            match rx.next().await {
+                Some(Ok(ws::Frame::Binary(echo))) => assert!(echo == data),
+                frame => panic!("unexpected frame: {:?}", frame),
+            }
+        }
+
+        // tunnel is closed with the client connection
+        sink.io().close();
+        ntex::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(upstream.backends()[0].active(), 0);
+
+        // upstream refuses the upgrade
+        let err = ws::WsClient::builder(format!("http://{}/private", proxy_srv.addr()))
+            .build(SharedCfg::default())
+            .await
+            .unwrap()
+            .connect()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ws::error::WsClientError::InvalidResponseStatus(StatusCode::FORBIDDEN)
+        ));
+    }
+
+    /// Upstream server with cacheable responses, bodies count upstream requests
+    async fn cacheable(requests: Arc<AtomicUsize>) -> test::TestServer {
+        test::server(async move || {
+            let requests = requests.clone();
+            App::new().default_service(web::route().to(move |req: HttpRequest| {
+                let count = requests.fetch_add(1, Ordering::Relaxed) + 1;
+                async move {
+                    let mut res = HttpResponse::Ok();
+                    match req.path() {
+                        "/fresh" => res.header(header::CACHE_CONTROL, "max-age=60"),
+                        "/private" => res.header(header::CACHE_CONTROL, "private"),
+                        "/etag" => {
+                            if req.headers().get(header::IF_NONE_MATCH).is_some() {
+                                return HttpResponse::NotModified()
+                                    .header(header::ETAG, "\"v1\"")
+                                    .finish();
+                            }
+                            res.header(header::CACHE_CONTROL, "no-cache")
+                                .header(header::ETAG, "\"v1\"")
+                        }
+                        // origin stops allowing the response to be stored
+                        "/revoked" if req.headers().contains_key("x-revoke") => {
+                            res.header(header::CACHE_CONTROL, "no-store")
+                        }
+                        "/revoked" => {
+                            if req.headers().get(header::IF_NONE_MATCH).is_some() {
+                                return HttpResponse::NotModified()
+                                    .header(header::ETAG, "\"v1\"")
+                                    .finish();
+                            }
+                            res.header(header::CACHE_CONTROL, "no-cache")
+                                .header(header::ETAG, "\"v1\"")
+                        }
+                        _ => &mut res,
+                    };
+                    res.body(count.to_string())
+                }
+            }))
+        })
+        .await
+    }
+
+    #[ntex::test]
+    async fn test_cache() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let srv = cacheable(requests.clone()).await;
+        let upstream = Upstream::new(vec![url(&srv)], Strategy::RoundRobin);
+        let cache = Arc::new(Cache::new(1024 * 1024));
+        let proxy_srv = proxy_cached(
+            Arc::new(Router::new(Arc::new(upstream), Policy::default())),
+            ForwardedConfig::default(),
+            Some(cache.clone()),
+        )
+        .await;
+
+        let get = |path: &'static str| {
+            let req = proxy_srv.get(path);
+            async {
+                let resp = req.send().await.unwrap();
+                let status = resp.status();
+                (status, proxy_srv.load_body(resp).await.unwrap())
+            }
+        };
+
+        // fresh responses are served from the cache
+        assert_eq!(get("/fresh").await, (StatusCode::OK, "1".into()));
+        assert_eq!(get("/fresh").await, (StatusCode::OK, "1".into()));
+        let resp = proxy_srv
+            .get("/fresh")
+            .header(header::CACHE_CONTROL, "no-store")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(proxy_srv.load_body(resp).await.unwrap(), "2");
+
+        // uncacheable responses are not stored
+        assert_eq!(get("/private").await, (StatusCode::OK, "3".into()));
+        assert_eq!(get("/private").await, (StatusCode::OK, "4".into()));
+        assert_eq!(get("/plain").await, (StatusCode::OK, "5".into()));
+        assert_eq!(get("/plain").await, (StatusCode::OK, "6".into()));
+
+        // stale responses are revalidated, body comes from the cache
+        assert_eq!(get("/etag").await, (StatusCode::OK, "7".into()));
+        assert_eq!(get("/etag").await, (StatusCode::OK, "7".into()));
+        assert_eq!(requests.load(Ordering::Relaxed), 8);
+
+        // clients with matching validators get no body
+        let resp = proxy_srv
+            .get("/fresh")
+            .header(header::IF_NONE_MATCH, "*")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.revalidated, stats.entries), (2, 1, 2));
+
+        // admin endpoint
+        let admin = test::init_service(
+            App::new()
+                .state(Some(cache.clone()))
+                .service(web::resource("/cache").to(cache_stats)),
+        )
+        .await;
+        let resp = test::call_service(
+            &admin,
+            test::TestRequest::with_uri("/cache").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["hits"], 2);
+        assert_eq!(body["revalidated"], 1);
+
+        // stale entry replaced by an uncacheable response is dropped, its
+        // validators are not sent any more
+        assert_eq!(get("/revoked").await, (StatusCode::OK, "9".into()));
+        let resp = proxy_srv
+            .get("/revoked")
+            .header("x-revoke", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(proxy_srv.load_body(resp).await.unwrap(), "10");
+        assert_eq!(get("/revoked").await, (StatusCode::OK, "11".into()));
+    }
+
+    /// Read from `stream` until `end` is received
+    fn read_until(stream: &mut std::net::TcpStream, end: &str) -> String {
+        let mut data = Vec::new();
+        let mut byte = [0; 1];
+        while !data.ends_with(end.as_bytes()) {
+            assert_eq!(stream.read(&mut byte).unwrap(), 1, "connection closed");
+            data.push(byte[0]);
+        }
+        String::from_utf8(data).unwrap().to_lowercase()
+    }
+
+    #[ntex::test]
+    async fn test_upgrade() {
+        // upstream speaking a custom protocol once upgraded, it greets the
+        // client right after its response head and echoes everything else
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_url =
+            Url::parse(&format!("http://{}", listener.local_addr().unwrap()));
+        let echo = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let head = read_until(&mut stream, "\r\n\r\n");
+            stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\n\
+                      connection: upgrade\r\n\
+                      upgrade: echo/1\r\n\
+                      x-echo: 1\r\n\r\nhello",
+                )
+                .unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            head
+        });
+        let upstream = Upstream::new(vec![upstream_url.unwrap()], Strategy::RoundRobin);
+        let proxy_srv = proxy_to(Arc::new(upstream), ForwardedConfig::default()).await;
+
+        let addr = proxy_srv.addr();
+        let (head, data) = std::thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(
+                    b"GET /raw?q=1 HTTP/1.1\r\n\
+                      host: localhost\r\n\
+                      connection: upgrade, x-settings\r\n\
+                      upgrade: echo/1\r\n\
+                      x-settings: abc\r\n\
+                      keep-alive: timeout=5\r\n\r\n",
+                )
+                .unwrap();
+            let head = read_until(&mut stream, "\r\n\r\n");
+            let mut data = read_until(&mut stream, "hello");
+            stream.write_all(b"ping").unwrap();
+            data.push_str(&read_until(&mut stream, "ping"));
+            (head, data)
+        })
+        .join()
+        .unwrap();
+
+        assert!(head.starts_with("http/1.1 101 switching protocols"));
+        assert!(head.contains("\r\nupgrade: echo/1"));
+        assert!(head.contains("\r\nx-echo: 1"));
+        assert_eq!(data, "helloping");
+
+        // upgrade headers reach the upstream, other hop-by-hop ones do not
+        let head = echo.join().unwrap();
+        assert!(head.starts_with("get /raw?q=1 http/1.1"));
+        assert!(head.contains("\r\nupgrade: echo/1"));
+        assert!(head.contains("\r\nconnection: upgrade, x-settings"));
+        assert!(head.contains("\r\nx-settings: abc"));
+        assert!(!head.contains("keep-alive"));
+        assert!(head.contains("\r\nforwarded: for=127.0.0.1"));
+    }
+
+    #[ntex::test]
+    async fn test_upgrade_declined() {
+        // upstream declining upgrades, with bodies framed in every way
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_url =
+            Url::parse(&format!("http://{}", listener.local_addr().unwrap()));
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let head = read_until(&mut stream, "\r\n\r\n");
+                assert!(head.contains("\r\nupgrade: echo/1"));
+                let res: &[u8] = if head.starts_with("get /auth ") {
+                    b"HTTP/1.1 401 Unauthorized\r\n\
+                      www-authenticate: Basic realm=\"echo\"\r\n\
+                      content-length: 6\r\n\r\nlogin!"
+                } else if head.starts_with("get /old ") {
+                    b"HTTP/1.1 426 Upgrade Required\r\n\
+                      upgrade: echo/2\r\n\
+                      connection: upgrade\r\n\
+                      transfer-encoding: chunked\r\n\r\n\
+                      4;ext=1\r\nuse \r\n6\r\necho/2\r\n0\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 403 Forbidden\r\nconnection: close\r\n\r\nnot for you"
+                };
+                stream.write_all(res).unwrap();
+            }
+        });
+        let upstream = Upstream::new(vec![upstream_url.unwrap()], Strategy::RoundRobin);
+        let srv = proxy_to(Arc::new(upstream), ForwardedConfig::default()).await;
+
+        let upgrade = async |path| {
+            let resp = srv
+                .get(path)
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, "echo/1")
+                .send()
+                .await
+                .unwrap();
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            (status, headers, srv.load_body(resp).await.unwrap())
+        };
+
+        let (status, headers, body) = upgrade("/auth").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            headers.get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"echo\""
+        );
+        assert_eq!(body, "login!");
+
+        // protocols the upstream supports are announced to the client
+        let (status, headers, body) = upgrade("/old").await;
+        assert_eq!(status, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(headers.get(header::UPGRADE).unwrap(), "echo/2");
+        assert_eq!(body, "use echo/2");
+
+        let (status, _, body) = upgrade("/private").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body, "not for you");
+    }
+
+    #[ntex::test]
+    async fn test_upgrade_failures() {
+        // accepts connections but never answers
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_url = Url::parse(&format!("http://{}", silent.local_addr().unwrap()));
+        // nothing listens on this port
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap()
+        };
+        let policy = Policy {
+            read_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let breaker = Breaker {
+            failures: 2,
+            cooldown: Duration::from_secs(60),
+        };
+        let proxy = async |url| {
+            let upstream =
+                Upstream::new(vec![url], Strategy::RoundRobin).breaker(breaker);
+            let router = Router::new(Arc::new(upstream), policy);
+            proxy_with(Arc::new(router), ForwardedConfig::default()).await
+        };
+        let upgrade = |srv: &test::TestServer| {
+            let addr = srv.addr();
+            std::thread::spawn(move || {
+                let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                stream
+                    .write_all(
+                        b"GET / HTTP/1.1\r\n\
+                          host: localhost\r\n\
+                          connection: upgrade\r\n\
+                          upgrade: echo/1\r\n\r\n",
+                    )
+                    .unwrap();
+                read_until(&mut stream, "\r\n\r\n")
+            })
+            .join()
+            .unwrap()
+        };
+
+        // upstream not answering in time is a gateway timeout
+        let srv = proxy(silent_url.unwrap()).await;
+        assert!(upgrade(&srv).starts_with("http/1.1 504"));
+
+        // failed upgrades open the circuit
+        let srv = proxy(dead).await;
+        assert!(upgrade(&srv).starts_with("http/1.1 502"));
+        assert!(upgrade(&srv).starts_with("http/1.1 502"));
+        let head = upgrade(&srv);
+        assert!(head.starts_with("http/1.1 503"));
+        assert!(head.contains("\r\nretry-after: 60"));
+    }
+}
@@ -0,0 +1,505 @@
+//! Host and path prefix based routing table.
+//!
+//! The table is loaded from a TOML or JSON file:
+//!
+//! ```toml
+//! [upstreams.api]
+//! servers = ["127.0.0.1:8081", "127.0.0.1:8082"]
+//! balance = "least-conn"
+//! health_check = "/health"
+//!
+//! [upstreams.static]
+//! servers = ["127.0.0.1:9000"]
+//!
+//! [[routes]]
+//! host = "api.example.com"
+//! upstream = "api"
+//!
+//! [[routes]]
+//! prefix = "/assets"
+//! strip_prefix = true
+//! upstream = "static"
+//!
+//! [[routes]]
+//! prefix = "/v1"
+//! rewrite = "/api/v1"
+//! upstream = "api"
+//! read_timeout = 60
+//! retries = 0
+//! ```
+//!
+//! Routes with a host are preferred over routes without one, then the route
+//! with the longest matching prefix wins. Requests that match no route go to
+//! the default upstream.
+use std::collections::HashMap;
+use std::{borrow::Cow, fs, path::Path, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+
+use crate::upstream::{self, Breaker, HealthCheck, Strategy, Upstream};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    upstreams: HashMap<String, UpstreamConfig>,
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamConfig {
+    servers: Vec<String>,
+    balance: Option<String>,
+    health_check: Option<String>,
+    /// Seconds between health checks
+    health_interval: Option<u64>,
+    breaker_failures: Option<usize>,
+    /// Seconds
+    breaker_cooldown: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    host: Option<String>,
+    #[serde(default = "default_prefix")]
+    prefix: String,
+    upstream: String,
+    #[serde(default)]
+    strip_prefix: bool,
+    rewrite: Option<String>,
+    /// Seconds
+    connect_timeout: Option<f64>,
+    /// Seconds
+    read_timeout: Option<f64>,
+    retries: Option<usize>,
+    /// Seconds
+    retry_backoff: Option<f64>,
+}
+
+fn default_prefix() -> String {
+    "/".to_owned()
+}
+
+#[derive(Debug)]
+struct Route {
+    /// Lowercase host name, `*.` prefix matches any subdomain
+    host: Option<String>,
+    /// Path prefix without trailing slash, empty for `/`
+    prefix: String,
+    /// Replacement for the matched prefix
+    rewrite: Option<String>,
+    upstream: Arc<Upstream>,
+    policy: Policy,
+}
+
+impl Route {
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        match (&self.host, host) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(pattern), Some(host)) => match pattern.strip_prefix('*') {
+                Some(suffix) => host.ends_with(suffix) && host.len() > suffix.len(),
+                None => pattern == host,
+            },
+        }
+    }
+
+    /// Remaining path after the prefix, if prefix matches on a segment
+    /// boundary.
+    fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+}
+
+/// How requests are sent to the upstream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    /// Time allowed to establish a connection.
+    pub connect_timeout: Duration,
+    /// Time allowed for the response headers to arrive once connected.
+    pub read_timeout: Duration,
+    /// Attempts after the first one, for idempotent requests without body.
+    pub retries: usize,
+    /// Delay before the first retry, doubled for each next one.
+    pub retry_backoff: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+            retries: 2,
+            retry_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+fn seconds(name: &str, secs: Option<f64>) -> Result<Option<Duration>, String> {
+    secs.map(|secs| {
+        Duration::try_from_secs_f64(secs)
+            .map_err(|_| format!("invalid {}: {}", name, secs))
+    })
+    .transpose()
+}
+
+/// Request destination selected by the [`Router`].
+pub struct Target<'a> {
+    pub upstream: &'a Arc<Upstream>,
+    /// Path to request from the upstream
+    pub path: Cow<'a, str>,
+    pub policy: &'a Policy,
+}
+
+/// Routing table, maps requests to upstream groups.
+#[derive(Debug)]
+pub struct Router {
+    routes: Vec<Route>,
+    default: Arc<Upstream>,
+    policy: Policy,
+    health_checks: Vec<(Arc<Upstream>, HealthCheck)>,
+}
+
+impl Router {
+    /// Router that sends every request to `upstream`.
+    ///
+    /// `policy` applies to every route that does not override it.
+    pub fn new(default: Arc<Upstream>, policy: Policy) -> Self {
+        Router {
+            default,
+            policy,
+            routes: Vec::new(),
+            health_checks: Vec::new(),
+        }
+    }
+
+    /// Load routing table from a `.toml` or `.json` file.
+    pub fn load(
+        path: &Path,
+        default: Arc<Upstream>,
+        policy: Policy,
+    ) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let config: Config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&content).map_err(|e| e.to_string())?,
+            _ => toml::from_str(&content).map_err(|e| e.to_string())?,
+        };
+        Self::from_config(config, default, policy)
+            .map_err(|e| format!("invalid routing table {}: {}", path.display(), e))
+    }
+
+    fn from_config(
+        config: Config,
+        default: Arc<Upstream>,
+        policy: Policy,
+    ) -> Result<Self, String> {
+        let mut router = Router::new(default, policy);
+
+        let mut upstreams = HashMap::new();
+        for (name, cfg) in config.upstreams {
+            if cfg.servers.is_empty() {
+                return Err(format!("upstream {:?} has no servers", name));
+            }
+            let urls = cfg
+                .servers
+                .iter()
+                .map(|addr| upstream::resolve(addr))
+                .collect::<Result<_, _>>()?;
+            let strategy = match cfg.balance {
+                Some(s) => s.parse()?,
+                None => Strategy::RoundRobin,
+            };
+            let mut breaker = Breaker::default();
+            if let Some(failures) = cfg.breaker_failures {
+                breaker.failures = failures;
+            }
+            if let Some(cooldown) = seconds("breaker_cooldown", cfg.breaker_cooldown)? {
+                breaker.cooldown = cooldown;
+            }
+            let group = Arc::new(Upstream::new(urls, strategy).breaker(breaker));
+
+            if let Some(path) = cfg.health_check {
+                let mut health = HealthCheck {
+                    path,
+                    ..Default::default()
+                };
+                if let Some(secs) = cfg.health_interval {
+                    health.interval = Duration::from_secs(secs);
+                }
+                router.health_checks.push((group.clone(), health));
+            }
+            upstreams.insert(name, group);
+        }
+
+        for cfg in config.routes {
+            let upstream = upstreams
+                .get(&cfg.upstream)
+                .ok_or_else(|| format!("unknown upstream {:?}", cfg.upstream))?
+                .clone();
+            if !cfg.prefix.starts_with('/') {
+                return Err(format!("prefix {:?} must start with '/'", cfg.prefix));
+            }
+            let rewrite = match (cfg.strip_prefix, cfg.rewrite) {
+                (true, Some(_)) => {
+                    return Err(format!(
+                        "route for {:?} sets both strip_prefix and rewrite",
+                        cfg.prefix
+                    ));
+                }
+                (true, None) => Some(String::new()),
+                (false, rewrite) => rewrite.map(|r| r.trim_end_matches('/').to_owned()),
+            };
+            let mut policy = policy;
+            if let Some(timeout) = seconds("connect_timeout", cfg.connect_timeout)? {
+                policy.connect_timeout = timeout;
+            }
+            if let Some(timeout) = seconds("read_timeout", cfg.read_timeout)? {
+                policy.read_timeout = timeout;
+            }
+            if let Some(retries) = cfg.retries {
+                policy.retries = retries;
+            }
+            if let Some(backoff) = seconds("retry_backoff", cfg.retry_backoff)? {
+                policy.retry_backoff = backoff;
+            }
+            router.routes.push(Route {
+                upstream,
+                rewrite,
+                policy,
+                host: cfg.host.map(|h| h.to_ascii_lowercase()),
+                prefix: cfg.prefix.trim_end_matches('/').to_owned(),
+            });
+        }
+
+        // most specific routes first
+        router
+            .routes
+            .sort_by_key(|r| (r.host.is_none(), std::cmp::Reverse(r.prefix.len())));
+        Ok(router)
+    }
+
+    /// Connect timeouts used by any route.
+    pub fn connect_timeouts(&self) -> Vec<Duration> {
+        let mut timeouts: Vec<_> = self
+            .routes
+            .iter()
+            .map(|r| r.policy.connect_timeout)
+            .chain(Some(self.policy.connect_timeout))
+            .collect();
+        timeouts.sort_unstable();
+        timeouts.dedup();
+        timeouts
+    }
+
+    /// Upstream groups that have active health checks configured.
+    pub fn health_checks(&self) -> &[(Arc<Upstream>, HealthCheck)] {
+        &self.health_checks
+    }
+
+    /// Select upstream and path for a request.
+    pub fn route<'a>(&'a self, host: Option<&str>, path: &'a str) -> Target<'a> {
+        // host header may carry a port
+        let host = host.map(|h| {
+            let h = h.rsplit_once(':').map_or(h, |(name, port)| {
+                if port.bytes().all(|b| b.is_ascii_digit()) {
+                    name
+                } else {
+                    h
+                }
+            });
+            h.to_ascii_lowercase()
+        });
+
+        for route in &self.routes {
+            if !route.matches_host(host.as_deref()) {
+                continue;
+            }
+            if let Some(rest) = route.strip(path) {
+                let path = match route.rewrite {
+                    Some(ref rewrite) if rewrite.is_empty() && rest.is_empty() => {
+                        Cow::Borrowed("/")
+                    }
+                    Some(ref rewrite) => Cow::Owned(format!("{}{}", rewrite, rest)),
+                    None => Cow::Borrowed(path),
+                };
+                return Target {
+                    path,
+                    upstream: &route.upstream,
+                    policy: &route.policy,
+                };
+            }
+        }
+
+        Target {
+            upstream: &self.default,
+            path: Cow::Borrowed(path),
+            policy: &self.policy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [upstreams.api]
+        servers = ["127.0.0.1:8081", "127.0.0.1:8082"]
+        balance = "least-conn"
+        health_check = "/health"
+        health_interval = 1
+
+        [upstreams.assets]
+        servers = ["127.0.0.1:9000"]
+
+        [upstreams.admin]
+        servers = ["127.0.0.1:9100"]
+
+        [[routes]]
+        prefix = "/assets/"
+        strip_prefix = true
+        upstream = "assets"
+
+        [[routes]]
+        prefix = "/v1"
+        rewrite = "/api/v1/"
+        upstream = "api"
+        connect_timeout = 0.5
+        retries = 0
+
+        [[routes]]
+        host = "admin.example.com"
+        upstream = "admin"
+
+        [[routes]]
+        host = "*.example.com"
+        prefix = "/assets"
+        upstream = "api"
+    "#;
+
+    fn router(config: &str) -> Result<Router, String> {
+        let default = Upstream::new(
+            vec![upstream::resolve("127.0.0.1:8080").unwrap()],
+            Strategy::RoundRobin,
+        );
+        let config = toml::from_str(config).map_err(|e| e.to_string())?;
+        Router::from_config(config, Arc::new(default), Policy::default())
+    }
+
+    fn route(router: &Router, host: Option<&str>, path: &str) -> (u16, String) {
+        let target = router.route(host, path);
+        let port = target.upstream.backends()[0].url().port().unwrap();
+        (port, target.path.into_owned())
+    }
+
+    #[test]
+    fn test_route() {
+        let router = router(CONFIG).unwrap();
+        assert_eq!(router.health_checks().len(), 1);
+        assert_eq!(router.health_checks()[0].1.interval, Duration::from_secs(1));
+
+        // route policy overrides defaults
+        let policy = router.route(None, "/v1").policy;
+        assert_eq!(policy.connect_timeout, Duration::from_millis(500));
+        assert_eq!(policy.retries, 0);
+        assert_eq!(policy.read_timeout, Policy::default().read_timeout);
+        assert_eq!(router.route(None, "/").policy, &Policy::default());
+        assert_eq!(
+            router.connect_timeouts(),
+            [Duration::from_millis(500), Duration::from_secs(5)]
+        );
+
+        // unmatched requests go to default upstream
+        assert_eq!(route(&router, None, "/"), (8080, "/".to_owned()));
+        assert_eq!(route(&router, None, "/v2"), (8080, "/v2".to_owned()));
+        // prefix matches on segment boundary only
+        assert_eq!(route(&router, None, "/v1x"), (8080, "/v1x".to_owned()));
+
+        // strip and rewrite
+        assert_eq!(route(&router, None, "/assets"), (9000, "/".to_owned()));
+        assert_eq!(
+            route(&router, None, "/assets/img/logo.png"),
+            (9000, "/img/logo.png".to_owned())
+        );
+        assert_eq!(route(&router, None, "/v1"), (8081, "/api/v1".to_owned()));
+        assert_eq!(
+            route(&router, None, "/v1/users"),
+            (8081, "/api/v1/users".to_owned())
+        );
+
+        // host routes win over prefix routes, port and case are ignored
+        assert_eq!(
+            route(&router, Some("Admin.Example.com:8080"), "/v1/users"),
+            (9100, "/v1/users".to_owned())
+        );
+        assert_eq!(
+            route(&router, Some("cdn.example.com"), "/assets/a.css"),
+            (8081, "/assets/a.css".to_owned())
+        );
+        assert_eq!(
+            route(&router, Some("example.com"), "/assets/a.css"),
+            (9000, "/a.css".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let err = router("[[routes]]\nupstream = \"missing\"").unwrap_err();
+        assert!(err.contains("unknown upstream"), "{}", err);
+
+        let err = router("[upstreams.a]\nservers = []").unwrap_err();
+        assert!(err.contains("no servers"), "{}", err);
+
+        let err =
+            router("[upstreams.a]\nservers = [\"127.0.0.1:1\"]\nbalance = \"random\"")
+                .unwrap_err();
+        assert!(err.contains("unknown strategy"), "{}", err);
+
+        let err = router(
+            "[upstreams.a]\nservers = [\"127.0.0.1:1\"]\n\
+             [[routes]]\nprefix = \"/a\"\nstrip_prefix = true\nrewrite = \"/b\"\nupstream = \"a\"",
+        )
+        .unwrap_err();
+        assert!(err.contains("both strip_prefix and rewrite"), "{}", err);
+
+        let err = router(
+            "[upstreams.a]\nservers = [\"127.0.0.1:1\"]\n\
+             [[routes]]\nupstream = \"a\"\nread_timeout = -1",
+        )
+        .unwrap_err();
+        assert!(err.contains("invalid read_timeout"), "{}", err);
+
+        assert!(router("[[routes]]\nprefix = \"/a\"\nupstrem = \"a\"").is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let path =
+            std::env::temp_dir().join(format!("routes-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{
+                "upstreams": {"api": {"servers": ["127.0.0.1:8081"]}},
+                "routes": [{"prefix": "/api", "strip_prefix": true, "upstream": "api"}]
+            }"#,
+        )
+        .unwrap();
+        let default = Upstream::new(
+            vec![upstream::resolve("127.0.0.1:8080").unwrap()],
+            Strategy::RoundRobin,
+        );
+        let router = Router::load(&path, Arc::new(default), Policy::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(route(&router, None, "/api/x"), (8081, "/x".to_owned()));
+    }
+}
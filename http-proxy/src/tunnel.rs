@@ -0,0 +1,391 @@
+//! Protocol upgrade tunnelling.
+//!
+//! Requests asking for an upgrade (WebSocket, h2c or any other protocol) are
+//! sent to the upstream on a connection of their own. Once the upstream
+//! switches protocols, its `101` response is passed to the client and raw
+//! bytes are copied between both connections. The upgraded protocol is never
+//! decoded, so WebSocket masking, extensions and subprotocols are handled
+//! end-to-end by the client and the upstream.
+use std::pin::pin;
+
+use futures::future::select;
+use futures::stream;
+use ntex::client::error::{ClientError, ConnectError};
+use ntex::codec::BytesCodec;
+use ntex::http::body::BodySize;
+use ntex::http::error::{DecodeError, PayloadError};
+use ntex::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use ntex::http::{h1, Method, RequestHead, StatusCode};
+use ntex::io::IoBoxed;
+use ntex::time::timeout;
+use ntex::util::{Bytes, BytesMut};
+use ntex::web::{Error, HttpRequest, HttpResponse};
+use ntex::{rt, SharedCfg};
+use url::{Position, Url};
+
+use crate::routing::Policy;
+use crate::upstream::{Connection, Upstream};
+use crate::{content_length, hop, stream_body, upstream_error};
+
+/// Largest upstream response head accepted.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Most headers accepted in an upstream response head.
+const MAX_HEADERS: usize = 96;
+
+/// Whether request asks for a protocol upgrade.
+///
+/// Requests with a body are not upgrades, upstreams get them as plain
+/// requests without the `Upgrade` header.
+pub fn is_upgrade(head: &RequestHead) -> bool {
+    head.upgrade() && head.headers.contains_key(header::UPGRADE)
+}
+
+/// Send upgrade request to `url` and splice the connections once the upstream
+/// switches protocols.
+///
+/// `headers` are end-to-end headers of the request. Connecting and waiting
+/// for the response head are limited by the `policy` timeouts, the outcome is
+/// reported to `upstream`. The upstream `conn` is held until the tunnel is
+/// closed.
+pub async fn start(
+    req: &HttpRequest,
+    url: &Url,
+    mut headers: HeaderMap,
+    upstream: &Upstream,
+    conn: Connection,
+    policy: &Policy,
+) -> Result<HttpResponse, Error> {
+    upgrade_headers(req.headers(), &mut headers);
+
+    let (server, status, mut server_headers, rest) =
+        match handshake(req, url, &headers, policy).await {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                upstream.report(&conn, false);
+                println!("Upstream {} upgrade failed: {}", conn.backend().url(), err);
+                return Ok(upstream_error(&err));
+            }
+        };
+    upstream.report(&conn, !status.is_server_error());
+    // upstream declined the upgrade, its answer is passed on as a plain response
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(declined(req, status, server_headers, server, rest, conn));
+    }
+
+    // the upgraded protocol, negotiated subprotocol and other headers go back
+    // to the client
+    let mut res = HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS);
+    if let Some(protocol) = server_headers.get(header::UPGRADE) {
+        res.upgrade(protocol.clone());
+    }
+    hop::remove_hop_by_hop(&mut server_headers);
+    for (name, value) in &server_headers {
+        res.header(name.clone(), value.clone());
+    }
+    let res = res.finish().into_parts().0;
+
+    let Some((io, codec)) = req.head().take_io() else {
+        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST));
+    };
+    if io
+        .encode(h1::Message::Item((res, BodySize::Empty)), &codec)
+        .is_err()
+    {
+        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST));
+    }
+    // the h1 dispatcher may have started a headers-read timer on this io
+    io.stop_timer();
+
+    rt::spawn(async move {
+        // upstream may speak the new protocol right after its response head
+        if rest.is_empty() || io.send(rest, &BytesCodec).await.is_ok() {
+            splice(&io, &server).await;
+        }
+        io.close();
+        server.close();
+        drop(conn);
+    });
+
+    // the connection is taken over, this response is not sent
+    Ok(HttpResponse::new(StatusCode::SWITCHING_PROTOCOLS))
+}
+
+/// Connect to `url`, send the upgrade request and read the response head.
+///
+/// Returns the upstream connection, response status, headers and the data
+/// received after the head.
+async fn handshake(
+    req: &HttpRequest,
+    url: &Url,
+    headers: &HeaderMap,
+    policy: &Policy,
+) -> Result<(IoBoxed, StatusCode, HeaderMap, Bytes), ClientError> {
+    let server = timeout(policy.connect_timeout, connect(url))
+        .await
+        .map_err(|_| ConnectError::Timeout)??;
+    server
+        .send(request_head(req, url, headers), &BytesCodec)
+        .await
+        .map_err(|e| ClientError::Send(e.into_inner()))?;
+    let (status, headers, rest) = timeout(policy.read_timeout, response_head(&server))
+        .await
+        .map_err(|_| ClientError::Timeout)??;
+    Ok((server, status, headers, rest))
+}
+
+/// Response of an upstream that did not switch protocols.
+///
+/// Headers other than hop-by-hop ones and the body go to the client, the
+/// upstream connection is closed once the body is read.
+fn declined(
+    req: &HttpRequest,
+    status: StatusCode,
+    mut headers: HeaderMap,
+    server: IoBoxed,
+    rest: Bytes,
+    conn: Connection,
+) -> HttpResponse {
+    let framing = if req.method() == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        None
+    } else if headers
+        .get(header::TRANSFER_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.to_ascii_lowercase().ends_with("chunked"))
+    {
+        Some(Framing::Chunked(None))
+    } else {
+        match content_length(&headers) {
+            Some(len) => Some(Framing::Length(len)),
+            None => Some(Framing::Close),
+        }
+    };
+    // protocols offered by `426 Upgrade Required` are for the client
+    let offered = match status {
+        StatusCode::UPGRADE_REQUIRED => headers.get(header::UPGRADE).cloned(),
+        _ => None,
+    };
+    hop::remove_hop_by_hop(&mut headers);
+
+    let mut res = HttpResponse::build(status);
+    if let Some(offered) = offered {
+        res.header(header::UPGRADE, offered);
+    }
+    for (name, value) in &headers {
+        res.header(name.clone(), value.clone());
+    }
+    let Some(framing) = framing else {
+        server.close();
+        return res.finish();
+    };
+    let size = match framing {
+        Framing::Length(len) => Some(len),
+        _ => None,
+    };
+    let body = Payload {
+        io: server,
+        buf: BytesMut::from(&rest[..]),
+        framing,
+        _conn: conn,
+    };
+    let stream = stream::unfold(body, |mut body| async move {
+        let item = body.next().await?;
+        Some((item, body))
+    });
+    res.body(stream_body(size, Box::pin(stream)))
+}
+
+/// How the end of a response body is found.
+enum Framing {
+    /// Bytes left to read
+    Length(u64),
+    /// Bytes left of the current chunk, `None` before its size line
+    Chunked(Option<u64>),
+    /// Body ends when the connection is closed
+    Close,
+    Done,
+}
+
+/// Body of an upstream response read from its connection.
+struct Payload {
+    io: IoBoxed,
+    /// Data received and not yet returned
+    buf: BytesMut,
+    framing: Framing,
+    /// The upstream connection is in use until the body is read
+    _conn: Connection,
+}
+
+impl Payload {
+    async fn next(&mut self) -> Option<Result<Bytes, PayloadError>> {
+        let item = self.decode().await;
+        if !matches!(item, Some(Ok(_))) {
+            self.framing = Framing::Done;
+            self.io.close();
+        }
+        item
+    }
+
+    async fn decode(&mut self) -> Option<Result<Bytes, PayloadError>> {
+        loop {
+            match self.framing {
+                Framing::Done => return None,
+                Framing::Length(0) => return None,
+                Framing::Length(left) if !self.buf.is_empty() => {
+                    let len = left.min(self.buf.len() as u64);
+                    self.framing = Framing::Length(left - len);
+                    return Some(Ok(self.buf.split_to(len as usize)));
+                }
+                Framing::Close if !self.buf.is_empty() => {
+                    let len = self.buf.len();
+                    return Some(Ok(self.buf.split_to(len)));
+                }
+                Framing::Chunked(None) => match httparse::parse_chunk_size(&self.buf) {
+                    Ok(httparse::Status::Complete((_, 0))) => return None,
+                    Ok(httparse::Status::Complete((len, size))) => {
+                        let _ = self.buf.split_to(len);
+                        self.framing = Framing::Chunked(Some(size));
+                        continue;
+                    }
+                    Ok(httparse::Status::Partial) => (),
+                    Err(_) => {
+                        return Some(Err(PayloadError::EncodingCorrupted));
+                    }
+                },
+                // chunk data is followed by CRLF
+                Framing::Chunked(Some(0)) if self.buf.len() >= 2 => {
+                    if &self.buf[..2] != b"\r\n" {
+                        return Some(Err(PayloadError::EncodingCorrupted));
+                    }
+                    let _ = self.buf.split_to(2);
+                    self.framing = Framing::Chunked(None);
+                    continue;
+                }
+                Framing::Chunked(Some(left)) if left > 0 && !self.buf.is_empty() => {
+                    let len = left.min(self.buf.len() as u64);
+                    self.framing = Framing::Chunked(Some(left - len));
+                    return Some(Ok(self.buf.split_to(len as usize)));
+                }
+                _ => (),
+            }
+
+            match self.io.recv(&BytesCodec).await {
+                Ok(Some(chunk)) => self.buf.extend_from_slice(&chunk),
+                Ok(None) if matches!(self.framing, Framing::Close) => return None,
+                Ok(None) => return Some(Err(PayloadError::Incomplete(None))),
+                Err(e) => return Some(Err(PayloadError::Io(e.into_inner()))),
+            }
+        }
+    }
+}
+
+/// Put back headers of the upgrade that were removed as hop-by-hop.
+///
+/// `Upgrade`, `Connection` and the headers it lists, like `HTTP2-Settings` of
+/// h2c, are meant for the server switching protocols.
+fn upgrade_headers(original: &HeaderMap, headers: &mut HeaderMap) {
+    let listed = hop::connection_options(original)
+        .into_iter()
+        .filter(|name| !hop::is_hop_by_hop(name))
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok());
+    for name in listed.chain([header::UPGRADE, header::CONNECTION]) {
+        for value in original.get_all(&name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+async fn connect(url: &Url) -> Result<IoBoxed, ConnectError> {
+    let addr = url
+        .socket_addrs(|| None)
+        .map_err(ConnectError::Resolver)?
+        .into_iter()
+        .next()
+        .ok_or(ConnectError::NoRecords)?;
+    let io = rt::tcp_connect(addr, SharedCfg::default())
+        .await
+        .map_err(|e| ConnectError::Disconnected(Some(e)))?;
+    Ok(IoBoxed::from(io))
+}
+
+fn request_head(req: &HttpRequest, url: &Url, headers: &HeaderMap) -> Bytes {
+    let mut buf = BytesMut::new();
+    let target = &url[Position::BeforePath..];
+    buf.extend_from_slice(
+        format!("{} {} HTTP/1.1\r\n", req.method(), target).as_bytes(),
+    );
+    for (name, value) in headers {
+        buf.extend_from_slice(name.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf.extend_from_slice(b"\r\n");
+    buf.freeze()
+}
+
+/// Read response head of the upstream.
+///
+/// Returns status, headers and the data received after the head.
+async fn response_head(
+    io: &IoBoxed,
+) -> Result<(StatusCode, HeaderMap, Bytes), ClientError> {
+    let mut buf = BytesMut::new();
+    loop {
+        let chunk = io
+            .recv(&BytesCodec)
+            .await
+            .map_err(|e| ConnectError::Disconnected(Some(e.into_inner())))?
+            .ok_or(DecodeError::Incomplete)?;
+        buf.extend_from_slice(&chunk);
+
+        let mut parsed = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut res = httparse::Response::new(&mut parsed);
+        match res.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => {
+                let status = res
+                    .code
+                    .and_then(|code| StatusCode::from_u16(code).ok())
+                    .ok_or(DecodeError::Status)?;
+                let mut headers = HeaderMap::new();
+                for h in res.headers.iter() {
+                    headers.append(
+                        HeaderName::from_bytes(h.name.as_bytes())
+                            .map_err(|_| DecodeError::Header)?,
+                        HeaderValue::from_bytes(h.value)
+                            .map_err(|_| DecodeError::Header)?,
+                    );
+                }
+                let _ = buf.split_to(len);
+                return Ok((status, headers, buf.freeze()));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_SIZE => (),
+            Ok(httparse::Status::Partial) => {
+                return Err(DecodeError::TooLarge(buf.len()).into())
+            }
+            Err(httparse::Error::TooManyHeaders) => {
+                return Err(DecodeError::MaxHeaders.into())
+            }
+            Err(_) => {
+                return Err(DecodeError::InvalidInput("invalid response head").into())
+            }
+        }
+    }
+}
+
+/// Copy data in both directions until either side is closed.
+async fn splice(a: &IoBoxed, b: &IoBoxed) {
+    select(pin!(pipe(a, b)), pin!(pipe(b, a))).await;
+}
+
+async fn pipe(from: &IoBoxed, to: &IoBoxed) {
+    while let Ok(Some(chunk)) = from.recv(&BytesCodec).await {
+        if to.send(chunk, &BytesCodec).await.is_err() {
+            break;
+        }
+    }
+}
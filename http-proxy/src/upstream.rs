@@ -0,0 +1,574 @@
+//! Pool of upstream servers.
+//!
+//! Requests are spread over healthy backends with one of the balancing
+//! strategies, a background task periodically probes every backend and
+//! ejects or reinstates it depending on the outcome. Backends that keep
+//! failing requests are skipped by a circuit breaker for a while.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{net::ToSocketAddrs, str::FromStr};
+
+use futures::future::join_all;
+use ntex::client::Client;
+use ntex::http::header::{HeaderMap, HeaderName};
+use ntex::time::sleep;
+use url::Url;
+
+/// Resolve `host:port` address of an upstream server to its url.
+pub fn resolve(addr: &str) -> Result<Url, String> {
+    let addr = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {:?}: {}", addr, e))?
+        .next()
+        .ok_or_else(|| format!("cannot resolve {:?}", addr))?;
+    Url::parse(&format!("http://{}", addr)).map_err(|e| e.to_string())
+}
+
+/// Number of points each backend occupies on the consistent hash ring.
+const RING_REPLICAS: usize = 64;
+
+/// How requests are assigned to backends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Healthy backends take turns.
+    RoundRobin,
+    /// Backend with the fewest in-flight requests is used.
+    LeastConnections,
+    /// Requests with the same header value go to the same backend, requests
+    /// without the header are balanced round-robin.
+    ConsistentHash(HeaderName),
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-conn" => Ok(Strategy::LeastConnections),
+            _ => match s.strip_prefix("hash:") {
+                Some(name) => HeaderName::from_str(name)
+                    .map(Strategy::ConsistentHash)
+                    .map_err(|_| format!("invalid header name: {:?}", name)),
+                None => Err(format!(
+                    "unknown strategy {:?}, expected round-robin, least-conn or hash:<header>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Circuit breaker settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breaker {
+    /// Consecutive failed requests that open the circuit.
+    pub failures: usize,
+    /// Time the backend is skipped once the circuit is open.
+    pub cooldown: Duration,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker {
+            failures: 5,
+            cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Single upstream server.
+#[derive(Debug)]
+pub struct Backend {
+    url: Url,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+    /// Consecutive failed requests
+    failures: AtomicUsize,
+    /// Circuit is open until this time
+    open_until: Mutex<Option<Instant>>,
+    /// A request probes the half-open circuit
+    probing: AtomicBool,
+}
+
+impl Backend {
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Remaining time the circuit stays open.
+    pub fn open_for(&self) -> Option<Duration> {
+        let open_until = (*self.open_until.lock().unwrap())?;
+        open_until
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Whether the cooldown of an open circuit is over.
+    fn is_half_open(&self) -> bool {
+        self.open_until.lock().unwrap().is_some() && self.open_for().is_none()
+    }
+
+    /// Whether requests may be sent to the backend.
+    ///
+    /// Once the cooldown is over the circuit is half-open, a single request
+    /// probes the backend and other requests are kept away until it reports.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy()
+            && self.open_for().is_none()
+            && !(self.is_half_open() && self.probing.load(Ordering::Acquire))
+    }
+
+    /// Take the backend for a request, `Some(true)` if the request probes a
+    /// half-open circuit and `None` if the backend is not available.
+    fn claim(&self) -> Option<bool> {
+        if !self.is_available() {
+            return None;
+        }
+        if !self.is_half_open() {
+            return Some(false);
+        }
+        self.probing
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| true)
+    }
+
+    /// Number of in-flight requests.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// Backend selected for a request.
+///
+/// Counts as an in-flight request of the backend until dropped. A probe of a
+/// half-open circuit lets the next request probe if dropped without a report.
+#[derive(Debug)]
+pub struct Connection {
+    backend: Arc<Backend>,
+    probe: bool,
+}
+
+impl Connection {
+    fn new(backend: &Arc<Backend>, probe: bool) -> Self {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Connection {
+            backend: backend.clone(),
+            probe,
+        }
+    }
+
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+        if self.probe {
+            self.backend.probing.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Group of backends serving the same content.
+#[derive(Debug)]
+pub struct Upstream {
+    backends: Vec<Arc<Backend>>,
+    strategy: Strategy,
+    breaker: Breaker,
+    next: AtomicUsize,
+    /// Consistent hash ring, sorted points with backend indexes
+    ring: Vec<(u64, usize)>,
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Upstream {
+    pub fn new(urls: Vec<Url>, strategy: Strategy) -> Self {
+        let backends: Vec<_> = urls
+            .into_iter()
+            .map(|url| {
+                Arc::new(Backend {
+                    url,
+                    healthy: AtomicBool::new(true),
+                    active: AtomicUsize::new(0),
+                    failures: AtomicUsize::new(0),
+                    open_until: Mutex::new(None),
+                    probing: AtomicBool::new(false),
+                })
+            })
+            .collect();
+
+        let mut ring = Vec::new();
+        if let Strategy::ConsistentHash(_) = strategy {
+            for (idx, backend) in backends.iter().enumerate() {
+                for replica in 0..RING_REPLICAS {
+                    ring.push((hash(&(backend.url.as_str(), replica)), idx));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        Upstream {
+            backends,
+            strategy,
+            ring,
+            breaker: Breaker::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Use circuit breaker settings.
+    pub fn breaker(mut self, breaker: Breaker) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    /// Record outcome of a request sent to the backend of `conn`.
+    pub fn report(&self, conn: &Connection, ok: bool) {
+        let backend = conn.backend();
+        if ok {
+            backend.failures.store(0, Ordering::Relaxed);
+            *backend.open_until.lock().unwrap() = None;
+        } else {
+            let failures = backend.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= self.breaker.failures {
+                if backend.open_for().is_none() {
+                    println!("Upstream {} keeps failing, opening circuit", backend.url);
+                }
+                *backend.open_until.lock().unwrap() =
+                    Some(Instant::now() + self.breaker.cooldown);
+            }
+        }
+        if conn.probe {
+            backend.probing.store(false, Ordering::Release);
+        }
+    }
+
+    /// Time until a healthy backend with open circuit becomes available.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.backends
+            .iter()
+            .filter(|b| b.is_healthy())
+            .filter_map(|b| b.open_for())
+            .min()
+    }
+
+    /// Select available backend for a request with `headers`.
+    ///
+    /// Returns `None` if every backend is ejected, its circuit is open or
+    /// already probed.
+    pub fn select(&self, headers: &HeaderMap) -> Option<Connection> {
+        let backend = match self.strategy {
+            Strategy::RoundRobin => self.round_robin(),
+            Strategy::LeastConnections => self.least_connections(),
+            Strategy::ConsistentHash(ref name) => match headers.get(name) {
+                Some(value) => self.consistent_hash(hash(value.as_bytes())),
+                None => self.round_robin(),
+            },
+        };
+        backend.map(|(backend, probe)| Connection::new(backend, probe))
+    }
+
+    fn round_robin(&self) -> Option<(&Arc<Backend>, bool)> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.backends.len();
+        (0..len)
+            .map(|i| &self.backends[(start + i) % len])
+            .find_map(|b| b.claim().map(|probe| (b, probe)))
+    }
+
+    fn least_connections(&self) -> Option<(&Arc<Backend>, bool)> {
+        // start from a rotating position, so ties do not always pick the
+        // first backend
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.backends.len();
+        // a backend probed meanwhile is not available on the next round
+        loop {
+            let backend = (0..len)
+                .map(|i| &self.backends[(start + i) % len])
+                .filter(|b| b.is_available())
+                .min_by_key(|b| b.active())?;
+            if let Some(probe) = backend.claim() {
+                return Some((backend, probe));
+            }
+        }
+    }
+
+    fn consistent_hash(&self, key: u64) -> Option<(&Arc<Backend>, bool)> {
+        // first point after the key, then clockwise skipping ejected backends
+        let start = self.ring.partition_point(|(point, _)| *point < key);
+        let len = self.ring.len();
+        (0..len)
+            .map(|i| &self.backends[self.ring[(start + i) % len].1])
+            .find_map(|b| b.claim().map(|probe| (b, probe)))
+    }
+}
+
+/// Active health check settings.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// Path requested with `GET`, any 2xx response is a success.
+    pub path: String,
+    /// Delay between two rounds of checks.
+    pub interval: Duration,
+    /// Time allowed for a single check.
+    pub timeout: Duration,
+    /// Consecutive failures before a backend is ejected.
+    pub fails: usize,
+    /// Consecutive successes before an ejected backend is reinstated.
+    pub passes: usize,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            path: "/".to_owned(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            fails: 2,
+            passes: 2,
+        }
+    }
+}
+
+impl HealthCheck {
+    async fn probe(&self, client: &Client, backend: &Backend) -> bool {
+        let mut url = backend.url.clone();
+        url.set_path(&self.path);
+
+        match client.get(url.as_str()).timeout(self.timeout).send().await {
+            Ok(res) => res.status().is_success(),
+            Err(_) => false,
+        }
+    }
+
+    /// Probe all backends of `upstream` forever.
+    pub async fn run(self, upstream: Arc<Upstream>, client: Client) {
+        let backends = upstream.backends();
+        // consecutive results that disagree with the current backend state
+        let mut streaks = vec![0; backends.len()];
+
+        loop {
+            let results =
+                join_all(backends.iter().map(|backend| self.probe(&client, backend)))
+                    .await;
+
+            for ((backend, ok), streak) in backends.iter().zip(results).zip(&mut streaks)
+            {
+                let healthy = backend.is_healthy();
+                if ok == healthy {
+                    *streak = 0;
+                    continue;
+                }
+
+                *streak += 1;
+                if healthy && *streak >= self.fails {
+                    println!("Upstream {} failed health check, ejecting", backend.url);
+                    backend.healthy.store(false, Ordering::Relaxed);
+                    *streak = 0;
+                } else if !healthy && *streak >= self.passes {
+                    println!("Upstream {} is healthy again, reinstating", backend.url);
+                    backend.healthy.store(true, Ordering::Relaxed);
+                    *streak = 0;
+                }
+            }
+
+            sleep(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::http::header::HeaderValue;
+
+    fn upstream(strategy: Strategy) -> Upstream {
+        let urls = (1..=3)
+            .map(|i| Url::parse(&format!("http://10.0.0.{}:8080", i)).unwrap())
+            .collect();
+        Upstream::new(urls, strategy)
+    }
+
+    fn host(conn: &Connection) -> String {
+        conn.backend().url().host_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_strategy_parse() {
+        assert_eq!("round-robin".parse(), Ok(Strategy::RoundRobin));
+        assert_eq!("least-conn".parse(), Ok(Strategy::LeastConnections));
+        assert_eq!(
+            "hash:x-user-id".parse(),
+            Ok(Strategy::ConsistentHash(HeaderName::from_static(
+                "x-user-id"
+            )))
+        );
+        assert!("hash:".parse::<Strategy>().is_err());
+        assert!("random".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let up = upstream(Strategy::RoundRobin);
+        let hdrs = HeaderMap::new();
+        let hosts: Vec<_> = (0..4).map(|_| host(&up.select(&hdrs).unwrap())).collect();
+        assert_eq!(hosts, ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.1"]);
+
+        up.backends[1].healthy.store(false, Ordering::Relaxed);
+        let hosts: Vec<_> = (0..3).map(|_| host(&up.select(&hdrs).unwrap())).collect();
+        assert_eq!(hosts, ["10.0.0.3", "10.0.0.3", "10.0.0.1"]);
+
+        for b in &up.backends {
+            b.healthy.store(false, Ordering::Relaxed);
+        }
+        assert!(up.select(&hdrs).is_none());
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let up = upstream(Strategy::LeastConnections);
+        let hdrs = HeaderMap::new();
+
+        let c1 = up.select(&hdrs).unwrap();
+        let c2 = up.select(&hdrs).unwrap();
+        let c3 = up.select(&hdrs).unwrap();
+        let mut hosts = vec![host(&c1), host(&c2), host(&c3)];
+        hosts.sort();
+        assert_eq!(hosts, ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+
+        // only backend without in-flight requests is picked
+        let freed = host(&c2);
+        drop(c2);
+        assert_eq!(host(&up.select(&hdrs).unwrap()), freed);
+        assert_eq!(host(&up.select(&hdrs).unwrap()), freed);
+
+        drop((c1, c3));
+        assert!(up.backends.iter().all(|b| b.active() == 0));
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let name = HeaderName::from_static("x-user-id");
+        let up = upstream(Strategy::ConsistentHash(name.clone()));
+
+        let mut hdrs = HeaderMap::new();
+        let mut seen = Vec::new();
+        for user in 0..32 {
+            hdrs.insert(name.clone(), HeaderValue::from(user));
+            let first = host(&up.select(&hdrs).unwrap());
+            // same key, same backend
+            assert_eq!(host(&up.select(&hdrs).unwrap()), first);
+            seen.push(first);
+        }
+        // keys are spread over all backends
+        for i in 1..=3 {
+            assert!(seen.contains(&format!("10.0.0.{}", i)));
+        }
+
+        // keys of an ejected backend move elsewhere, others stay in place
+        up.backends[0].healthy.store(false, Ordering::Relaxed);
+        for (user, prev) in seen.iter().enumerate() {
+            hdrs.insert(name.clone(), HeaderValue::from(user));
+            let current = host(&up.select(&hdrs).unwrap());
+            if prev == "10.0.0.1" {
+                assert_ne!(current, "10.0.0.1");
+            } else {
+                assert_eq!(&current, prev);
+            }
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let up = upstream(Strategy::RoundRobin).breaker(Breaker {
+            failures: 2,
+            cooldown: Duration::from_millis(50),
+        });
+        let hdrs = HeaderMap::new();
+
+        // success resets the failure streak
+        let conn = up.select(&hdrs).unwrap();
+        assert_eq!(host(&conn), "10.0.0.1");
+        up.report(&conn, false);
+        up.report(&conn, true);
+        up.report(&conn, false);
+        assert!(up.backends[0].is_available());
+
+        // consecutive failures open the circuit
+        up.report(&conn, false);
+        assert!(!up.backends[0].is_available());
+        assert!(up.retry_after().unwrap() <= Duration::from_millis(50));
+        let hosts: Vec<_> = (0..4).map(|_| host(&up.select(&hdrs).unwrap())).collect();
+        assert!(!hosts.contains(&"10.0.0.1".to_owned()));
+
+        drop(conn);
+
+        // half-open after cooldown, a single request probes the backend
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(up.backends[0].is_available());
+        assert_eq!(up.retry_after(), None);
+        // the first backend is selected, unless its circuit keeps it away
+        let first = || loop {
+            let conn = up.select(&hdrs).unwrap();
+            if host(&conn) == "10.0.0.1" {
+                break conn;
+            }
+        };
+        let probe = first();
+        assert!(!up.backends[0].is_available());
+        let hosts: Vec<_> = (0..4).map(|_| host(&up.select(&hdrs).unwrap())).collect();
+        assert!(!hosts.contains(&"10.0.0.1".to_owned()));
+
+        // probe dropped without outcome, the next request probes
+        drop(probe);
+        assert!(up.backends[0].is_available());
+        let probe = first();
+        assert!(!up.backends[0].is_available());
+
+        // failed probe opens the circuit again
+        up.report(&probe, false);
+        assert!(!up.backends[0].is_available());
+        assert!(up.retry_after().is_some());
+        drop(probe);
+        assert!(!up.backends[0].is_available());
+
+        // successful probe closes it
+        std::thread::sleep(Duration::from_millis(60));
+        let probe = first();
+        up.report(&probe, true);
+        assert!(up.backends[0].is_available());
+        drop(probe);
+        let conn = first();
+        up.report(&conn, false);
+        assert!(up.backends[0].is_available());
+        up.report(&conn, false);
+        assert!(!up.backends[0].is_available());
+
+        // no backend left
+        for b in &up.backends[1..] {
+            b.healthy.store(false, Ordering::Relaxed);
+        }
+        assert!(up.select(&hdrs).is_none());
+        assert!(up.retry_after().is_some());
+    }
+}
@@ -0,0 +1,399 @@
+//! JSONRPC clients over HTTP and WebSocket.
+//!
+//! ```rust,no_run
+//! # async fn example(http: ntex::client::Client) -> Result<(), jsonrpc_example::client::Error> {
+//! use jsonrpc_example::client::Client;
+//!
+//! let client = Client::new(http, "http://127.0.0.1:8080/");
+//! let pong: String = client.call("ping", ()).await?;
+//! client.notify("inc", ()).await?;
+//!
+//! let mut batch = client.batch();
+//! batch.call("get", ())?;
+//! batch.notify("inc", ())?;
+//! let results = batch.send().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! In-flight calls can be cancelled over a websocket connection only:
+//!
+//! ```rust,no_run
+//! # async fn example() -> Result<(), jsonrpc_example::client::Error> {
+//! use jsonrpc_example::client::WsClient;
+//!
+//! let client = WsClient::connect("ws://127.0.0.1:8080/ws").await?;
+//! let call = client.start("wait", [5]).await?;
+//! client.cancel(call.id()).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{cell::Cell, cell::RefCell, collections::HashMap, error, fmt, rc::Rc};
+
+use futures::StreamExt;
+use ntex::channel::oneshot;
+use ntex::client::error::ClientError;
+use ntex::http::{error::PayloadError, StatusCode};
+use ntex::util::Bytes;
+use ntex::{rt, ws, SharedCfg};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Number, Value};
+
+use crate::convention::{ErrorData, Id, Outcome, Params, Request, Response};
+pub use crate::ws::REQUEST_CANCELLED;
+
+/// Largest response body accepted.
+const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Errors of a rpc call.
+#[derive(Debug)]
+pub enum Error {
+    /// Request could not be sent or its response received.
+    Http(ClientError),
+    /// Response body could not be read.
+    Payload(PayloadError),
+    /// Server answered with an unexpected http status.
+    Status(StatusCode),
+    /// Parameters do not serialize to an array or an object.
+    Params,
+    /// Response or result does not decode.
+    Decode(serde_json::Error),
+    /// Server did not answer the call.
+    NoResponse,
+    /// Method failed, as reported by the server.
+    Rpc(ErrorData),
+    /// Websocket connection could not be established.
+    Ws(Box<dyn error::Error>),
+    /// Websocket connection is closed.
+    Closed,
+}
+
+impl error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "http error: {}", e),
+            Error::Payload(e) => write!(f, "payload error: {}", e),
+            Error::Status(status) => write!(f, "unexpected http status: {}", status),
+            Error::Params => write!(f, "params must be an array or an object"),
+            Error::Decode(e) => write!(f, "invalid response: {}", e),
+            Error::NoResponse => write!(f, "no response"),
+            Error::Rpc(e) => write!(f, "rpc error: {}", e),
+            Error::Ws(e) => write!(f, "websocket error: {}", e),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl From<ErrorData> for Error {
+    fn from(e: ErrorData) -> Self {
+        Error::Rpc(e)
+    }
+}
+
+/// Parameters of a call, `()` omits them.
+fn to_params<P: Serialize>(params: P) -> Result<Option<Params>, Error> {
+    match serde_json::to_value(params).map_err(Error::Decode)? {
+        Value::Null => Ok(None),
+        Value::Array(params) => Ok(Some(Params::Array(params))),
+        Value::Object(params) => Ok(Some(Params::Object(params))),
+        _ => Err(Error::Params),
+    }
+}
+
+/// Request of `method`, a notification has no id.
+fn request<P: Serialize>(
+    next_id: &Cell<u64>,
+    method: &str,
+    params: P,
+    notification: bool,
+) -> Result<Request, Error> {
+    let id = if notification {
+        None
+    } else {
+        let id = next_id.get();
+        next_id.set(id + 1);
+        Some(Id::Number(Number::from(id)))
+    };
+    Ok(Request {
+        jsonrpc: crate::convention::JSONRPC_VERSION.into(),
+        method: method.into(),
+        params: to_params(params)?,
+        id,
+    })
+}
+
+/// Result of a call decoded into `R`.
+fn result<R: DeserializeOwned>(outcome: Outcome) -> Result<R, Error> {
+    match outcome {
+        Outcome::Result(value) => serde_json::from_value(value).map_err(Error::Decode),
+        Outcome::Error(e) => Err(Error::Rpc(e)),
+    }
+}
+
+/// JSONRPC client, sends requests to a server url.
+pub struct Client {
+    http: ntex::client::Client,
+    url: String,
+    next_id: Cell<u64>,
+}
+
+impl Client {
+    pub fn new(http: ntex::client::Client, url: impl Into<String>) -> Self {
+        Client {
+            http,
+            url: url.into(),
+            next_id: Cell::new(1),
+        }
+    }
+
+    fn request<P: Serialize>(
+        &self,
+        method: &str,
+        params: P,
+        notification: bool,
+    ) -> Result<Request, Error> {
+        request(&self.next_id, method, params, notification)
+    }
+
+    /// Post `body`, returns the response body, empty if there is none.
+    async fn post(&self, body: String) -> Result<Bytes, Error> {
+        let res = self
+            .http
+            .post(&self.url)
+            .content_type("application/json")
+            .send_body(body)
+            .await
+            .map_err(Error::Http)?;
+        match res.status() {
+            StatusCode::OK => res
+                .body()
+                .limit(MAX_RESPONSE_SIZE)
+                .await
+                .map_err(Error::Payload),
+            StatusCode::NO_CONTENT => Ok(Bytes::new()),
+            status => Err(Error::Status(status)),
+        }
+    }
+
+    /// Call `method` and decode its result.
+    pub async fn call<P, R>(&self, method: &str, params: P) -> Result<R, Error>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let req = self.request(method, params, false)?;
+        let body = self.post(req.dump()).await?;
+        if body.is_empty() {
+            return Err(Error::NoResponse);
+        }
+        let res: Response = serde_json::from_slice(&body).map_err(Error::Decode)?;
+        result(res.outcome)
+    }
+
+    /// Send a notification, the server does not answer it.
+    pub async fn notify<P: Serialize>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<(), Error> {
+        let req = self.request(method, params, true)?;
+        self.post(req.dump()).await.map(|_| ())
+    }
+
+    /// Start a batch of calls sent in a single request.
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+}
+
+/// Calls sent together, the server executes them concurrently.
+pub struct Batch<'a> {
+    client: &'a Client,
+    requests: Vec<Request>,
+}
+
+impl Batch<'_> {
+    /// Add a call, its result is at the same position in [`Batch::send`] output.
+    pub fn call<P: Serialize>(
+        &mut self,
+        method: &str,
+        params: P,
+    ) -> Result<&mut Self, Error> {
+        self.requests
+            .push(self.client.request(method, params, false)?);
+        Ok(self)
+    }
+
+    /// Add a notification, it has no result.
+    pub fn notify<P: Serialize>(
+        &mut self,
+        method: &str,
+        params: P,
+    ) -> Result<&mut Self, Error> {
+        self.requests
+            .push(self.client.request(method, params, true)?);
+        Ok(self)
+    }
+
+    /// Send the batch, returns results of the calls in the order they were added.
+    pub async fn send(self) -> Result<Vec<Result<Value, Error>>, Error> {
+        if self.requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let body = serde_json::to_string(&self.requests).expect("Should never failed");
+        let body = self.client.post(body).await?;
+
+        let mut responses: Vec<Response> = if body.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_slice(&body).map_err(Error::Decode)?
+        };
+        // responses may come in any order
+        Ok(self
+            .requests
+            .iter()
+            .filter_map(|req| req.id.as_ref())
+            .map(|id| match responses.iter().position(|res| res.id == *id) {
+                Some(idx) => result(responses.swap_remove(idx).outcome),
+                None => Err(Error::NoResponse),
+            })
+            .collect())
+    }
+}
+
+/// Number of an id generated by the clients.
+fn id_number(id: &Id) -> Option<u64> {
+    match id {
+        Id::Number(n) => n.as_u64(),
+        _ => None,
+    }
+}
+
+/// JSONRPC client over a websocket connection.
+///
+/// Calls do not wait for each other, replies are matched to them by id.
+/// Notifications pushed by the server are dropped.
+pub struct WsClient {
+    sink: ws::WsSink,
+    /// Calls waiting for their reply, by id
+    pending: Rc<RefCell<HashMap<u64, oneshot::Sender<Outcome>>>>,
+    next_id: Cell<u64>,
+}
+
+impl WsClient {
+    /// Connect to the websocket endpoint of a server, `ws://127.0.0.1:8080/ws`.
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        let con = ws::WsClient::builder(url)
+            .build(SharedCfg::default())
+            .await
+            .map_err(|e| Error::Ws(e.into()))?
+            .connect()
+            .await
+            .map_err(|e| Error::Ws(e.into()))?;
+        let sink = con.sink();
+        let mut frames = con.seal().receiver();
+
+        let pending: Rc<RefCell<HashMap<_, oneshot::Sender<_>>>> = Rc::default();
+        rt::spawn({
+            let pending = pending.clone();
+            async move {
+                while let Some(Ok(frame)) = frames.next().await {
+                    let (ws::Frame::Text(body) | ws::Frame::Binary(body)) = frame else {
+                        continue;
+                    };
+                    // notifications do not parse as responses
+                    let Ok(res) = serde_json::from_slice::<Response>(&body) else {
+                        continue;
+                    };
+                    let tx = id_number(&res.id)
+                        .and_then(|id| pending.borrow_mut().remove(&id));
+                    if let Some(tx) = tx {
+                        let _ = tx.send(res.outcome);
+                    }
+                }
+                // calls still waiting get no response
+                pending.borrow_mut().clear();
+            }
+        });
+
+        Ok(WsClient {
+            sink,
+            pending,
+            next_id: Cell::new(1),
+        })
+    }
+
+    async fn send(&self, req: Request) -> Result<(), Error> {
+        self.sink
+            .send(ws::Message::Text(req.dump().into()))
+            .await
+            .map_err(|_| Error::Closed)
+    }
+
+    /// Send a call of `method` without waiting for its result.
+    pub async fn start<P: Serialize>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<WsCall, Error> {
+        let req = request(&self.next_id, method, params, false)?;
+        let id = req.id.clone().expect("Should never failed");
+        let key = id_number(&id).expect("Should never failed");
+        let (tx, rx) = oneshot::channel();
+        self.pending.borrow_mut().insert(key, tx);
+        if let Err(e) = self.send(req).await {
+            self.pending.borrow_mut().remove(&key);
+            return Err(e);
+        }
+        Ok(WsCall { id, rx })
+    }
+
+    /// Call `method` and decode its result.
+    pub async fn call<P, R>(&self, method: &str, params: P) -> Result<R, Error>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        self.start(method, params).await?.result().await
+    }
+
+    /// Send a notification, the server does not answer it.
+    pub async fn notify<P: Serialize>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<(), Error> {
+        self.send(request(&self.next_id, method, params, true)?)
+            .await
+    }
+
+    /// Abort in-flight call `id`, it fails with [`REQUEST_CANCELLED`].
+    ///
+    /// Returns whether the call was still in-flight.
+    pub async fn cancel(&self, id: &Id) -> Result<bool, Error> {
+        self.call("$/cancelRequest", [id]).await
+    }
+}
+
+/// Call sent by [`WsClient::start`].
+pub struct WsCall {
+    id: Id,
+    rx: oneshot::Receiver<Outcome>,
+}
+
+impl WsCall {
+    /// Id of the call, to cancel it.
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    /// Wait for the result of the call and decode it.
+    pub async fn result<R: DeserializeOwned>(self) -> Result<R, Error> {
+        let outcome = self.rx.await.map_err(|_| Error::NoResponse)?;
+        result(outcome)
+    }
+}
@@ -3,17 +3,95 @@
 use std::error;
 use std::fmt;
 
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Number, Value};
 
 pub static JSONRPC_VERSION: &str = "2.0";
 
+/// Error codes, the range from -32768 to -32000 is reserved for pre-defined errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "i32", into = "i32")]
+pub enum ErrorCode {
+    /// Invalid JSON was received by the server. An error occurred on the server while parsing the JSON text.
+    ParseError,
+    /// The JSON sent is not a valid Request object.
+    InvalidRequest,
+    /// The method does not exist / is not available.
+    MethodNotFound,
+    /// Invalid method parameter(s).
+    InvalidParams,
+    /// Internal JSON-RPC error.
+    InternalError,
+    /// Reserved for implementation-defined server-errors, -32099 to -32000.
+    ServerError(i32),
+    /// Any other code within the reserved range, reserved for future use.
+    Reserved(i32),
+    /// Errors defined by the application, any code outside the reserved range.
+    Application(i32),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::InvalidRequest => -32600,
+            ErrorCode::MethodNotFound => -32601,
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::InternalError => -32603,
+            ErrorCode::ServerError(code)
+            | ErrorCode::Reserved(code)
+            | ErrorCode::Application(code) => code,
+        }
+    }
+
+    /// Short description of the error.
+    pub fn message(self) -> &'static str {
+        match self {
+            ErrorCode::ParseError => "Parse error",
+            ErrorCode::InvalidRequest => "Invalid Request",
+            ErrorCode::MethodNotFound => "Method not found",
+            ErrorCode::InvalidParams => "Invalid params",
+            ErrorCode::InternalError => "Internal error",
+            ErrorCode::ServerError(_) => "Server error",
+            ErrorCode::Reserved(_) => "Reserved error",
+            ErrorCode::Application(_) => "Application error",
+        }
+    }
+}
+
+impl From<i32> for ErrorCode {
+    fn from(code: i32) -> Self {
+        match code {
+            -32700 => ErrorCode::ParseError,
+            -32600 => ErrorCode::InvalidRequest,
+            -32601 => ErrorCode::MethodNotFound,
+            -32602 => ErrorCode::InvalidParams,
+            -32603 => ErrorCode::InternalError,
+            -32099..=-32000 => ErrorCode::ServerError(code),
+            -32768..=-32000 => ErrorCode::Reserved(code),
+            _ => ErrorCode::Application(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for i32 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
 /// When a rpc call encounters an error, the Response Object MUST contain the
 /// error member with a value that is a Object with the following members:
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorData {
     /// A Number that indicates the error type that occurred. This MUST be an integer.
-    pub code: i32,
+    pub code: ErrorCode,
 
     /// A String providing a short description of the error. The message SHOULD be
     /// limited to a concise single sentence.
@@ -23,34 +101,23 @@ pub struct ErrorData {
     /// about the error. This may be omitted. The value of this member is
     /// defined by the Server (e.g. detailed error information, nested errors
     /// etc.).
+    #[serde(default, skip_serializing_if = "Value::is_null")]
     pub data: Value,
 }
 
 impl ErrorData {
-    pub fn new(code: i32, message: &str) -> Self {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
         Self {
             code,
-            message: String::from(message),
+            message: message.into(),
             data: Value::Null,
         }
     }
 
-    pub fn std(code: i32) -> Self {
-        match code {
-            // Invalid JSON was received by the server. An error occurred on the server while parsing the JSON text.
-            -32700 => ErrorData::new(-32700, "Parse error"),
-            // The JSON sent is not a valid Request object.
-            -32600 => ErrorData::new(-32600, "Invalid Request"),
-            // The method does not exist / is not available.
-            -32601 => ErrorData::new(-32601, "Method not found"),
-            // Invalid method parameter(s).
-            -32602 => ErrorData::new(-32602, "Invalid params"),
-            // Internal JSON-RPC error.
-            -32603 => ErrorData::new(-32603, "Internal error"),
-            // The error codes from and including -32768 to -32000 are reserved for pre-defined errors. Any code within
-            // this range, but not defined explicitly below is reserved for future use.
-            _ => panic!("Undefined pre-defined error codes"),
-        }
+    /// Attach additional information, values that do not serialize are dropped.
+    pub fn with_data<T: Serialize>(mut self, data: T) -> Self {
+        self.data = serde_json::to_value(data).unwrap_or(Value::Null);
+        self
     }
 
     /// Prints out the value as JSON string.
@@ -59,6 +126,21 @@ impl ErrorData {
     }
 }
 
+/// Error with the standard message of `code`.
+impl From<ErrorCode> for ErrorData {
+    fn from(code: ErrorCode) -> Self {
+        ErrorData::new(code, code.message())
+    }
+}
+
+/// Failures of method handlers are reported as server errors, with the
+/// error description as `data`.
+impl From<Box<dyn error::Error>> for ErrorData {
+    fn from(e: Box<dyn error::Error>) -> Self {
+        ErrorData::from(ErrorCode::ServerError(-32000)).with_data(e.to_string())
+    }
+}
+
 impl error::Error for ErrorData {}
 impl fmt::Display for ErrorData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -66,6 +148,43 @@ impl fmt::Display for ErrorData {
     }
 }
 
+/// Identifier of a call, a String, Number, or NULL value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(Number),
+    String(String),
+    Null,
+}
+
+/// Parameter values of a call, by-position or by-name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Params {
+    /// Values in the order the method expects.
+    Array(Vec<Value>),
+    /// Values by parameter name.
+    Object(Map<String, Value>),
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params::Array(Vec::new())
+    }
+}
+
+/// Deserialize a member that is present, even if it is `null`.
+///
+/// Missing members are handled by `#[serde(default)]`, so `"id": null` and a
+/// missing `id` can be told apart.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
 /// A rpc call is represented by sending a Request object to a Server.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request {
@@ -79,21 +198,53 @@ pub struct Request {
 
     /// A Structured value that holds the parameter values to be used during the invocation of the method. This member
     /// MAY be omitted.
-    pub params: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Params>,
 
     /// An identifier established by the Client that MUST contain a String, Number, or NULL value if included. If it is
     /// not included it is assumed to be a notification. The value SHOULD normally not be Null [1] and Numbers SHOULD
     /// NOT contain fractional parts.
-    pub id: Value,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Id>,
 }
 
 impl Request {
+    /// Parse a Request object, -32600 if the value is not a valid one.
+    pub fn from_value(value: Value) -> Result<Self, ErrorData> {
+        match serde_json::from_value::<Request>(value) {
+            Ok(req) if req.jsonrpc == JSONRPC_VERSION => Ok(req),
+            _ => Err(ErrorCode::InvalidRequest.into()),
+        }
+    }
+
+    /// A Notification is a Request object without an "id" member.
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+
     /// Prints out the value as JSON string.
     pub fn dump(&self) -> String {
         serde_json::to_string(self).expect("Should never failed")
     }
 }
 
+/// Outcome of a call, either `result` or `error` member of the Response.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// This member is REQUIRED on success.
+    /// The value of this member is determined by the method invoked on the Server.
+    Result(Value),
+
+    /// This member is REQUIRED on error.
+    /// The value for this member MUST be an Object as defined in section 5.1.
+    Error(ErrorData),
+}
+
 /// When a rpc call is made, the Server MUST reply with a Response, except for in the case of Notifications. The
 /// Response is expressed as a single JSON Object, with the following members:
 #[derive(Debug, Serialize, Deserialize)]
@@ -101,37 +252,64 @@ pub struct Response {
     /// A String specifying the version of the JSON-RPC protocol. MUST be exactly "2.0".
     pub jsonrpc: String,
 
-    /// This member is REQUIRED on success.
-    /// This member MUST NOT exist if there was an error invoking the method.
-    /// The value of this member is determined by the method invoked on the Server.
-    pub result: Value,
-
-    // This member is REQUIRED on error.
-    // This member MUST NOT exist if there was no error triggered during invocation.
-    // The value for this member MUST be an Object as defined in section 5.1.
-    pub error: Option<ErrorData>,
+    /// Exactly one of `result` and `error`.
+    #[serde(flatten)]
+    pub outcome: Outcome,
 
     /// This member is REQUIRED.
     /// It MUST be the same as the value of the id member in the Request Object.
     /// If there was an error in detecting the id in the Request object (e.g. Parse error/Invalid Request),
     /// it MUST be Null.
-    pub id: Value,
+    pub id: Id,
 }
 
 impl Response {
+    pub fn new(id: Id, outcome: Result<Value, ErrorData>) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.into(),
+            outcome: match outcome {
+                Ok(result) => Outcome::Result(result),
+                Err(error) => Outcome::Error(error),
+            },
+            id,
+        }
+    }
+
+    /// Response to a request whose id could not be detected.
+    pub fn error(error: ErrorData) -> Self {
+        Self::new(Id::Null, Err(error))
+    }
+
     /// Prints out the value as JSON string.
     pub fn dump(&self) -> String {
         serde_json::to_string(self).expect("Should never failed")
     }
 }
 
-impl Default for Response {
-    fn default() -> Self {
-        Self {
-            jsonrpc: JSONRPC_VERSION.into(),
-            result: Value::Null,
-            error: None,
-            id: Value::Null,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        for (code, expected) in [
+            (-32700, ErrorCode::ParseError),
+            (-32601, ErrorCode::MethodNotFound),
+            (-32000, ErrorCode::ServerError(-32000)),
+            (-32099, ErrorCode::ServerError(-32099)),
+            (-32100, ErrorCode::Reserved(-32100)),
+            (-32768, ErrorCode::Reserved(-32768)),
+            (-32769, ErrorCode::Application(-32769)),
+            (-31999, ErrorCode::Application(-31999)),
+            (42, ErrorCode::Application(42)),
+        ] {
+            assert_eq!(ErrorCode::from(code), expected);
+            assert_eq!(i32::from(expected), code);
         }
+
+        let err: ErrorData =
+            serde_json::from_str(r#"{"code": -32050, "message": "Busy"}"#).unwrap();
+        assert_eq!(err.code, ErrorCode::ServerError(-32050));
+        assert_eq!(err.dump(), r#"{"code":-32050,"message":"Busy"}"#);
     }
 }
@@ -0,0 +1,11 @@
+//! JSON-RPC 2.0 over HTTP and WebSocket, server and client.
+// Allow this lint since it's fine to use type directly in the short example.
+#![allow(clippy::type_complexity)]
+// Shared state is behind std locks, holding one across an await can deadlock a worker.
+#![deny(clippy::await_holding_lock)]
+
+pub mod client;
+pub mod convention;
+pub mod registry;
+pub mod server;
+mod ws;
@@ -0,0 +1,316 @@
+//! Registry of rpc methods with typed parameters and results.
+//!
+//! Parameters are decoded into a `Deserialize` struct, so the same method
+//! accepts them by-position (`[4]`) and by-name (`{"seconds": 4}`). Values
+//! that do not fit are rejected with -32602 before the method is invoked.
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{any, fmt, future::Future, panic::AssertUnwindSafe, pin::Pin, sync::Arc};
+
+use futures::FutureExt;
+use ntex::time::timeout;
+
+use serde::de::{self, DeserializeOwned, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::convention::{ErrorCode, ErrorData, Params};
+
+/// Parameters of methods that take none.
+#[derive(Debug, Deserialize)]
+pub struct NoParams {}
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<Value, ErrorData>>>>;
+
+/// Deadline of methods registered without one.
+const DEFAULT_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Error of calls that did not complete before their deadline.
+pub const DEADLINE_EXCEEDED: ErrorCode = ErrorCode::ServerError(-32001);
+
+struct Method<S> {
+    handler: Box<dyn Fn(Arc<S>, Params) -> BoxFuture>,
+    /// Parameter names, in by-position order
+    params: &'static [&'static str],
+    result: &'static str,
+    deadline: Duration,
+}
+
+/// Rpc methods operating on shared state `S`.
+pub struct Registry<S> {
+    methods: BTreeMap<String, Method<S>>,
+    /// Methods served by websocket sessions only
+    websocket: Vec<&'static str>,
+}
+
+impl<S: 'static> Default for Registry<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: 'static> Registry<S> {
+    pub fn new() -> Self {
+        Registry {
+            methods: BTreeMap::new(),
+            websocket: Vec::new(),
+        }
+    }
+
+    /// Register async method `name`.
+    ///
+    /// Names starting with `rpc.` are reserved, `rpc.discover` lists the
+    /// registered methods.
+    pub fn register<P, R, F, Fut>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        P: DeserializeOwned + 'static,
+        R: Serialize + 'static,
+        F: Fn(Arc<S>, P) -> Fut + 'static,
+        Fut: Future<Output = Result<R, ErrorData>> + 'static,
+    {
+        assert!(!name.starts_with("rpc."), "reserved method name: {}", name);
+
+        let f = Arc::new(f);
+        let handler = move |state: Arc<S>, params: Params| -> BoxFuture {
+            let params = match params {
+                Params::Array(params) => Value::Array(params),
+                Params::Object(params) => Value::Object(params),
+            };
+            let params = match serde_json::from_value::<P>(params) {
+                Ok(params) => params,
+                Err(e) => {
+                    let err = ErrorData::from(ErrorCode::InvalidParams)
+                        .with_data(e.to_string());
+                    return Box::pin(async move { Err(err) });
+                }
+            };
+            let f = f.clone();
+            let call = async move {
+                let result = f(state, params).await?;
+                serde_json::to_value(result).map_err(|_| ErrorCode::InternalError.into())
+            };
+            // a panicking method fails the call, not the worker
+            Box::pin(AssertUnwindSafe(call).catch_unwind().map(|result| {
+                result.unwrap_or_else(|panic| {
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned());
+                    Err(ErrorData::from(ErrorCode::InternalError).with_data(message))
+                })
+            }))
+        };
+        self.methods.insert(
+            name.to_owned(),
+            Method {
+                handler: Box::new(handler),
+                params: field_names::<P>(),
+                result: short_type_name::<R>(),
+                deadline: DEFAULT_DEADLINE,
+            },
+        );
+        self
+    }
+
+    /// Time allowed for calls of `name`, the default is 30 seconds.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is not registered.
+    pub fn deadline(&mut self, name: &str, deadline: Duration) -> &mut Self {
+        match self.methods.get_mut(name) {
+            Some(m) => m.deadline = deadline,
+            None => panic!("unknown method: {}", name),
+        }
+        self
+    }
+
+    /// List `name` in `rpc.discover` as a method of websocket sessions.
+    ///
+    /// Sessions handle such calls before they reach the registry, calls over
+    /// other transports fail with -32601 and a note in `data`.
+    pub fn websocket(&mut self, name: &'static str) -> &mut Self {
+        self.websocket.push(name);
+        self
+    }
+
+    /// Invoke `method` with `params`.
+    ///
+    /// Calls running past the method deadline are dropped and fail with
+    /// [`DEADLINE_EXCEEDED`].
+    pub async fn call(
+        &self,
+        state: Arc<S>,
+        method: &str,
+        params: Params,
+    ) -> Result<Value, ErrorData> {
+        if method == "rpc.discover" {
+            return Ok(self.discover());
+        }
+        if self.websocket.contains(&method) {
+            return Err(ErrorData::from(ErrorCode::MethodNotFound)
+                .with_data("only available on websocket connections"));
+        }
+        match self.methods.get(method) {
+            Some(m) => match timeout(m.deadline, (m.handler)(state, params)).await {
+                Ok(result) => result,
+                Err(()) => Err(ErrorData::new(DEADLINE_EXCEEDED, "Deadline exceeded")
+                    .with_data(json!({ "deadline": m.deadline.as_secs_f64() }))),
+            },
+            None => Err(ErrorCode::MethodNotFound.into()),
+        }
+    }
+
+    /// Description of the registered methods.
+    pub fn discover(&self) -> Value {
+        let methods: Vec<_> = self
+            .methods
+            .iter()
+            .map(|(name, m)| {
+                json!({
+                    "name": name,
+                    "params": m.params,
+                    "result": m.result,
+                    "deadline": m.deadline.as_secs_f64(),
+                })
+            })
+            .collect();
+        json!({ "methods": methods, "websocket": self.websocket })
+    }
+}
+
+/// Type name without its path, `String` for `alloc::string::String`.
+///
+/// Generic types keep the full name.
+fn short_type_name<T>() -> &'static str {
+    let name = any::type_name::<T>();
+    match name.rfind("::") {
+        Some(idx) if !name.contains('<') => &name[idx + 2..],
+        _ => name,
+    }
+}
+
+/// Field names of struct `T`, empty for other types.
+///
+/// Asks `T` to deserialize itself from a deserializer that only records the
+/// fields passed to `deserialize_struct`.
+fn field_names<T: DeserializeOwned>() -> &'static [&'static str] {
+    #[derive(Debug)]
+    struct Probe(Option<&'static [&'static str]>);
+
+    impl fmt::Display for Probe {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("probe")
+        }
+    }
+
+    impl std::error::Error for Probe {}
+
+    impl de::Error for Probe {
+        fn custom<M: fmt::Display>(_: M) -> Self {
+            Probe(None)
+        }
+    }
+
+    struct Deserializer;
+
+    impl<'de> de::Deserializer<'de> for Deserializer {
+        type Error = Probe;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Probe> {
+            Err(Probe(None))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Probe> {
+            Err(Probe(Some(fields)))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    match T::deserialize(Deserializer) {
+        Err(Probe(Some(fields))) => fields,
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::time::sleep;
+
+    #[derive(Deserialize)]
+    struct Div {
+        a: u32,
+        b: u32,
+    }
+
+    async fn div(_: Arc<()>, p: Div) -> Result<u32, ErrorData> {
+        Ok(p.a / p.b)
+    }
+
+    #[ntex::test]
+    async fn test_call() {
+        let mut registry = Registry::new();
+        registry.register("div", div);
+        let call = |params| registry.call(Arc::new(()), "div", params);
+
+        let params = Params::Array(vec![Value::from(6), Value::from(3)]);
+        assert_eq!(call(params).await.unwrap(), 2);
+
+        let params = Params::Array(vec![Value::from(6)]);
+        let err = call(params).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidParams);
+
+        // division by zero panics
+        let params =
+            Params::Object(serde_json::from_str(r#"{"a": 1, "b": 0}"#).unwrap());
+        let err = call(params).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::InternalError);
+        assert_eq!(err.data, "attempt to divide by zero");
+
+        assert_eq!(
+            registry.discover(),
+            json!({"methods": [
+                {"name": "div", "params": ["a", "b"], "result": "u32", "deadline": 30.0}
+            ], "websocket": []})
+        );
+
+        registry.websocket("subscribe");
+        let err = registry
+            .call(Arc::new(()), "subscribe", Params::Array(Vec::new()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::MethodNotFound);
+        assert_eq!(err.data, "only available on websocket connections");
+        assert_eq!(registry.discover()["websocket"], json!(["subscribe"]));
+    }
+
+    #[ntex::test]
+    async fn test_deadline() {
+        let mut registry = Registry::new();
+        registry
+            .register("sleep", async |_: Arc<()>, ms: [u64; 1]| {
+                sleep(Duration::from_millis(ms[0])).await;
+                Ok(ms[0])
+            })
+            .deadline("sleep", Duration::from_millis(100));
+        let call = |ms: u64| {
+            registry.call(Arc::new(()), "sleep", Params::Array(vec![Value::from(ms)]))
+        };
+
+        assert_eq!(call(10).await.unwrap(), 10);
+        let err = call(1000).await.unwrap_err();
+        assert_eq!(err.code, DEADLINE_EXCEEDED);
+        assert_eq!(err.data, json!({"deadline": 0.1}));
+    }
+}
@@ -0,0 +1,459 @@
+//! JSONRPC server, methods of the example and their shared state.
+use std::sync::Arc;
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::{error, pin::Pin, rc::Rc, time::Duration};
+
+use futures::channel::mpsc;
+use futures::{future::join_all, Future, FutureExt};
+use ntex::web::{self, Error, HttpResponse};
+use ntex::{time::sleep, util::Bytes};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::convention::{self, ErrorCode, ErrorData};
+use crate::registry::{NoParams, Registry};
+use crate::ws;
+
+/// Register shared state and the JSONRPC endpoints, `POST /` and `/ws`.
+pub fn configure(app_state: Arc<AppState>, cfg: &mut web::ServiceConfig) {
+    cfg.state(app_state)
+        .state(Rc::new(methods()))
+        .service(web::resource("/").route(web::post().to(rpc_handler)))
+        .service(web::resource("/ws").route(web::get().to(ws::ws_index)));
+}
+
+/// The main handler for JSONRPC server.
+async fn rpc_handler(
+    body: Bytes,
+    app_state: web::types::State<Arc<AppState>>,
+    methods: web::types::State<Rc<Registry<AppState>>>,
+) -> Result<HttpResponse, Error> {
+    let body = rpc_message(&app_state, &methods, None, body.as_ref()).await;
+    Ok(rpc_response(body))
+}
+
+/// Handle a message received over any transport, returns the reply if any.
+///
+/// Accepts a single call or a batch of calls, calls of a batch are executed
+/// concurrently. Notifications are not answered, so a message carrying only
+/// notifications gets no reply.
+pub(crate) async fn rpc_message(
+    app_state: &Arc<AppState>,
+    methods: &Registry<AppState>,
+    session: Option<&ws::Session>,
+    body: &[u8],
+) -> Option<String> {
+    let reqjson: Value = match serde_json::from_slice(body) {
+        Ok(ok) => ok,
+        Err(_) => return Some(rpc_error(ErrorCode::ParseError).dump()),
+    };
+
+    match reqjson {
+        // an empty batch is not a valid request
+        Value::Array(calls) if calls.is_empty() => {
+            Some(rpc_error(ErrorCode::InvalidRequest).dump())
+        }
+        Value::Array(calls) => {
+            let responses: Vec<_> = join_all(
+                calls
+                    .into_iter()
+                    .map(|call| rpc_call(app_state, methods, session, call)),
+            )
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(serde_json::to_string(&responses).expect("Should never failed"))
+            }
+        }
+        call => rpc_call(app_state, methods, session, call)
+            .await
+            .map(|r| r.dump()),
+    }
+}
+
+fn rpc_response(body: Option<String>) -> HttpResponse {
+    match body {
+        Some(body) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(body),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+fn rpc_error(code: ErrorCode) -> convention::Response {
+    convention::Response::error(code.into())
+}
+
+/// Execute a single call, returns `None` for notifications.
+///
+/// Methods of the websocket `session` take precedence over `methods`.
+async fn rpc_call(
+    app_state: &Arc<AppState>,
+    methods: &Registry<AppState>,
+    session: Option<&ws::Session>,
+    call: Value,
+) -> Option<convention::Response> {
+    let reqjson = match convention::Request::from_value(call) {
+        Ok(ok) => ok,
+        Err(e) => return Some(convention::Response::error(e)),
+    };
+    let method = reqjson.method.as_str();
+    let result = match session.and_then(|s| s.call(method, reqjson.params.as_ref())) {
+        Some(result) => result,
+        None => {
+            let params = reqjson.params.unwrap_or_default();
+            let call = methods.call(app_state.clone(), method, params);
+            match (session, &reqjson.id) {
+                (Some(session), Some(id)) => session.cancellable(id, call).await,
+                _ => call.await,
+            }
+        }
+    };
+    reqjson.id.map(|id| convention::Response::new(id, result))
+}
+
+/// Methods served by the JSONRPC server.
+pub fn methods() -> Registry<AppState> {
+    let mut methods = Registry::new();
+    methods
+        .register("ping", ping)
+        .register("wait", wait)
+        .register("get", get)
+        .register("inc", inc)
+        .deadline("wait", Duration::from_secs(10));
+    for name in ws::METHODS {
+        methods.websocket(name);
+    }
+    methods
+}
+
+async fn ping(app_state: Arc<AppState>, _: NoParams) -> Result<String, ErrorData> {
+    Ok(app_state.read(|network| network.ping()))
+}
+
+#[derive(Deserialize)]
+struct WaitParams {
+    seconds: u64,
+}
+
+async fn wait(
+    app_state: Arc<AppState>,
+    params: WaitParams,
+) -> Result<String, ErrorData> {
+    // the lock is not held while waiting, so other calls can proceed
+    let wait = app_state.read(|network| network.wait(params.seconds));
+    Ok(wait.await?)
+}
+
+async fn get(app_state: Arc<AppState>, _: NoParams) -> Result<u32, ErrorData> {
+    Ok(app_state.read(|network| network.get()))
+}
+
+/// Error of `inc` once the counter reached its maximum.
+const COUNTER_OVERFLOW: ErrorCode = ErrorCode::Application(1);
+
+async fn inc(app_state: Arc<AppState>, _: NoParams) -> Result<(), ErrorData> {
+    let value = app_state.write(|network| network.inc());
+    match value {
+        Some(value) => {
+            app_state.changed(value);
+            Ok(())
+        }
+        None => Err(ErrorData::new(COUNTER_OVERFLOW, "Counter overflow")
+            .with_data(json!({ "max": u32::MAX }))),
+    }
+}
+
+#[derive(Default)]
+pub struct ObjNetwork {
+    c: u32,
+}
+
+impl ObjNetwork {
+    pub fn new() -> Self {
+        Self { c: 0 }
+    }
+}
+
+impl ObjNetwork {
+    fn ping(&self) -> String {
+        String::from("pong")
+    }
+
+    fn wait(
+        &self,
+        d: u64,
+    ) -> Pin<Box<dyn Future<Output = Result<String, Box<dyn error::Error>>>>> {
+        async move {
+            sleep(Duration::from_secs(d)).await;
+            Ok(String::from("pong"))
+        }
+        .boxed_local()
+    }
+
+    fn get(&self) -> u32 {
+        self.c
+    }
+
+    /// Increment the counter, returns the new value or `None` on overflow.
+    fn inc(&mut self) -> Option<u32> {
+        self.c = self.c.checked_add(1)?;
+        Some(self.c)
+    }
+}
+
+pub struct AppState {
+    network: RwLock<ObjNetwork>,
+    /// Receivers of counter changes
+    watchers: Mutex<Vec<mpsc::UnboundedSender<u32>>>,
+}
+
+impl AppState {
+    pub fn new(network: RwLock<ObjNetwork>) -> Self {
+        Self {
+            network,
+            watchers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Read access to the network.
+    ///
+    /// The lock is held for the duration of `f` only, so it is never held
+    /// across an await point. A lock poisoned by a panicking method is
+    /// still usable, the counter is always consistent.
+    pub fn read<R>(&self, f: impl FnOnce(&ObjNetwork) -> R) -> R {
+        f(&self.network.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Write access to the network, see [`AppState::read`].
+    pub fn write<R>(&self, f: impl FnOnce(&mut ObjNetwork) -> R) -> R {
+        f(&mut self.network.write().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Stream of counter values, sent on every change.
+    pub fn watch(&self) -> mpsc::UnboundedReceiver<u32> {
+        let (tx, rx) = mpsc::unbounded();
+        self.watchers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn changed(&self, value: u32) {
+        // dropped receivers are forgotten
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(value).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::http::StatusCode;
+    use ntex::web::{test, App};
+    use serde_json::json;
+    use std::time::Instant;
+
+    async fn call(body: Value) -> (StatusCode, Value) {
+        let app_state = Arc::new(AppState::new(RwLock::new(ObjNetwork::new())));
+        let app =
+            test::init_service(App::new().configure(|cfg| configure(app_state, cfg)))
+                .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status();
+        let bytes = test::read_body(resp).await;
+        let body = if bytes.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&bytes).unwrap()
+        };
+        (status, body)
+    }
+
+    #[ntex::test]
+    async fn test_batch() {
+        let (status, body) = call(json!([
+            {"jsonrpc": "2.0", "method": "ping", "params": [], "id": 1},
+            {"jsonrpc": "2.0", "method": "inc", "params": []},
+            {"foo": "boo"},
+            {"jsonrpc": "2.0", "method": "nope", "params": [], "id": "x"},
+            1,
+        ]))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let codes: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                (
+                    r["id"].clone(),
+                    r["result"].clone(),
+                    r["error"]["code"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                (Value::from(1), Value::from("pong"), Value::Null),
+                (Value::Null, Value::Null, Value::from(-32600)),
+                (Value::from("x"), Value::Null, Value::from(-32601)),
+                (Value::Null, Value::Null, Value::from(-32600)),
+            ]
+        );
+
+        // empty batch is invalid
+        let (_, body) = call(json!([])).await;
+        assert_eq!(body["error"]["code"], -32600);
+
+        // notifications only, nothing to answer
+        let (status, body) = call(json!([
+            {"jsonrpc": "2.0", "method": "inc", "params": []},
+            {"jsonrpc": "2.0", "method": "ping", "params": []},
+        ]))
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(body, Value::Null);
+    }
+
+    #[ntex::test]
+    async fn test_single() {
+        let (_, body) = call(json!({"jsonrpc": "2.0", "method": "ping", "id": 1})).await;
+        assert_eq!(body, json!({"jsonrpc": "2.0", "result": "pong", "id": 1}));
+
+        // by-name params, `null` id is still answered
+        let (_, body) = call(json!({
+            "jsonrpc": "2.0", "method": "wait", "params": {"seconds": 0}, "id": null
+        }))
+        .await;
+        assert_eq!(
+            body,
+            json!({"jsonrpc": "2.0", "result": "pong", "id": null})
+        );
+
+        let (_, body) = call(json!({
+            "jsonrpc": "2.0", "method": "wait", "params": {"secs": 0}, "id": "a"
+        }))
+        .await;
+        assert_eq!(
+            body,
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32602,
+                    "message": "Invalid params",
+                    "data": "missing field `seconds`"
+                },
+                "id": "a"
+            })
+        );
+
+        // notification
+        let (status, _) = call(json!({"jsonrpc": "2.0", "method": "inc"})).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        // structurally invalid requests
+        for req in [
+            json!({"jsonrpc": "1.0", "method": "ping", "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "ping", "id": {}}),
+            json!({"jsonrpc": "2.0", "method": "ping", "params": 1, "id": 1}),
+            json!({"jsonrpc": "2.0", "id": 1}),
+            json!("ping"),
+        ] {
+            let (_, body) = call(req).await;
+            assert_eq!(body["error"]["code"], -32600);
+            assert_eq!(body["id"], Value::Null);
+        }
+    }
+
+    #[ntex::test]
+    async fn test_registry() {
+        let (_, body) =
+            call(json!({"jsonrpc": "2.0", "method": "rpc.discover", "id": 1})).await;
+        assert_eq!(
+            body["result"]["methods"],
+            json!([
+                {"name": "get", "params": [], "result": "u32", "deadline": 30.0},
+                {"name": "inc", "params": [], "result": "()", "deadline": 30.0},
+                {"name": "ping", "params": [], "result": "String", "deadline": 30.0},
+                {"name": "wait", "params": ["seconds"], "result": "String", "deadline": 10.0},
+            ])
+        );
+        assert_eq!(
+            body["result"]["websocket"],
+            json!([
+                "$/cancelRequest",
+                "counter.subscribe",
+                "counter.unsubscribe"
+            ])
+        );
+
+        // cancellation is bound to a websocket session
+        let (_, body) = call(json!({
+            "jsonrpc": "2.0", "method": "$/cancelRequest", "params": [1], "id": 1
+        }))
+        .await;
+        assert_eq!(body["error"]["code"], -32601);
+        assert_eq!(
+            body["error"]["data"],
+            "only available on websocket connections"
+        );
+
+        for params in [
+            json!(["1"]),
+            json!([1, 2]),
+            json!({}),
+            json!({"seconds": -1}),
+        ] {
+            let (_, body) = call(json!({
+                "jsonrpc": "2.0", "method": "wait", "params": params, "id": 1
+            }))
+            .await;
+            assert_eq!(body["error"]["code"], -32602);
+            assert!(body["error"]["data"].is_string());
+        }
+        let (_, body) = call(json!({
+            "jsonrpc": "2.0", "method": "get", "params": [1], "id": 1
+        }))
+        .await;
+        assert_eq!(body["error"]["code"], -32602);
+
+        let (_, body) = call(json!({"jsonrpc": "2.0", "method": "nope", "id": 1})).await;
+        assert_eq!(body["error"]["code"], -32601);
+    }
+
+    #[ntex::test]
+    async fn test_errors() {
+        let app_state = Arc::new(AppState::new(RwLock::new(ObjNetwork { c: u32::MAX })));
+        let err = inc(app_state.clone(), NoParams {}).await.unwrap_err();
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            json!({"code": 1, "message": "Counter overflow", "data": {"max": u32::MAX}})
+        );
+        assert_eq!(get(app_state, NoParams {}).await.unwrap(), u32::MAX);
+    }
+
+    #[ntex::test]
+    async fn test_batch_concurrency() {
+        let start = Instant::now();
+        let (_, body) = call(json!([
+            {"jsonrpc": "2.0", "method": "wait", "params": [1], "id": 1},
+            {"jsonrpc": "2.0", "method": "wait", "params": [1], "id": 2},
+            {"jsonrpc": "2.0", "method": "inc", "params": [], "id": 3},
+        ]))
+        .await;
+        assert!(start.elapsed() < Duration::from_millis(1900));
+        assert_eq!(body.as_array().unwrap().len(), 3);
+        assert_eq!(body[1]["result"], "pong");
+    }
+}
@@ -0,0 +1,327 @@
+//! JSON-RPC over WebSocket.
+//!
+//! Every text or binary frame carries one message, a call or a batch, as on
+//! the http transport. Messages are handled concurrently and replies are sent
+//! as they complete, clients match them to calls by `id`.
+//!
+//! Sessions can subscribe to counter changes, the server then pushes
+//! `counter.changed` notifications with the new value as the only parameter.
+//!
+//! A `$/cancelRequest` notification with the `id` of an in-flight call of the
+//! session aborts it, the call fails with [`REQUEST_CANCELLED`]. Ids of
+//! in-flight calls must be unique within the session, a call reusing one is
+//! rejected as an invalid request. Cancellation is bound to the session, http
+//! calls can not be cancelled.
+use std::collections::{hash_map::Entry, HashMap};
+use std::{cell::RefCell, io, rc::Rc, sync::Arc};
+
+use futures::future::{abortable, ready, select, AbortHandle, Either};
+use futures::{Future, StreamExt};
+use ntex::channel::oneshot;
+use ntex::service::{fn_factory_with_config, fn_service, fn_shutdown, Service};
+use ntex::web::{self, ws, Error, HttpRequest, HttpResponse};
+use ntex::{chain, rt};
+use serde_json::Value;
+
+use crate::convention::{self, ErrorCode, ErrorData, Id, Params};
+use crate::registry::Registry;
+use crate::server::AppState;
+
+/// Error of calls aborted by `$/cancelRequest`.
+pub const REQUEST_CANCELLED: ErrorCode = ErrorCode::ServerError(-32002);
+
+/// Methods bound to the connection, see [`Session::call`].
+pub(crate) const METHODS: &[&str] = &[
+    "$/cancelRequest",
+    "counter.subscribe",
+    "counter.unsubscribe",
+];
+
+/// State of a websocket connection.
+pub struct Session {
+    app_state: Arc<AppState>,
+    sink: ws::WsSink,
+    /// Stops the counter subscription once dropped
+    subscription: RefCell<Option<oneshot::Sender<()>>>,
+    /// Calls that can be cancelled, by id
+    in_flight: RefCell<HashMap<String, AbortHandle>>,
+}
+
+/// Key of a call id in the in-flight table.
+fn id_key(id: &Id) -> String {
+    serde_json::to_string(id).expect("Should never failed")
+}
+
+impl Session {
+    /// Call a method bound to the connection, `None` if there is no such method.
+    pub fn call(
+        &self,
+        method: &str,
+        params: Option<&Params>,
+    ) -> Option<Result<Value, ErrorData>> {
+        match method {
+            "$/cancelRequest" => {
+                let id = match params {
+                    Some(Params::Array(params)) if params.len() == 1 => params.first(),
+                    Some(Params::Object(params)) => params.get("id"),
+                    _ => None,
+                };
+                let Some(id) = id.and_then(|id| serde_json::from_value(id.clone()).ok())
+                else {
+                    return Some(Err(ErrorCode::InvalidParams.into()));
+                };
+                let handle = self.in_flight.borrow_mut().remove(&id_key(&id));
+                let cancelled = handle.map(|handle| handle.abort()).is_some();
+                Some(Ok(Value::Bool(cancelled)))
+            }
+            "counter.subscribe" => {
+                let (tx, rx) = oneshot::channel();
+                *self.subscription.borrow_mut() = Some(tx);
+                rt::spawn(notify(self.app_state.clone(), self.sink.clone(), rx));
+                Some(Ok(Value::Bool(true)))
+            }
+            "counter.unsubscribe" => {
+                let subscribed = self.subscription.borrow_mut().take().is_some();
+                Some(Ok(Value::Bool(subscribed)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Run call `id`, unless it is cancelled first.
+    ///
+    /// Fails with -32600 if another call with the same id is in-flight.
+    pub async fn cancellable<F>(&self, id: &Id, call: F) -> Result<Value, ErrorData>
+    where
+        F: Future<Output = Result<Value, ErrorData>>,
+    {
+        let (call, handle) = abortable(call);
+        let key = id_key(id);
+        match self.in_flight.borrow_mut().entry(key.clone()) {
+            Entry::Occupied(_) => {
+                return Err(ErrorData::from(ErrorCode::InvalidRequest)
+                    .with_data(format!("call {} is already in-flight", key)));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(handle);
+            }
+        }
+        let result = call.await;
+        self.in_flight.borrow_mut().remove(&key);
+        result.unwrap_or_else(|_| {
+            Err(ErrorData::new(REQUEST_CANCELLED, "Request cancelled"))
+        })
+    }
+}
+
+/// Push counter changes to the client until `stop` is dropped.
+async fn notify(
+    app_state: Arc<AppState>,
+    sink: ws::WsSink,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut changes = app_state.watch();
+    loop {
+        match select(changes.next(), &mut stop).await {
+            Either::Left((Some(value), _)) => {
+                let notification = convention::Request {
+                    jsonrpc: convention::JSONRPC_VERSION.into(),
+                    method: "counter.changed".into(),
+                    params: Some(Params::Array(vec![Value::from(value)])),
+                    id: None,
+                };
+                let msg = ws::Message::Text(notification.dump().into());
+                if sink.send(msg).await.is_err() {
+                    return;
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+/// WebSockets service factory
+async fn ws_service(
+    sink: ws::WsSink,
+    app_state: Arc<AppState>,
+    methods: Rc<Registry<AppState>>,
+) -> Result<
+    impl Service<ws::Frame, Response = Option<ws::Message>, Error = io::Error>,
+    web::Error,
+> {
+    let session = Rc::new(Session {
+        app_state,
+        sink,
+        subscription: RefCell::new(None),
+        in_flight: RefCell::new(HashMap::new()),
+    });
+
+    let service = fn_service({
+        let session = session.clone();
+        move |frame| {
+            let item = match frame {
+                ws::Frame::Text(body) | ws::Frame::Binary(body) => {
+                    // calls must not wait for each other
+                    let session = session.clone();
+                    let methods = methods.clone();
+                    rt::spawn(async move {
+                        let app_state = &session.app_state;
+                        let reply = crate::server::rpc_message(
+                            app_state,
+                            &methods,
+                            Some(&session),
+                            &body,
+                        )
+                        .await;
+                        if let Some(reply) = reply {
+                            let _ =
+                                session.sink.send(ws::Message::Text(reply.into())).await;
+                        }
+                    });
+                    None
+                }
+                ws::Frame::Ping(msg) => Some(ws::Message::Pong(msg)),
+                ws::Frame::Close(reason) => Some(ws::Message::Close(reason)),
+                _ => None,
+            };
+            ready(Ok(item))
+        }
+    });
+
+    // stop the subscription and pending calls with the connection
+    let on_shutdown = fn_shutdown(async move || {
+        session.subscription.borrow_mut().take();
+        for (_, handle) in session.in_flight.borrow_mut().drain() {
+            handle.abort();
+        }
+    });
+
+    Ok(chain(service).and_then(on_shutdown))
+}
+
+/// do websocket handshake and start web sockets service
+pub async fn ws_index(
+    req: HttpRequest,
+    app_state: web::types::State<Arc<AppState>>,
+    methods: web::types::State<Rc<Registry<AppState>>>,
+) -> Result<HttpResponse, Error> {
+    let app_state = app_state.get_ref().clone();
+    let methods = methods.get_ref().clone();
+    ws::start(
+        req,
+        None::<&str>,
+        fn_factory_with_config(move |sink| {
+            ws_service(sink, app_state.clone(), methods.clone())
+        }),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::web::{test, App};
+    use ntex::SharedCfg;
+    use serde_json::json;
+    use std::sync::RwLock;
+
+    use crate::server::ObjNetwork;
+
+    #[ntex::test]
+    async fn test_ws() {
+        let app_state = Arc::new(AppState::new(RwLock::new(ObjNetwork::new())));
+        let srv = test::server(async move || {
+            App::new().configure(|cfg| crate::server::configure(app_state.clone(), cfg))
+        })
+        .await;
+
+        let con = ntex::ws::WsClient::builder(format!("http://{}/ws", srv.addr()))
+            .build(SharedCfg::default())
+            .await
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let sink = con.sink();
+        let mut rx = con.seal().receiver();
+        let send = async |msg: Value| {
+            sink.send(ws::Message::Text(msg.to_string().into()))
+                .await
+                .unwrap();
+        };
+        let mut recv = async || match rx.next().await {
+            Some(Ok(ws::Frame::Text(text))) => {
+                serde_json::from_slice::<Value>(&text).unwrap()
+            }
+            frame => panic!("unexpected frame: {:?}", frame),
+        };
+
+        // replies are sent as calls complete
+        send(json!({"jsonrpc": "2.0", "method": "wait", "params": [1], "id": 1})).await;
+        send(json!({"jsonrpc": "2.0", "method": "ping", "id": 2})).await;
+        assert_eq!(recv().await["id"], 2);
+        assert_eq!(recv().await["id"], 1);
+
+        send(json!({"jsonrpc": "2.0", "method": "counter.subscribe", "id": 3})).await;
+        assert_eq!(
+            recv().await,
+            json!({"jsonrpc": "2.0", "result": true, "id": 3})
+        );
+        send(json!({"jsonrpc": "2.0", "method": "inc"})).await;
+        assert_eq!(
+            recv().await,
+            json!({"jsonrpc": "2.0", "method": "counter.changed", "params": [1]})
+        );
+
+        send(json!({"jsonrpc": "2.0", "method": "counter.unsubscribe", "id": 4})).await;
+        assert_eq!(recv().await["result"], true);
+        send(json!([
+            {"jsonrpc": "2.0", "method": "inc"},
+            {"jsonrpc": "2.0", "method": "get", "id": 5},
+        ]))
+        .await;
+        assert_eq!(
+            recv().await,
+            json!([{"jsonrpc": "2.0", "result": 2, "id": 5}])
+        );
+
+        // in-flight call is cancelled
+        send(json!({"jsonrpc": "2.0", "method": "wait", "params": [5], "id": 6})).await;
+        send(
+            json!({"jsonrpc": "2.0", "method": "$/cancelRequest", "params": {"id": 6}}),
+        )
+        .await;
+        assert_eq!(
+            recv().await,
+            json!({
+                "jsonrpc": "2.0",
+                "error": {"code": -32002, "message": "Request cancelled"},
+                "id": 6
+            })
+        );
+        send(json!({"jsonrpc": "2.0", "method": "$/cancelRequest", "params": [6], "id": 7}))
+            .await;
+        assert_eq!(recv().await["result"], false);
+
+        // ids of in-flight calls are unique, the first call keeps running
+        send(json!({"jsonrpc": "2.0", "method": "wait", "params": [1], "id": "w"}))
+            .await;
+        send(json!({"jsonrpc": "2.0", "method": "ping", "id": "w"})).await;
+        assert_eq!(
+            recv().await,
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32600,
+                    "message": "Invalid Request",
+                    "data": "call \"w\" is already in-flight"
+                },
+                "id": "w"
+            })
+        );
+        assert_eq!(
+            recv().await,
+            json!({"jsonrpc": "2.0", "result": "pong", "id": "w"})
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::{Arc, RwLock};
+
+use jsonrpc_example::client::{Client, Error, WsClient, REQUEST_CANCELLED};
+use jsonrpc_example::convention::ErrorCode;
+use jsonrpc_example::server::{self, AppState, ObjNetwork};
+use ntex::web::{test, App};
+use serde::Serialize;
+use serde_json::Value;
+
+async fn http() -> ntex::client::Client {
+    ntex::client::Client::builder()
+        .build(ntex::SharedCfg::default())
+        .await
+        .unwrap()
+}
+
+async fn start() -> (test::TestServer, Client) {
+    let app_state = Arc::new(AppState::new(RwLock::new(ObjNetwork::new())));
+    let srv = test::server(async move || {
+        App::new().configure(|cfg| server::configure(app_state.clone(), cfg))
+    })
+    .await;
+    let client = Client::new(http().await, srv.url("/"));
+    (srv, client)
+}
+
+#[derive(Serialize)]
+struct Wait {
+    seconds: u64,
+}
+
+#[ntex::test]
+async fn test_call() {
+    let (_srv, client) = start().await;
+
+    let pong: String = client.call("ping", ()).await.unwrap();
+    assert_eq!(pong, "pong");
+    let pong: String = client.call("wait", [0]).await.unwrap();
+    assert_eq!(pong, "pong");
+    let pong: String = client.call("wait", Wait { seconds: 0 }).await.unwrap();
+    assert_eq!(pong, "pong");
+
+    let _: () = client.call("inc", ()).await.unwrap();
+    let count: u32 = client.call("get", ()).await.unwrap();
+    assert_eq!(count, 1);
+
+    let methods: Value = client.call("rpc.discover", ()).await.unwrap();
+    assert_eq!(methods["methods"].as_array().unwrap().len(), 4);
+}
+
+#[ntex::test]
+async fn test_notify() {
+    let (_srv, client) = start().await;
+
+    for _ in 0..3 {
+        client.notify("inc", ()).await.unwrap();
+    }
+    // unknown methods are not reported for notifications
+    client.notify("nope", ()).await.unwrap();
+
+    let count: u32 = client.call("get", ()).await.unwrap();
+    assert_eq!(count, 3);
+}
+
+#[ntex::test]
+async fn test_batch() {
+    let (_srv, client) = start().await;
+
+    let mut batch = client.batch();
+    batch
+        .call("ping", ())
+        .unwrap()
+        .notify("inc", ())
+        .unwrap()
+        .call("nope", ())
+        .unwrap()
+        .call("wait", Wait { seconds: 0 })
+        .unwrap();
+    let results = batch.send().await.unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().unwrap(), "pong");
+    assert!(
+        matches!(&results[1], Err(Error::Rpc(e)) if e.code == ErrorCode::MethodNotFound)
+    );
+    assert_eq!(results[2].as_ref().unwrap(), "pong");
+
+    // notifications only
+    let mut batch = client.batch();
+    batch.notify("inc", ()).unwrap();
+    assert!(batch.send().await.unwrap().is_empty());
+
+    let count: u32 = client.call("get", ()).await.unwrap();
+    assert_eq!(count, 2);
+}
+
+#[ntex::test]
+async fn test_errors() {
+    let (_srv, client) = start().await;
+
+    let err = client.call::<_, String>("nope", ()).await.unwrap_err();
+    assert!(matches!(err, Error::Rpc(e) if e.code == ErrorCode::MethodNotFound));
+
+    let err = client.call::<_, String>("wait", ["1"]).await.unwrap_err();
+    match err {
+        Error::Rpc(e) => {
+            assert_eq!(e.code, ErrorCode::InvalidParams);
+            assert!(e.data.is_string());
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+
+    // params must be structured
+    let err = client.call::<_, String>("wait", 1).await.unwrap_err();
+    assert!(matches!(err, Error::Params));
+
+    // result of unexpected type
+    let err = client.call::<_, u32>("ping", ()).await.unwrap_err();
+    assert!(matches!(err, Error::Decode(_)));
+
+    let err = Client::new(http().await, "http://127.0.0.1:1/")
+        .call::<_, String>("ping", ())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Http(_)));
+}
+
+#[ntex::test]
+async fn test_ws() {
+    let (srv, _) = start().await;
+    let client = WsClient::connect(&srv.url("/ws")).await.unwrap();
+
+    let pong: String = client.call("ping", ()).await.unwrap();
+    assert_eq!(pong, "pong");
+    client.notify("inc", ()).await.unwrap();
+    let count: u32 = client.call("get", ()).await.unwrap();
+    assert_eq!(count, 1);
+
+    // calls do not wait for each other
+    let wait = client.start("wait", [1]).await.unwrap();
+    let pong: String = client.call("ping", ()).await.unwrap();
+    assert_eq!(pong, "pong");
+    let pong: String = wait.result().await.unwrap();
+    assert_eq!(pong, "pong");
+
+    // in-flight call is cancelled, once
+    let wait = client.start("wait", [5]).await.unwrap();
+    assert!(client.cancel(wait.id()).await.unwrap());
+    assert!(!client.cancel(wait.id()).await.unwrap());
+    let err = wait.result::<String>().await.unwrap_err();
+    assert!(matches!(err, Error::Rpc(e) if e.code == REQUEST_CANCELLED));
+}
+
+#[ntex::test]
+async fn test_cancel_over_http() {
+    let (_srv, client) = start().await;
+
+    // cancellation needs a websocket session
+    let err = client
+        .call::<_, bool>("$/cancelRequest", [1])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Rpc(e) if e.code == ErrorCode::MethodNotFound));
+}
@@ -13,7 +13,8 @@ queue = asyncio.Queue()
 async def start_client(url, loop):
     name = input('Please enter your name: ')
 
-    ws = await aiohttp.ClientSession().ws_connect(url, autoclose=False, autoping=False)
+    ws = await aiohttp.ClientSession().ws_connect(
+        url, autoclose=False, autoping=False, protocols=('chat.text',))
 
     def stdin_callback():
         line = sys.stdin.buffer.readline().decode('utf-8')
@@ -0,0 +1,196 @@
+//! Sessions of all chat server shards.
+//!
+//! Rooms are spread over the shards, but nicknames, session ids and direct
+//! messages are global. The directory keeps them, every shard holds it behind
+//! a mutex and keeps the lock for single lookups only.
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::queue;
+use crate::server::ClientMessage;
+
+/// Longest nickname accepted, in characters
+const MAX_NICK_LEN: usize = 32;
+
+/// Connected session
+pub struct Member {
+    pub name: String,
+    pub addr: queue::Sender<ClientMessage>,
+    /// Shard holding the session, shared with its connection
+    pub shard: Arc<AtomicUsize>,
+}
+
+/// Where to send the receipt of a direct message
+struct Receipt {
+    sender: usize,
+    recipient: usize,
+}
+
+pub struct Directory {
+    sessions: HashMap<usize, Member>,
+    /// Id of the next session, ids are handed out in order
+    next_id: usize,
+    /// Number of the last guest name handed out
+    guests: usize,
+    /// Rooms of all shards
+    rooms: HashSet<String>,
+    /// Direct messages waiting for a receipt, by message id
+    undelivered: HashMap<u64, Receipt>,
+}
+
+impl Directory {
+    pub fn new() -> Self {
+        Directory {
+            sessions: HashMap::new(),
+            next_id: 1,
+            guests: 0,
+            rooms: HashSet::new(),
+            undelivered: HashMap::new(),
+        }
+    }
+
+    /// Add session held by `shard`, returns its id, guest name and the shard
+    /// cell of the connection
+    pub fn register(
+        &mut self,
+        addr: queue::Sender<ClientMessage>,
+        shard: usize,
+    ) -> (usize, String, Arc<AtomicUsize>) {
+        let id = self.session_id();
+        let name = self.guest_name();
+        let shard = Arc::new(AtomicUsize::new(shard));
+        self.sessions.insert(
+            id,
+            Member {
+                name: name.clone(),
+                addr,
+                shard: shard.clone(),
+            },
+        );
+        (id, name, shard)
+    }
+
+    /// Remove session, pending receipts from and to it are forgotten
+    pub fn remove(&mut self, id: usize) -> Option<Member> {
+        self.undelivered
+            .retain(|_, r| r.sender != id && r.recipient != id);
+        self.sessions.remove(&id)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Member> {
+        self.sessions.get(&id)
+    }
+
+    /// Shard holding the session
+    pub fn shard(&self, id: usize) -> Option<usize> {
+        self.get(id).map(|m| m.shard.load(Ordering::Acquire))
+    }
+
+    /// Hand the session over to another shard, its connection sends there from
+    /// now on
+    pub fn move_to(&self, id: usize, shard: usize) {
+        if let Some(member) = self.get(id) {
+            member.shard.store(shard, Ordering::Release);
+        }
+    }
+
+    /// Session with the nickname, names are case-insensitive
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.sessions
+            .iter()
+            .find(|(_, m)| m.name.eq_ignore_ascii_case(name))
+            .map(|(id, _)| *id)
+    }
+
+    /// Change nickname of the session, nicknames must stay unique
+    pub fn rename(&mut self, id: usize, name: &str) -> Result<(), String> {
+        if name.is_empty()
+            || name.chars().count() > MAX_NICK_LEN
+            || name.contains(char::is_whitespace)
+        {
+            return Err(format!(
+                "nickname must be 1 to {} characters without spaces",
+                MAX_NICK_LEN
+            ));
+        }
+        if self.find(name).is_some_and(|other| other != id) {
+            return Err(format!("nickname {} is already taken", name));
+        }
+        match self.sessions.get_mut(&id) {
+            Some(member) => {
+                member.name = name.to_owned();
+                Ok(())
+            }
+            None => Err("session is closed".to_owned()),
+        }
+    }
+
+    /// Id for a new session
+    ///
+    /// Ids increase with every session, once they wrap around ids of live
+    /// sessions are skipped.
+    fn session_id(&mut self) -> usize {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.sessions.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Next `guest-N` name that is not taken
+    fn guest_name(&mut self) -> String {
+        loop {
+            self.guests += 1;
+            let name = format!("guest-{}", self.guests);
+            if self.find(&name).is_none() {
+                return name;
+            }
+        }
+    }
+
+    /// Record a room created by a shard
+    pub fn add_room(&mut self, name: &str) {
+        self.rooms.insert(name.to_owned());
+    }
+
+    /// Forget a room removed by a shard
+    pub fn remove_room(&mut self, name: &str) {
+        self.rooms.remove(name);
+    }
+
+    /// Names of the rooms of all shards, sorted
+    pub fn rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self.rooms.iter().cloned().collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// Wait for the receipt of a direct message
+    pub fn expect_receipt(&mut self, message: u64, sender: usize, recipient: usize) {
+        self.undelivered
+            .insert(message, Receipt { sender, recipient });
+    }
+
+    /// Sender of a direct message, once its recipient acknowledges it
+    pub fn receipt(&mut self, message: u64, recipient: usize) -> Option<usize> {
+        match self.undelivered.get(&message) {
+            Some(receipt) if receipt.recipient == recipient => {
+                self.undelivered.remove(&message).map(|r| r.sender)
+            }
+            _ => None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn pending_receipts(&self) -> usize {
+        self.undelivered.len()
+    }
+
+    #[cfg(test)]
+    pub fn set_next_id(&mut self, id: usize) {
+        self.next_id = id;
+    }
+}
@@ -0,0 +1,284 @@
+//! Room message history, persisted to SQLite.
+//!
+//! Every room keeps its last `limit` messages, older ones are deleted as new
+//! messages are stored.
+//!
+//! Rooms are spread over a fixed number of databases, the buckets, by the
+//! [`room_hash`] of their name. A room keeps its bucket whatever the number of
+//! chat server shards, and shards write to separate databases as long as their
+//! number divides the number of buckets.
+use std::{error, fmt, path::Path};
+
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+
+pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+#[derive(Debug)]
+pub enum Error {
+    Pool(r2d2::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Pool(e) => write!(f, "history pool error: {}", e),
+            Error::Sqlite(e) => write!(f, "history error: {}", e),
+        }
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(e: r2d2::Error) -> Self {
+        Error::Pool(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+/// Chat message sent to a room
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Message id, increases with every message
+    pub id: u64,
+    pub room: String,
+    /// Nickname of the sender at the time of sending
+    pub sender: String,
+    pub text: String,
+    /// Seconds since unix epoch
+    pub time: u64,
+}
+
+/// Hash of a room name, stable across builds and Rust releases.
+///
+/// Stored rooms are found in their bucket by it, so it must never change.
+/// This is 64-bit FNV-1a.
+pub fn room_hash(room: &str) -> u64 {
+    room.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Bounded per-room message history
+#[derive(Clone)]
+pub struct History {
+    buckets: Vec<Pool>,
+    limit: usize,
+}
+
+impl History {
+    /// Open history stored in `buckets`, creating the tables if needed.
+    ///
+    /// # Panics
+    ///
+    /// If `buckets` is empty.
+    pub fn new(buckets: Vec<Pool>, limit: usize) -> Result<Self, Error> {
+        assert!(!buckets.is_empty(), "history needs at least one bucket");
+        for pool in &buckets {
+            pool.get()?.execute_batch(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    room TEXT NOT NULL,
+                    sender TEXT NOT NULL,
+                    text TEXT NOT NULL,
+                    time INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS messages_room ON messages (room, id);",
+            )?;
+        }
+        Ok(History { buckets, limit })
+    }
+
+    /// History in the SQLite database files `<prefix>-0.db` up to
+    /// `<prefix>-<buckets - 1>.db`.
+    pub fn open<P: AsRef<Path>>(
+        prefix: P,
+        buckets: usize,
+        limit: usize,
+    ) -> Result<Self, Error> {
+        let prefix = prefix.as_ref().display();
+        let buckets = (0..buckets)
+            .map(|bucket| {
+                let path = format!("{}-{}.db", prefix, bucket);
+                r2d2::Pool::new(SqliteConnectionManager::file(path))
+            })
+            .collect::<Result<_, _>>()?;
+        History::new(buckets, limit)
+    }
+
+    /// History in private in-memory databases.
+    #[cfg(test)]
+    pub fn memory(buckets: usize, limit: usize) -> Result<Self, Error> {
+        let buckets = (0..buckets)
+            .map(|_| {
+                // every connection to `:memory:` is a separate database
+                r2d2::Pool::builder()
+                    .max_size(1)
+                    .build(SqliteConnectionManager::memory())
+            })
+            .collect::<Result<_, _>>()?;
+        History::new(buckets, limit)
+    }
+
+    /// Database holding the messages of `room`
+    fn bucket(&self, room: &str) -> &Pool {
+        &self.buckets[(room_hash(room) % self.buckets.len() as u64) as usize]
+    }
+
+    /// Id of the latest stored message, 0 if there is none.
+    pub fn last_id(&self) -> Result<u64, Error> {
+        let mut last = 0;
+        for pool in &self.buckets {
+            let id: Option<i64> = pool.get()?.query_row(
+                "SELECT MAX(id) FROM messages",
+                NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            last = last.max(id.unwrap_or(0) as u64);
+        }
+        Ok(last)
+    }
+
+    /// Store message, drops messages of its room past the limit.
+    pub fn append(&self, entry: &Entry) -> Result<(), Error> {
+        let room = &entry.room;
+        let conn = self.bucket(room).get()?;
+        conn.execute(
+            "INSERT INTO messages (id, room, sender, text, time)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                entry.id as i64,
+                room,
+                entry.sender,
+                entry.text,
+                entry.time as i64
+            ],
+        )?;
+        conn.execute(
+            "DELETE FROM messages WHERE room = ?1 AND id <= (
+                SELECT id FROM messages WHERE room = ?1
+                ORDER BY id DESC LIMIT 1 OFFSET ?2
+            )",
+            params![room, self.limit as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Last `count` messages of `room`, oldest first.
+    pub fn recent(&self, room: &str, count: usize) -> Result<Vec<Entry>, Error> {
+        let conn = self.bucket(room).get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, sender, text, time FROM messages WHERE room = ?1
+             ORDER BY id DESC LIMIT ?2",
+        )?;
+        let count = count.min(self.limit) as i64;
+        let mut entries = stmt
+            .query_map(params![room, count], |row| {
+                Ok(Entry {
+                    id: row.get::<_, i64>(0)? as u64,
+                    room: room.to_owned(),
+                    sender: row.get(1)?,
+                    text: row.get(2)?,
+                    time: row.get::<_, i64>(3)? as u64,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Number of stored messages, in all rooms.
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Error> {
+        let mut len = 0;
+        for pool in &self.buckets {
+            let count: i64 = pool.get()?.query_row(
+                "SELECT COUNT(*) FROM messages",
+                NO_PARAMS,
+                |row| row.get(0),
+            )?;
+            len += count as usize;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let history = History::memory(4, 3).unwrap();
+        assert_eq!(history.last_id().unwrap(), 0);
+        let entry = |id: u64, room: &str, sender: &str| Entry {
+            id,
+            room: room.to_owned(),
+            sender: sender.to_owned(),
+            text: id.to_string(),
+            time: 0,
+        };
+        for id in 1..=5 {
+            history.append(&entry(id, "Main", "alice")).unwrap();
+        }
+        history.append(&entry(6, "Rust", "bob")).unwrap();
+        assert_eq!(history.len().unwrap(), 4);
+        assert_eq!(history.last_id().unwrap(), 6);
+
+        let texts = |entries: Vec<Entry>| -> Vec<String> {
+            entries.into_iter().map(|e| e.text).collect()
+        };
+        assert_eq!(texts(history.recent("Main", 2).unwrap()), ["4", "5"]);
+        assert_eq!(texts(history.recent("Main", 10).unwrap()), ["3", "4", "5"]);
+        assert_eq!(history.recent("Rust", 10).unwrap()[0].sender, "bob");
+        assert!(history.recent("Nope", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_buckets() {
+        // stored rooms are looked up by these, they must not change
+        assert_eq!(room_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(room_hash("Main"), 0x49e7_63ae_d008_77a8);
+
+        let dir =
+            std::env::temp_dir().join(format!("chat-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("chat");
+        let history = History::open(&prefix, 4, 10).unwrap();
+        let rooms: Vec<String> = (0..8).map(|i| format!("room-{}", i)).collect();
+        for (id, room) in rooms.iter().enumerate() {
+            let entry = Entry {
+                id: id as u64 + 1,
+                room: room.clone(),
+                sender: "alice".to_owned(),
+                text: room.clone(),
+                time: 0,
+            };
+            history.append(&entry).unwrap();
+        }
+        assert_eq!(history.len().unwrap(), rooms.len());
+
+        // rooms are spread over the buckets and found there once reopened
+        let history = History::open(&prefix, 4, 10).unwrap();
+        assert!(history.buckets.iter().all(|pool| {
+            let conn = pool.get().unwrap();
+            let count: i64 = conn
+                .query_row("SELECT COUNT(*) FROM messages", NO_PARAMS, |row| row.get(0))
+                .unwrap();
+            count > 0
+        }));
+        assert_eq!(history.last_id().unwrap(), rooms.len() as u64);
+        for room in &rooms {
+            assert_eq!(history.recent(room, 10).unwrap()[0].text, *room);
+        }
+        drop(history);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
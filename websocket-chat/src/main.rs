@@ -1,33 +1,49 @@
-use std::{cell::RefCell, io, rc::Rc, time::Duration, time::Instant};
+use std::sync::{atomic::AtomicUsize, Arc};
+use std::{cell::RefCell, env, io, rc::Rc, time::Duration, time::Instant};
 
-use futures::{channel::mpsc, future::ready, SinkExt, StreamExt};
+use futures::{future::ready, StreamExt};
 use ntex::service::{
     fn_factory_with_config, fn_service, fn_shutdown, map_config, Service,
 };
 use ntex::web::{self, ws, App, Error, HttpRequest, HttpResponse};
-use ntex::{chain, channel::oneshot, rt, time, util, util::ByteString, util::Bytes};
+use ntex::{chain, channel::oneshot, rt, time, util, util::Bytes};
 use ntex_files as fs;
 
+mod directory;
+mod history;
+mod protocol;
+mod queue;
 mod server;
-use self::server::{ClientMessage, ServerMessage};
+use self::protocol::{ChatRequest, Protocol, Request};
+use self::server::{
+    Chat, ClientMessage, Connected, Metrics, QueueConfig, ServerMessage,
+};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages kept per room
+const HISTORY_LIMIT: usize = 1000;
+/// History databases the rooms are spread over, changing it loses the history
+const HISTORY_BUCKETS: usize = 16;
 
 /// Entry point for our route
 async fn chat_route(
     req: HttpRequest,
-    srv: web::types::State<mpsc::UnboundedSender<ServerMessage>>,
+    srv: web::types::State<Chat>,
 ) -> Result<HttpResponse, Error> {
     let srv = srv.get_ref().clone();
+    // json protocol is used unless the client asks for another one
+    let protocol = Protocol::negotiate(ws::subprotocols(&req));
+    let name = protocol.map(Protocol::name);
+    let protocol = protocol.unwrap_or(Protocol::Json);
     ws::start(
         req,
-        None::<&str>,
+        name,
         // inject chat server send to a ws_service factory
         map_config(fn_factory_with_config(ws_service), move |cfg| {
-            (cfg, srv.clone())
+            (cfg, srv.clone(), protocol)
         }),
     )
     .await
@@ -39,55 +55,88 @@ struct WsChatSession {
     /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
     /// otherwise we drop connection.
     hb: Instant,
-    /// joined room
-    room: String,
-    /// peer name
-    name: Option<String>,
     /// server connectino
-    server: mpsc::UnboundedSender<ServerMessage>,
+    server: Chat,
+    /// Chat server shard holding the session
+    shard: Arc<AtomicUsize>,
+}
+
+impl WsChatSession {
+    fn send(&self, msg: ServerMessage) {
+        self.server.send(&self.shard, msg);
+    }
+
+    /// Forward client request to the chat server
+    fn request(&self, req: Request) {
+        let id = self.id;
+        let msg = match req.req {
+            ChatRequest::List => ServerMessage::ListRooms(id),
+            ChatRequest::Join(room) => {
+                let (name, password) = room.into_parts();
+                ServerMessage::Join { id, name, password }
+            }
+            ChatRequest::Nick(name) => ServerMessage::Nick { id, name },
+            ChatRequest::Who => ServerMessage::Who(id),
+            ChatRequest::History(count) => ServerMessage::History { id, count },
+            ChatRequest::Message(msg) => ServerMessage::Message { id, msg },
+            ChatRequest::Direct { to, text } => {
+                ServerMessage::Direct { id, to, msg: text }
+            }
+            ChatRequest::Kick(name) => ServerMessage::Kick { id, name },
+            ChatRequest::Ban(name) => ServerMessage::Ban { id, name },
+            ChatRequest::Invite(name) => ServerMessage::Invite { id, name },
+            ChatRequest::Password(password) => ServerMessage::Password { id, password },
+            ChatRequest::InviteOnly(on) => ServerMessage::InviteOnly { id, on },
+        };
+        // answers of the chat server refer to the request id
+        let msg = match req.id {
+            Some(tag) => ServerMessage::Tagged {
+                id,
+                tag,
+                msg: Box::new(msg),
+            },
+            None => msg,
+        };
+        self.send(msg);
+    }
 }
 
 impl Drop for WsChatSession {
     fn drop(&mut self) {
         // notify chat server
-        let _ = self.server.send(ServerMessage::Disconnect(self.id));
+        self.send(ServerMessage::Disconnect(self.id));
     }
 }
 
+/// Counters of the chat server
+async fn metrics_route(metrics: web::types::State<Arc<Metrics>>) -> HttpResponse {
+    HttpResponse::Ok().json(&metrics.to_json())
+}
+
 /// WebSockets service factory
 async fn ws_service(
-    (sink, mut server): (ws::WsSink, mpsc::UnboundedSender<ServerMessage>),
+    (sink, server, protocol): (ws::WsSink, Chat, Protocol),
 ) -> Result<
     impl Service<ws::Frame, Response = Option<ws::Message>, Error = io::Error>,
     web::Error,
 > {
-    let (tx, mut rx) = mpsc::unbounded();
-
-    // register self in chat server.
-    server.send(ServerMessage::Connect(tx)).await.unwrap();
-
-    // read first message from server, it shoould contain session id
-    let id = if let Some(ClientMessage::Id(id)) = rx.next().await {
-        id
-    } else {
-        panic!();
-    };
+    // register self in chat server, it answers with session id and queue
+    let Connected { id, rx, shard } = server.connect().await.unwrap();
 
     // create chat session
     let state = Rc::new(RefCell::new(WsChatSession {
         id,
         hb: Instant::now(),
         server: server.clone(),
-        room: "Main".to_owned(),
-        name: None,
+        shard: shard.clone(),
     }));
 
     // start server messages handler, it reads chat messages and sends to the peer
-    rt::spawn(messages(sink.clone(), rx));
+    rt::spawn(messages(sink.clone(), rx, protocol, server, shard, id));
 
     // start heartbeat task
     let (tx, rx) = oneshot::channel();
-    rt::spawn(heartbeat(state.clone(), sink.clone(), server.clone(), rx));
+    rt::spawn(heartbeat(state.clone(), sink.clone(), rx));
 
     // handler service for incoming websockets frames
     let service = fn_service(move |frame| {
@@ -105,67 +154,16 @@ async fn ws_service(
             }
             ws::Frame::Text(text) => {
                 let m = String::from_utf8(Vec::from(&text[..])).unwrap();
-
-                // we check for `/sss` type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            let mut srv = server.clone();
-                            rt::spawn(async move {
-                                let _ = srv.send(ServerMessage::ListRooms(id)).await;
-                            });
-                            None
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                let room = v[1].to_owned();
-                                state.borrow_mut().room = room.clone();
-                                let mut srv = server.clone();
-                                rt::spawn(async move {
-                                    let _ = srv
-                                        .send(ServerMessage::Join { id, name: room })
-                                        .await;
-                                });
-                                None
-                            } else {
-                                Some(ws::Message::Text(ByteString::from_static(
-                                    "!!! room name is required",
-                                )))
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                state.borrow_mut().name = Some(v[1].to_owned());
-                                None
-                            } else {
-                                Some(ws::Message::Text(ByteString::from_static(
-                                    "!!! name is required",
-                                )))
-                            }
-                        }
-                        _ => Some(ws::Message::Text(
-                            format!("!!! unknown command: {:?}", m).into(),
-                        )),
+                match protocol.decode(&m) {
+                    Ok(req) => {
+                        state.borrow().request(req);
+                        None
                     }
-                } else {
-                    let msg = if let Some(ref name) = state.borrow().name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m
-                    };
-                    // send message to chat server
-                    let mut srv = server.clone();
-                    let msg = ServerMessage::Message {
-                        id,
-                        msg,
-                        room: state.borrow().room.clone(),
-                    };
-                    rt::spawn(async move { srv.send(msg).await });
-                    None
+                    // malformed request, answer right away
+                    Err(msg) => protocol
+                        .encode(msg)
+                        .pop()
+                        .map(|msg| ws::Message::Text(msg.into())),
                 }
             }
             ws::Frame::Binary(_) => None,
@@ -185,21 +183,32 @@ async fn ws_service(
 }
 
 /// Handle messages from chat server, we simply send it to the peer websocket connection
-async fn messages(sink: ws::WsSink, mut server: mpsc::UnboundedReceiver<ClientMessage>) {
-    while let Some(msg) = server.next().await {
+///
+/// Direct messages are acknowledged once written to the connection. The
+/// connection is closed once the chat server drops the session.
+async fn messages(
+    sink: ws::WsSink,
+    mut rx: queue::Receiver<ClientMessage>,
+    protocol: Protocol,
+    server: Chat,
+    shard: Arc<AtomicUsize>,
+    id: usize,
+) {
+    while let Some(msg) = rx.next().await {
         println!("GOT chat server message: {:?}", msg);
-        match msg {
-            ClientMessage::Id(_) => (),
-            ClientMessage::Message(text) => {
-                let _ = sink.send(ws::Message::Text(text.into())).await;
-            }
-            ClientMessage::Rooms(rooms) => {
-                for room in rooms {
-                    let _ = sink.send(ws::Message::Text(room.into())).await;
-                }
-            }
+        let direct = match &msg {
+            ClientMessage::Direct(dm) => Some(dm.id),
+            _ => None,
+        };
+        let mut delivered = true;
+        for text in protocol.encode(msg) {
+            delivered &= sink.send(ws::Message::Text(text.into())).await.is_ok();
+        }
+        if let (Some(message), true) = (direct, delivered) {
+            server.send(&shard, ServerMessage::Delivered { id, message });
         }
     }
+    sink.io().close();
 }
 
 /// helper method that sends ping to client every second.
@@ -208,7 +217,6 @@ async fn messages(sink: ws::WsSink, mut server: mpsc::UnboundedReceiver<ClientMe
 async fn heartbeat(
     state: Rc<RefCell<WsChatSession>>,
     sink: ws::WsSink,
-    mut server: mpsc::UnboundedSender<ServerMessage>,
     mut rx: oneshot::Receiver<()>,
 ) {
     loop {
@@ -220,7 +228,8 @@ async fn heartbeat(
                     println!("Websocket Client heartbeat failed, disconnecting!");
 
                     // notify chat server
-                    let _ = server.send(ServerMessage::Disconnect(state.borrow().id));
+                    let state = state.borrow();
+                    state.send(ServerMessage::Disconnect(state.id));
 
                     // disconnect connection
                     sink.io().close();
@@ -244,13 +253,36 @@ async fn heartbeat(
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
+    // Outbound queues of the sessions
+    let mut queue = QueueConfig::default();
+    if let Ok(size) = env::var("CHAT_QUEUE_SIZE") {
+        queue.capacity = size.parse().expect("CHAT_QUEUE_SIZE must be a number");
+    }
+    if let Ok(overflow) = env::var("CHAT_OVERFLOW") {
+        queue.overflow = overflow
+            .parse()
+            .expect("CHAT_OVERFLOW must be drop-oldest, drop-new or disconnect");
+    }
+
+    // Rooms are spread over a chat server per cpu, unless configured
+    let shards = match env::var("CHAT_SHARDS") {
+        Ok(shards) => shards.parse().expect("CHAT_SHARDS must be a number"),
+        Err(_) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    // Message history, rooms keep their bucket whatever the number of shards
+    let history =
+        history::History::open("chat", HISTORY_BUCKETS, HISTORY_LIMIT).unwrap();
+
     // Start chat server actor
-    let server = server::start();
+    let metrics = Arc::new(Metrics::default());
+    let server = server::start(shards.max(1), history, queue, metrics.clone());
 
     // Create Http server with websocket support
     web::server(async move || {
         App::new()
             .state(server.clone())
+            .state(metrics.clone())
             // redirect to websocket.html
             .service(web::resource("/").route(web::get().to(|| async {
                 HttpResponse::Found()
@@ -259,6 +291,8 @@ async fn main() -> std::io::Result<()> {
             })))
             // websocket
             .service(web::resource("/ws/").to(chat_route))
+            // chat server counters
+            .service(web::resource("/metrics").route(web::get().to(metrics_route)))
             // static resources
             .service(fs::Files::new("/static/", "static/"))
     })
@@ -266,3 +300,161 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::web::test;
+    use ntex::SharedCfg;
+    use serde_json::{json, Value};
+
+    /// Next text frame, pings are skipped
+    async fn recv<E: std::fmt::Debug>(
+        rx: &mut (impl futures::Stream<Item = Result<ws::Frame, E>> + Unpin),
+    ) -> String {
+        loop {
+            match rx.next().await {
+                Some(Ok(ws::Frame::Text(text))) => {
+                    return String::from_utf8(text.to_vec()).unwrap()
+                }
+                Some(Ok(ws::Frame::Ping(_))) => continue,
+                frame => panic!("unexpected frame: {:?}", frame),
+            }
+        }
+    }
+
+    #[ntex::test]
+    async fn test_chat() {
+        // rooms of the test land on different shards
+        let history = history::History::memory(HISTORY_BUCKETS, HISTORY_LIMIT).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let server = server::start(4, history, QueueConfig::default(), metrics.clone());
+        let srv = test::server(async move || {
+            App::new()
+                .state(server.clone())
+                .state(metrics.clone())
+                .service(web::resource("/ws/").to(chat_route))
+                .service(web::resource("/metrics").route(web::get().to(metrics_route)))
+        })
+        .await;
+
+        let connect = async |protocols: &[&str]| {
+            let con = ntex::ws::WsClient::builder(format!("http://{}/ws/", srv.addr()))
+                .protocols(protocols)
+                .build(SharedCfg::default())
+                .await
+                .unwrap()
+                .connect()
+                .await
+                .unwrap();
+            (con.sink(), con.seal().receiver())
+        };
+        let send = async |sink: &ws::WsSink, msg: &str| {
+            sink.send(ws::Message::Text(msg.to_owned().into()))
+                .await
+                .unwrap();
+        };
+        let (alice, mut alice_rx) = connect(&["chat.text"]).await;
+        let guest = recv(&mut alice_rx).await;
+        assert!(guest.starts_with("*** you are now known as guest-"));
+        send(&alice, "/nick alice").await;
+        assert_eq!(recv(&mut alice_rx).await, "*** you are now known as alice");
+
+        let (bob, mut bob_rx) = connect(&["chat.text"]).await;
+        recv(&mut bob_rx).await;
+        assert!(recv(&mut alice_rx).await.ends_with(" joined"));
+        send(&bob, "/nick alice").await;
+        assert_eq!(
+            recv(&mut bob_rx).await,
+            "!!! nickname alice is already taken"
+        );
+        send(&bob, "/name bob").await;
+        assert_eq!(recv(&mut bob_rx).await, "*** you are now known as bob");
+        assert!(recv(&mut alice_rx).await.ends_with(" is now known as bob"));
+
+        send(&bob, "/who").await;
+        assert_eq!(recv(&mut bob_rx).await, "*** members: alice, bob");
+        send(&bob, "hello").await;
+        assert_eq!(recv(&mut alice_rx).await, "bob: hello");
+        send(&alice, "/history 1").await;
+        assert!(recv(&mut alice_rx).await.ends_with("] bob: hello"));
+
+        // json is the default protocol
+        let (carol, mut carol_rx) = connect(&[]).await;
+        let json =
+            async |rx: &mut _| serde_json::from_str::<Value>(&recv(rx).await).unwrap();
+        assert_eq!(
+            json(&mut carol_rx).await,
+            json!({"v": 1, "cmd": "nick", "data": "guest-3"})
+        );
+        let history = json(&mut carol_rx).await;
+        assert_eq!(history["cmd"], "history");
+        assert_eq!(history["data"][0]["sender"], "bob");
+        assert_eq!(recv(&mut alice_rx).await, "guest-3 joined");
+
+        send(&carol, r#"{"v": 1, "id": 1, "cmd": "nick", "data": "bob"}"#).await;
+        assert_eq!(
+            json(&mut carol_rx).await,
+            json!({
+                "v": 1,
+                "reply_to": 1,
+                "cmd": "error",
+                "data": "nickname bob is already taken"
+            })
+        );
+        send(&carol, r#"{"v": 2, "id": 2, "cmd": "who"}"#).await;
+        assert_eq!(
+            json(&mut carol_rx).await,
+            json!({"v": 1, "reply_to": 2, "cmd": "error", "data": "unsupported version: 2"})
+        );
+        send(&carol, r#"{"v": 1, "cmd": "message", "data": "hey"}"#).await;
+        assert_eq!(recv(&mut alice_rx).await, "guest-3: hey");
+        send(&alice, "hi").await;
+        let msg = json(&mut carol_rx).await;
+        assert_eq!(msg["cmd"], "message");
+        assert_eq!(msg["data"]["id"], 3);
+        assert_eq!(msg["data"]["sender"], "alice");
+        assert_eq!(msg["data"]["text"], "hi");
+        assert!(msg["data"]["time"].as_u64().unwrap() > 0);
+
+        // direct messages
+        send(&alice, "/msg guest-3 psst").await;
+        assert_eq!(recv(&mut alice_rx).await, "[dm -> guest-3] psst");
+        let dm = json(&mut carol_rx).await;
+        assert_eq!(dm["cmd"], "direct");
+        assert_eq!(
+            (&dm["data"]["from"], &dm["data"]["text"]),
+            (&json!("alice"), &json!("psst"))
+        );
+        assert_eq!(recv(&mut alice_rx).await, "*** delivered to guest-3");
+        send(
+            &carol,
+            r#"{"v": 1, "id": 3, "cmd": "direct", "data": {"to": "bob", "text": "hi"}}"#,
+        )
+        .await;
+        let sent = json(&mut carol_rx).await;
+        assert_eq!(
+            (&sent["cmd"], &sent["reply_to"]),
+            (&json!("sent"), &json!(3))
+        );
+        for expected in ["guest-3 joined", "guest-3: hey", "alice: hi"] {
+            assert_eq!(recv(&mut bob_rx).await, expected);
+        }
+        assert_eq!(recv(&mut bob_rx).await, "[dm] guest-3: hi");
+        let receipt = json(&mut carol_rx).await;
+        assert_eq!(
+            receipt,
+            json!({"v": 1, "cmd": "delivered", "data": {"id": sent["data"]["id"], "to": "bob"}})
+        );
+        send(&alice, "/msg dave hi").await;
+        assert_eq!(recv(&mut alice_rx).await, "!!! dave is offline");
+
+        bob.io().close();
+        assert_eq!(recv(&mut alice_rx).await, "bob disconnected");
+
+        let res = srv.get("/metrics").send().await.unwrap();
+        let metrics: Value = serde_json::from_slice(&res.body().await.unwrap()).unwrap();
+        assert!(metrics["queued"].as_u64().unwrap() > 20);
+        assert_eq!(metrics["dropped"], 0);
+    }
+}
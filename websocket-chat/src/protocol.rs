@@ -0,0 +1,469 @@
+//! Messages exchanged with websocket clients.
+//!
+//! Clients pick the protocol with the `Sec-WebSocket-Protocol` header:
+//!
+//! * `chat.v1`, the default, every text frame is a JSON object tagged by
+//!   `cmd`, with the protocol version in `v`. Requests may carry an `id`,
+//!   answers to them have it in `reply_to`.
+//! * `chat.text`, the legacy protocol, slash commands and plain text lines.
+use serde::{Deserialize, Serialize};
+
+use crate::history::Entry;
+use crate::server::{ClientMessage, DirectMessage};
+
+/// Version of the JSON protocol
+pub const VERSION: u32 = 1;
+
+/// Wire protocol of a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Json,
+    Text,
+}
+
+impl Protocol {
+    /// Subprotocol name
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Json => "chat.v1",
+            Protocol::Text => "chat.text",
+        }
+    }
+
+    /// First supported protocol offered by the client.
+    pub fn negotiate<'a>(mut offered: impl Iterator<Item = &'a str>) -> Option<Self> {
+        offered.find_map(|name| {
+            [Protocol::Json, Protocol::Text]
+                .iter()
+                .copied()
+                .find(|p| p.name() == name)
+        })
+    }
+
+    /// Parse a text frame, the error is the answer to send back.
+    pub fn decode(self, text: &str) -> Result<Request, ClientMessage> {
+        match self {
+            Protocol::Json => Request::from_json(text),
+            Protocol::Text => ChatRequest::from_text(text)
+                .map(|req| Request {
+                    v: VERSION,
+                    id: None,
+                    req,
+                })
+                .map_err(ClientMessage::Error),
+        }
+    }
+
+    /// Text frames for a message of the chat server.
+    pub fn encode(self, msg: ClientMessage) -> Vec<String> {
+        match self {
+            Protocol::Json => vec![Response::from_message(None, msg).to_json()],
+            Protocol::Text => text_lines(msg),
+        }
+    }
+}
+
+/// Client request
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "cmd", content = "data", rename_all = "snake_case")]
+pub enum ChatRequest {
+    /// List rooms
+    List,
+    /// Join room
+    Join(JoinRoom),
+    /// Change nickname
+    Nick(String),
+    /// List members of the room
+    Who,
+    /// Latest messages of the room
+    History(usize),
+    /// Send message to the room
+    Message(String),
+    /// Send message to a single session
+    Direct {
+        /// Nickname of the recipient
+        to: String,
+        text: String,
+    },
+    /// Remove a member from the room
+    Kick(String),
+    /// Remove a member from the room for good
+    Ban(String),
+    /// Let a session into the room
+    Invite(String),
+    /// Set or clear the room password
+    Password(Option<String>),
+    /// Make the room invite-only or open
+    InviteOnly(bool),
+}
+
+/// Room to join, protected rooms need the password
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum JoinRoom {
+    Open(String),
+    Protected { room: String, password: String },
+}
+
+impl JoinRoom {
+    /// Room name and password
+    pub fn into_parts(self) -> (String, Option<String>) {
+        match self {
+            JoinRoom::Open(room) => (room, None),
+            JoinRoom::Protected { room, password } => (room, Some(password)),
+        }
+    }
+}
+
+impl ChatRequest {
+    /// Parse a line of the legacy text protocol.
+    pub fn from_text(line: &str) -> Result<Self, String> {
+        // we check for `/sss` type of messages
+        if !line.starts_with('/') {
+            return Ok(ChatRequest::Message(line.to_owned()));
+        }
+        let v: Vec<&str> = line.splitn(2, ' ').collect();
+        let arg = v.get(1).map(|arg| arg.trim()).filter(|arg| !arg.is_empty());
+        match (v[0], arg) {
+            ("/list", _) => Ok(ChatRequest::List),
+            ("/join", Some(arg)) => {
+                let mut arg = arg.splitn(2, ' ');
+                let room = arg.next().unwrap_or("").to_owned();
+                Ok(ChatRequest::Join(match arg.next().map(str::trim) {
+                    Some(password) if !password.is_empty() => JoinRoom::Protected {
+                        room,
+                        password: password.to_owned(),
+                    },
+                    _ => JoinRoom::Open(room),
+                }))
+            }
+            ("/join", None) => Err("room name is required".to_owned()),
+            ("/nick" | "/name", Some(name)) => Ok(ChatRequest::Nick(name.to_owned())),
+            ("/nick" | "/name", None) => Err("name is required".to_owned()),
+            ("/who", _) => Ok(ChatRequest::Who),
+            ("/kick", Some(name)) => Ok(ChatRequest::Kick(name.to_owned())),
+            ("/ban", Some(name)) => Ok(ChatRequest::Ban(name.to_owned())),
+            ("/invite", Some(name)) => Ok(ChatRequest::Invite(name.to_owned())),
+            ("/kick" | "/ban" | "/invite", None) => {
+                Err("nickname is required".to_owned())
+            }
+            ("/password", password) => {
+                Ok(ChatRequest::Password(password.map(str::to_owned)))
+            }
+            ("/invite-only", Some("on")) => Ok(ChatRequest::InviteOnly(true)),
+            ("/invite-only", Some("off")) => Ok(ChatRequest::InviteOnly(false)),
+            ("/invite-only", _) => Err("on or off is required".to_owned()),
+            ("/msg", arg) => {
+                let mut arg = arg.unwrap_or("").splitn(2, ' ');
+                match (arg.next(), arg.next().map(str::trim)) {
+                    (Some(to), Some(text)) if !to.is_empty() && !text.is_empty() => {
+                        Ok(ChatRequest::Direct {
+                            to: to.to_owned(),
+                            text: text.to_owned(),
+                        })
+                    }
+                    _ => Err("nickname and message are required".to_owned()),
+                }
+            }
+            ("/history", None) => {
+                Ok(ChatRequest::History(crate::server::REPLAY_MESSAGES))
+            }
+            ("/history", Some(count)) => count
+                .parse()
+                .map(ChatRequest::History)
+                .map_err(|_| "number of messages is required".to_owned()),
+            _ => Err(format!("unknown command: {:?}", line)),
+        }
+    }
+}
+
+/// Request envelope of the JSON protocol
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Request {
+    /// Protocol version
+    pub v: u32,
+    /// Set by the client to match answers to the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub req: ChatRequest,
+}
+
+impl Request {
+    fn from_json(text: &str) -> Result<Self, ClientMessage> {
+        // version and id are checked first, so errors can refer to the request
+        #[derive(Deserialize)]
+        struct Header {
+            v: Option<u32>,
+            id: Option<u64>,
+        }
+
+        let error = |id: Option<u64>, e: String| match id {
+            Some(id) => ClientMessage::Reply(id, Box::new(ClientMessage::Error(e))),
+            None => ClientMessage::Error(e),
+        };
+        let header: Header = serde_json::from_str(text)
+            .map_err(|e| error(None, format!("invalid request: {}", e)))?;
+        match header.v {
+            Some(VERSION) => serde_json::from_str(text)
+                .map_err(|e| error(header.id, format!("invalid request: {}", e))),
+            Some(v) => Err(error(header.id, format!("unsupported version: {}", v))),
+            None => Err(error(header.id, "protocol version is required".to_owned())),
+        }
+    }
+}
+
+/// Server response
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "cmd", content = "data", rename_all = "snake_case")]
+pub enum ChatResponse {
+    /// Current nickname
+    Nick(String),
+    /// List of rooms
+    Rooms(Vec<String>),
+    /// Nicknames of the room members
+    Members(Vec<String>),
+    /// Chat message
+    Message(Entry),
+    /// Earlier messages, oldest first
+    History(Vec<Entry>),
+    /// Server notice
+    Notice(String),
+    /// Direct message from another session
+    Direct(DirectMessage),
+    /// Direct message was accepted for delivery
+    Sent(DirectMessage),
+    /// Direct message reached the recipient
+    Delivered {
+        /// Message id
+        id: u64,
+        /// Nickname of the recipient
+        to: String,
+    },
+    /// Request failed
+    Error(String),
+}
+
+/// Response envelope of the JSON protocol
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Response {
+    /// Protocol version
+    pub v: u32,
+    /// Id of the request this answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    #[serde(flatten)]
+    pub res: ChatResponse,
+}
+
+impl Response {
+    fn new(reply_to: Option<u64>, res: ChatResponse) -> Self {
+        Response {
+            v: VERSION,
+            reply_to,
+            res,
+        }
+    }
+
+    /// Response for a message of the chat server.
+    fn from_message(reply_to: Option<u64>, msg: ClientMessage) -> Self {
+        let res = match msg {
+            ClientMessage::Nick(name) => ChatResponse::Nick(name),
+            ClientMessage::Message(entry) => ChatResponse::Message(entry),
+            ClientMessage::Notice(text) => ChatResponse::Notice(text),
+            ClientMessage::Rooms(rooms) => ChatResponse::Rooms(rooms),
+            ClientMessage::Members(members) => ChatResponse::Members(members),
+            ClientMessage::History(entries) => ChatResponse::History(entries),
+            ClientMessage::Direct(dm) => ChatResponse::Direct(dm),
+            ClientMessage::Sent(dm) => ChatResponse::Sent(dm),
+            ClientMessage::Delivered { id, to } => ChatResponse::Delivered { id, to },
+            ClientMessage::Error(e) => ChatResponse::Error(e),
+            ClientMessage::Reply(tag, msg) => {
+                return Self::from_message(Some(tag), *msg)
+            }
+        };
+        Response::new(reply_to, res)
+    }
+
+    /// Prints out the value as JSON string.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Should never failed")
+    }
+}
+
+/// Lines of the legacy text protocol for a message of the chat server.
+fn text_lines(msg: ClientMessage) -> Vec<String> {
+    match msg {
+        ClientMessage::Nick(name) => vec![format!("*** you are now known as {}", name)],
+        ClientMessage::Message(entry) => {
+            vec![format!("{}: {}", entry.sender, entry.text)]
+        }
+        ClientMessage::Notice(text) => vec![text],
+        ClientMessage::Rooms(rooms) => rooms,
+        ClientMessage::Members(members) => {
+            vec![format!("*** members: {}", members.join(", "))]
+        }
+        ClientMessage::History(entries) => entries
+            .into_iter()
+            .map(|entry| {
+                // time of day, utc
+                let secs = entry.time % 86400;
+                format!(
+                    "[{:02}:{:02}] {}: {}",
+                    secs / 3600,
+                    secs / 60 % 60,
+                    entry.sender,
+                    entry.text
+                )
+            })
+            .collect(),
+        ClientMessage::Direct(dm) => vec![format!("[dm] {}: {}", dm.from, dm.text)],
+        ClientMessage::Sent(dm) => vec![format!("[dm -> {}] {}", dm.to, dm.text)],
+        ClientMessage::Delivered { to, .. } => vec![format!("*** delivered to {}", to)],
+        ClientMessage::Error(e) => vec![format!("!!! {}", e)],
+        ClientMessage::Reply(_, msg) => text_lines(*msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |offered: &[&str]| Protocol::negotiate(offered.iter().copied());
+        assert_eq!(negotiate(&["chat.text"]), Some(Protocol::Text));
+        assert_eq!(
+            negotiate(&["chat.v2", "chat.v1", "chat.text"]),
+            Some(Protocol::Json)
+        );
+        assert_eq!(negotiate(&["graphql-ws"]), None);
+        assert_eq!(negotiate(&[]), None);
+    }
+
+    #[test]
+    fn test_json() {
+        let decode = |v: Value| Protocol::Json.decode(&v.to_string());
+        assert_eq!(
+            decode(json!({"v": 1, "id": 7, "cmd": "join", "data": "Rust"})).unwrap(),
+            Request {
+                v: 1,
+                id: Some(7),
+                req: ChatRequest::Join(JoinRoom::Open("Rust".into()))
+            }
+        );
+        assert_eq!(
+            decode(json!({
+                "v": 1,
+                "cmd": "join",
+                "data": {"room": "Rust", "password": "crab"}
+            }))
+            .unwrap()
+            .req,
+            ChatRequest::Join(JoinRoom::Protected {
+                room: "Rust".into(),
+                password: "crab".into()
+            })
+        );
+        assert_eq!(
+            decode(json!({"v": 1, "cmd": "invite_only", "data": true}))
+                .unwrap()
+                .req,
+            ChatRequest::InviteOnly(true)
+        );
+        assert_eq!(
+            decode(json!({"v": 1, "cmd": "who"})).unwrap().req,
+            ChatRequest::Who
+        );
+
+        let error = |v: Value| {
+            let msg = Protocol::Json.encode(decode(v).unwrap_err()).remove(0);
+            serde_json::from_str::<Response>(&msg).unwrap()
+        };
+        let res = error(json!({"v": 2, "id": 1, "cmd": "who"}));
+        assert_eq!(res.reply_to, Some(1));
+        assert_eq!(
+            res.res,
+            ChatResponse::Error("unsupported version: 2".into())
+        );
+        let res = error(json!({"v": 1, "id": 2, "cmd": "join"}));
+        assert_eq!(res.reply_to, Some(2));
+        assert!(matches!(res.res, ChatResponse::Error(_)));
+        assert!(matches!(
+            error(json!({"cmd": "who"})).res,
+            ChatResponse::Error(_)
+        ));
+        assert!(Protocol::Json.decode("/who").is_err());
+
+        let entry = Entry {
+            id: 3,
+            room: "Main".into(),
+            sender: "alice".into(),
+            text: "hi".into(),
+            time: 60,
+        };
+        let msg =
+            ClientMessage::Reply(7, Box::new(ClientMessage::Message(entry.clone())));
+        let encoded: Value =
+            serde_json::from_str(&Protocol::Json.encode(msg)[0]).unwrap();
+        assert_eq!(
+            encoded,
+            json!({
+                "v": 1,
+                "reply_to": 7,
+                "cmd": "message",
+                "data": {"id": 3, "room": "Main", "sender": "alice", "text": "hi", "time": 60}
+            })
+        );
+        assert_eq!(
+            Protocol::Text.encode(ClientMessage::History(vec![entry])),
+            ["[00:01] alice: hi"]
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let decode = |line: &str| Protocol::Text.decode(line).map(|req| req.req);
+        assert_eq!(
+            decode("hello").unwrap(),
+            ChatRequest::Message("hello".into())
+        );
+        assert_eq!(
+            decode("/name bob").unwrap(),
+            ChatRequest::Nick("bob".into())
+        );
+        assert_eq!(decode("/history 5").unwrap(), ChatRequest::History(5));
+        assert_eq!(decode("/list").unwrap(), ChatRequest::List);
+        assert_eq!(
+            decode("/join Rust crab").unwrap(),
+            ChatRequest::Join(JoinRoom::Protected {
+                room: "Rust".into(),
+                password: "crab".into()
+            })
+        );
+        assert_eq!(decode("/password").unwrap(), ChatRequest::Password(None));
+        assert_eq!(
+            decode("/invite-only off").unwrap(),
+            ChatRequest::InviteOnly(false)
+        );
+        assert_eq!(
+            decode("/msg bob  see you later").unwrap(),
+            ChatRequest::Direct {
+                to: "bob".into(),
+                text: "see you later".into()
+            }
+        );
+
+        let error = |line: &str| {
+            let msg = Protocol::Text.decode(line).unwrap_err();
+            Protocol::Text.encode(msg).remove(0)
+        };
+        assert_eq!(error("/join"), "!!! room name is required");
+        assert_eq!(error("/history x"), "!!! number of messages is required");
+        assert_eq!(error("/msg bob"), "!!! nickname and message are required");
+        assert_eq!(error("/kick"), "!!! nickname is required");
+        assert_eq!(error("/invite-only yes"), "!!! on or off is required");
+        assert_eq!(error("/nope"), "!!! unknown command: \"/nope\"");
+    }
+}
@@ -0,0 +1,226 @@
+//! Bounded queue of messages from the chat server to a session.
+//!
+//! The chat server never waits for a session. Once the queue of a session is
+//! full, the [`Overflow`] policy decides what happens to new messages.
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::{fmt, str::FromStr};
+
+use futures::{task::AtomicWaker, Stream};
+
+/// What to do with messages for a session that does not keep up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Drop the oldest queued message to make room
+    DropOldest,
+    /// Drop the new message
+    DropNew,
+    /// Disconnect the session
+    Disconnect,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-new" => Ok(Overflow::DropNew),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err(format!("unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Overflow::DropOldest => "drop-oldest",
+            Overflow::DropNew => "drop-new",
+            Overflow::Disconnect => "disconnect",
+        })
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    /// All senders are dropped
+    closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    receiver: AtomicWaker,
+    capacity: usize,
+    senders: AtomicUsize,
+    /// Messages lost to the overflow policy
+    dropped: AtomicU64,
+}
+
+impl<T> Shared<T> {
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Create a queue holding up to `capacity` messages.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            closed: false,
+        }),
+        receiver: AtomicWaker::new(),
+        capacity: capacity.max(1),
+        senders: AtomicUsize::new(1),
+        dropped: AtomicU64::new(0),
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+/// Chat server side of the queue, the queue is closed once every clone is
+/// dropped
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T> Sender<T> {
+    /// Queue message, applying the overflow policy if the queue is full.
+    ///
+    /// Returns `false` if the message, or an older one, was dropped.
+    pub fn push(&self, item: T, overflow: Overflow) -> bool {
+        let item = match self.try_push(item) {
+            Ok(()) => return true,
+            Err(item) => item,
+        };
+        if overflow == Overflow::DropOldest {
+            self.push_evict(item);
+        }
+        self.0.dropped.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Number of messages lost to the overflow policy
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Queue message, it is returned back if the queue is full.
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        let mut state = self.0.state();
+        if state.items.len() >= self.0.capacity {
+            return Err(item);
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.0.receiver.wake();
+        Ok(())
+    }
+
+    /// Queue message, returns the oldest one if it was dropped to make room.
+    pub fn push_evict(&self, item: T) -> Option<T> {
+        let mut state = self.0.state();
+        let evicted = if state.items.len() >= self.0.capacity {
+            state.items.pop_front()
+        } else {
+            None
+        };
+        state.items.push_back(item);
+        drop(state);
+        self.0.receiver.wake();
+        evicted
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::Relaxed);
+        Sender(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.0.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.state().closed = true;
+            self.0.receiver.wake();
+        }
+    }
+}
+
+/// Session side of the queue, ends once the sender is dropped and the queued
+/// messages are consumed
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T> Receiver<T> {
+    /// Next queued message, if any.
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.0.state().items.pop_front()
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        // register first, so a push after the check is not missed
+        self.0.receiver.register(cx.waker());
+        let mut state = self.0.state();
+        match state.items.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if state.closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_overflow() {
+        let (tx, mut rx) = channel(2);
+        assert_eq!(tx.try_push(1), Ok(()));
+        assert_eq!(tx.try_push(2), Ok(()));
+        assert_eq!(tx.try_push(3), Err(3));
+        assert_eq!(tx.push_evict(4), Some(1));
+        assert_eq!(rx.try_recv(), Some(2));
+        assert_eq!(tx.push_evict(5), None);
+        assert_eq!(rx.try_recv(), Some(4));
+        assert_eq!(rx.try_recv(), Some(5));
+        assert_eq!(rx.try_recv(), None);
+
+        assert!(tx.push(6, Overflow::DropNew));
+        assert!(tx.push(7, Overflow::DropNew));
+        assert!(!tx.push(8, Overflow::DropNew));
+        assert!(!tx.push(9, Overflow::DropOldest));
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(rx.try_recv(), Some(7));
+        assert_eq!(rx.try_recv(), Some(9));
+
+        assert_eq!("drop-new".parse(), Ok(Overflow::DropNew));
+        assert!("drop-all".parse::<Overflow>().is_err());
+    }
+
+    #[ntex::test]
+    async fn test_stream() {
+        let (tx, rx) = channel(8);
+        let producer = tx.clone();
+        // the queue stays open while a clone is alive
+        drop(tx);
+        let thread = std::thread::spawn(move || {
+            for i in 0..100 {
+                while producer.try_push(i).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let items: Vec<u32> = rx.collect().await;
+        thread.join().unwrap();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+    }
+}
@@ -1,36 +1,121 @@
 //! `ChatServer` maintains list of connection client session.
 //! And manages available rooms. Peers send messages to other peers in same
 //! room through `ChatServer`.
+//!
+//! Rooms are spread over several chat servers, the shards, by the hash of the
+//! room name. Every shard runs on an arbiter of its own and holds the sessions
+//! that are members of its rooms. A session joining a room of another shard is
+//! handed over once that shard admits it, the connection then sends its
+//! requests to the new shard. Nicknames, session ids and direct messages are
+//! global, shards share them through the [`Directory`].
+//!
+//! Sessions create rooms by joining them and own the rooms they create. Owners
+//! can protect their room with a password or make it invite-only, and kick or
+//! ban members. Empty rooms are removed, along with their settings.
+//!
+//! Every session has a nickname, unique across the server. New sessions get
+//! a `guest-N` name until they pick one with `/nick`.
+//!
+//! Sessions can also message each other directly, the sender gets a receipt
+//! once the message is written to the recipient's connection.
+//!
+//! Messages for a session wait in a bounded queue until its connection
+//! takes them, sessions that fall behind lose messages or get disconnected
+//! according to the [`Overflow`] policy.
+//!
+//! Room messages are stored in the [`History`], sessions get the latest ones
+//! when they join a room. Database calls block, which is fine as every shard
+//! has a thread of its own.
 
-use rand::{self, rngs::ThreadRng, Rng};
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use futures::channel::mpsc::{self, UnboundedSender};
-use futures::{SinkExt, StreamExt};
+use futures::channel::oneshot;
+use futures::StreamExt;
 use ntex::rt;
+use serde::{Deserialize, Serialize};
+
+use crate::directory::Directory;
+use crate::history::{self, Entry, History};
+use crate::queue::{self, Overflow};
+
+/// Room every session joins first, it has no owner and is never removed
+const MAIN_ROOM: &str = "Main";
+/// Number of messages sent to sessions joining a room
+pub const REPLAY_MESSAGES: usize = 20;
+
+/// Message sent to a single session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirectMessage {
+    /// Message id, from the same sequence as room messages
+    pub id: u64,
+    /// Nickname of the sender
+    pub from: String,
+    /// Nickname of the recipient
+    pub to: String,
+    pub text: String,
+    /// Seconds since unix epoch
+    pub time: u64,
+}
 
 /// Chat server sends this messages to session
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ClientMessage {
-    Id(usize),
-    Message(String),
+    /// Current nickname of the session
+    Nick(String),
+    /// Chat message of a room member
+    Message(Entry),
+    /// Server notice, like presence changes
+    Notice(String),
     Rooms(Vec<String>),
+    /// Nicknames of the room members
+    Members(Vec<String>),
+    /// Earlier messages of the room, oldest first
+    History(Vec<Entry>),
+    /// Direct message from another session
+    Direct(DirectMessage),
+    /// Direct message was accepted for delivery
+    Sent(DirectMessage),
+    /// Direct message reached the recipient
+    Delivered {
+        /// Message id
+        id: u64,
+        /// Nickname of the recipient
+        to: String,
+    },
+    /// Command was rejected
+    Error(String),
+    /// Answer to a tagged request
+    Reply(u64, Box<ClientMessage>),
+}
+
+/// Answer to [`ServerMessage::Connect`]
+pub struct Connected {
+    /// Session id
+    pub id: usize,
+    /// Messages for the session
+    pub rx: queue::Receiver<ClientMessage>,
+    /// Shard holding the session, see [`Chat::send`]
+    pub shard: Arc<AtomicUsize>,
 }
 
 /// Message for chat server communications
 pub enum ServerMessage {
-    /// New chat session is created
-    Connect(UnboundedSender<ClientMessage>),
+    /// New chat session is created, the chat server answers with the session
+    /// id and the queue of messages for the session
+    Connect(oneshot::Sender<Connected>),
     /// Client session is closed
     Disconnect(usize),
-    /// Send message to specific room
+    /// Send message to the session's room
     Message {
         /// Id of the client session
         id: usize,
         /// Peer message
         msg: String,
-        /// Room name
-        room: String,
     },
     /// List of available rooms
     ListRooms(usize),
@@ -40,158 +125,1772 @@ pub enum ServerMessage {
         id: usize,
         /// Room name
         name: String,
+        /// Password of a protected room
+        password: Option<String>,
+    },
+    /// Remove a member from the session's room, owner only
+    Kick {
+        /// Client id
+        id: usize,
+        /// Nickname of the member
+        name: String,
+    },
+    /// Remove a member from the session's room for good, owner only
+    Ban {
+        /// Client id
+        id: usize,
+        /// Nickname of the member
+        name: String,
+    },
+    /// Let a session into the session's room, owner only
+    Invite {
+        /// Client id
+        id: usize,
+        /// Nickname of the invited session
+        name: String,
+    },
+    /// Set or clear the password of the session's room, owner only
+    Password {
+        /// Client id
+        id: usize,
+        password: Option<String>,
+    },
+    /// Make the session's room invite-only or open, owner only
+    InviteOnly {
+        /// Client id
+        id: usize,
+        on: bool,
+    },
+    /// Change session nickname
+    Nick {
+        /// Client id
+        id: usize,
+        /// New nickname
+        name: String,
+    },
+    /// List members of the session's room
+    Who(usize),
+    /// Fetch latest messages of the session's room
+    History {
+        /// Client id
+        id: usize,
+        /// Number of messages
+        count: usize,
+    },
+    /// Send message to a single session
+    Direct {
+        /// Client id
+        id: usize,
+        /// Nickname of the recipient
+        to: String,
+        msg: String,
+    },
+    /// Direct message was written to the recipient's connection
+    Delivered {
+        /// Client id of the recipient
+        id: usize,
+        /// Message id
+        message: u64,
+    },
+    /// Request with a tag, messages sent back to the session while it is
+    /// handled are wrapped in [`ClientMessage::Reply`] with the same tag.
+    Tagged {
+        /// Client id
+        id: usize,
+        tag: u64,
+        msg: Box<ServerMessage>,
+    },
+    /// Session is handed over to the shard of a room, between shards only
+    Enter {
+        /// Client id
+        id: usize,
+        /// Room name
+        room: String,
+        password: Option<String>,
+        session: Session,
+        /// Shard still holding the session, it waits for [`ServerMessage::Moved`]
+        from: Option<usize>,
+    },
+    /// Answer to [`ServerMessage::Enter`], between shards only
+    Moved {
+        /// Client id
+        id: usize,
+        /// Session joined the room, the old shard lets it go
+        accepted: bool,
     },
 }
 
+impl ServerMessage {
+    /// Session that sent the request, it is handled by the shard holding it
+    fn session(&self) -> Option<usize> {
+        match self {
+            ServerMessage::Disconnect(id)
+            | ServerMessage::ListRooms(id)
+            | ServerMessage::Who(id)
+            | ServerMessage::Message { id, .. }
+            | ServerMessage::Join { id, .. }
+            | ServerMessage::Kick { id, .. }
+            | ServerMessage::Ban { id, .. }
+            | ServerMessage::Invite { id, .. }
+            | ServerMessage::Password { id, .. }
+            | ServerMessage::InviteOnly { id, .. }
+            | ServerMessage::Nick { id, .. }
+            | ServerMessage::History { id, .. }
+            | ServerMessage::Direct { id, .. } => Some(*id),
+            ServerMessage::Tagged { msg, .. } => msg.session(),
+            ServerMessage::Connect(_)
+            | ServerMessage::Delivered { .. }
+            | ServerMessage::Enter { .. }
+            | ServerMessage::Moved { .. } => None,
+        }
+    }
+}
+
+/// Outbound queues of the sessions
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// Messages queued per session
+    pub capacity: usize,
+    /// What to do once a queue is full
+    pub overflow: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: 256,
+            overflow: Overflow::DropOldest,
+        }
+    }
+}
+
+/// Counters of the chat server, shared with the http server
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Messages queued for sessions
+    pub queued: AtomicU64,
+    /// Messages lost because the queue of a session was full
+    pub dropped: AtomicU64,
+    /// Sessions disconnected because their queue was full
+    pub disconnected: AtomicU64,
+}
+
+impl Metrics {
+    /// Current values of the counters
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "queued": self.queued.load(Ordering::Relaxed),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+            "disconnected": self.disconnected.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Shard of a room
+pub fn shard_of(room: &str, shards: usize) -> usize {
+    (history::room_hash(room) % shards as u64) as usize
+}
+
+/// Handle to the chat server shards, they stop once every handle is dropped
+#[derive(Clone)]
+pub struct Chat(Arc<Shards>);
+
+struct Shards(Vec<UnboundedSender<ServerMessage>>);
+
+impl Drop for Shards {
+    fn drop(&mut self) {
+        for shard in &self.0 {
+            shard.close_channel();
+        }
+    }
+}
+
+impl Chat {
+    /// Register new session with the shard of the main room
+    pub fn connect(&self) -> oneshot::Receiver<Connected> {
+        let (tx, rx) = oneshot::channel();
+        let shard = shard_of(MAIN_ROOM, self.0 .0.len());
+        let _ = self.0 .0[shard].unbounded_send(ServerMessage::Connect(tx));
+        rx
+    }
+
+    /// Send message to the shard holding the session
+    pub fn send(&self, shard: &AtomicUsize, msg: ServerMessage) {
+        let shard = shard.load(Ordering::Acquire);
+        let _ = self.0 .0[shard].unbounded_send(msg);
+    }
+}
+
+/// State shared by the shards
+struct Shared {
+    directory: Mutex<Directory>,
+    /// Id of the last chat message
+    last_message: AtomicU64,
+    queue: QueueConfig,
+    metrics: Arc<Metrics>,
+    /// Times the directory was locked
+    #[cfg(test)]
+    locks: AtomicUsize,
+}
+
+impl Shared {
+    fn new(last_message: u64, queue: QueueConfig, metrics: Arc<Metrics>) -> Self {
+        Shared {
+            directory: Mutex::new(Directory::new()),
+            last_message: AtomicU64::new(last_message),
+            queue,
+            metrics,
+            #[cfg(test)]
+            locks: AtomicUsize::new(0),
+        }
+    }
+
+    fn directory(&self) -> MutexGuard<'_, Directory> {
+        #[cfg(test)]
+        self.locks.fetch_add(1, Ordering::Relaxed);
+        self.directory
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Client session held by a shard
+pub struct Session {
+    addr: queue::Sender<ClientMessage>,
+    name: String,
+}
+
+/// Chat room
+#[derive(Default)]
+struct Room {
+    members: HashSet<usize>,
+    /// Session that created the room, or got it from the previous owner
+    owner: Option<usize>,
+    password: Option<String>,
+    /// Only the owner and invited sessions can join
+    invite_only: bool,
+    /// Lowercase nicknames of the invited sessions
+    invited: HashSet<String>,
+    /// Banned sessions
+    banned: HashSet<usize>,
+    /// Lowercase nicknames of the banned sessions
+    banned_names: HashSet<String>,
+}
+
 /// `ChatServer` manages chat rooms and responsible for coordinating chat
 /// session. implementation is super primitive
 pub struct ChatServer {
-    sessions: HashMap<usize, UnboundedSender<ClientMessage>>,
-    rooms: HashMap<String, HashSet<usize>>,
-    rng: ThreadRng,
+    /// Index of this shard
+    shard: usize,
+    /// Chat servers of all shards
+    shards: Vec<UnboundedSender<ServerMessage>>,
+    /// Sessions in the rooms of this shard
+    sessions: HashMap<usize, Session>,
+    rooms: HashMap<String, Room>,
+    shared: Arc<Shared>,
+    history: History,
+    /// Session and tag of the request being handled
+    reply: Option<(usize, u64)>,
+    /// Sessions waiting for another shard to admit them
+    moving: HashSet<usize>,
+    /// Sessions to disconnect, their queue overflowed
+    lagging: RefCell<Vec<usize>>,
 }
 
-impl Default for ChatServer {
-    fn default() -> ChatServer {
-        // default room
+/// Seconds since unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl ChatServer {
+    fn new(
+        shard: usize,
+        shards: Vec<UnboundedSender<ServerMessage>>,
+        shared: Arc<Shared>,
+        history: History,
+    ) -> ChatServer {
         let mut rooms = HashMap::new();
-        rooms.insert("Main".to_owned(), HashSet::new());
+        if shard_of(MAIN_ROOM, shards.len()) == shard {
+            // default room
+            rooms.insert(MAIN_ROOM.to_owned(), Room::default());
+            shared.directory().add_room(MAIN_ROOM);
+        }
 
         ChatServer {
+            shard,
+            shards,
             sessions: HashMap::new(),
             rooms,
-            rng: rand::thread_rng(),
+            shared,
+            history,
+            reply: None,
+            moving: HashSet::new(),
+            lagging: RefCell::new(Vec::new()),
         }
     }
-}
 
-impl ChatServer {
-    /// Send message to all users in the room
-    fn send_message(&mut self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        let msg = message.to_owned();
-                        let mut addr = addr.clone();
-                        rt::spawn(async move {
-                            let _ = addr.send(ClientMessage::Message(msg)).await;
-                        });
-                    }
+    fn directory(&self) -> MutexGuard<'_, Directory> {
+        self.shared.directory()
+    }
+
+    /// Send message to a single session
+    fn send(&self, id: usize, msg: ClientMessage) {
+        let msg = match self.reply {
+            Some((session, tag)) if session == id => {
+                ClientMessage::Reply(tag, Box::new(msg))
+            }
+            _ => msg,
+        };
+        match self.sessions.get(&id) {
+            Some(session) => self.push(id, &session.addr, msg),
+            None => {
+                // session of another shard
+                let addr = match self.directory().get(id) {
+                    Some(member) => member.addr.clone(),
+                    None => return,
+                };
+                self.push(id, &addr, msg);
+            }
+        }
+    }
+
+    /// Queue message for a session, sessions that do not keep up lose messages
+    fn push(&self, id: usize, addr: &queue::Sender<ClientMessage>, msg: ClientMessage) {
+        let metrics = &self.shared.metrics;
+        let overflow = self.shared.queue.overflow;
+        if addr.push(msg, overflow) {
+            metrics.queued.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        metrics.dropped.fetch_add(1, Ordering::Relaxed);
+        match overflow {
+            Overflow::DropOldest => {
+                metrics.queued.fetch_add(1, Ordering::Relaxed);
+            }
+            Overflow::DropNew => (),
+            Overflow::Disconnect => {
+                let mut lagging = self.lagging.borrow_mut();
+                if !lagging.contains(&id) {
+                    lagging.push(id);
                 }
             }
         }
     }
 
-    /// Handler for server messages.
-    fn handle(&mut self, msg: ServerMessage) {
-        match msg {
-            // Register new session and assign unique id to this session
-            ServerMessage::Connect(mut sender) => {
-                println!("Someone joined");
+    /// Send message to all users in the room, except `skip`
+    fn broadcast(&self, room: &str, msg: ClientMessage, skip: Option<usize>) {
+        if let Some(room) = self.rooms.get(room) {
+            for id in &room.members {
+                if Some(*id) != skip {
+                    self.send(*id, msg.clone());
+                }
+            }
+        }
+    }
 
-                // notify all users in same room
-                self.send_message("Main", "Someone joined", 0);
+    /// Send notice to all users in the room, except `skip`
+    fn send_message(&self, room: &str, message: &str, skip: Option<usize>) {
+        self.broadcast(room, ClientMessage::Notice(message.to_owned()), skip);
+    }
 
-                // register session with random id
-                let id = self.rng.gen::<usize>();
-                self.sessions.insert(id, sender.clone());
+    /// Pass message on to another shard, answers keep the tag of the request
+    fn forward(&self, shard: usize, id: usize, msg: ServerMessage) {
+        let msg = match self.reply {
+            Some((session, tag)) if session == id => ServerMessage::Tagged {
+                id,
+                tag,
+                msg: Box::new(msg),
+            },
+            _ => msg,
+        };
+        let _ = self.shards[shard].unbounded_send(msg);
+    }
 
-                // auto join session to Main room
-                self.rooms
-                    .entry("Main".to_owned())
-                    .or_insert_with(HashSet::new)
-                    .insert(id);
+    /// Shard the message belongs to, if it is not this one
+    ///
+    /// Requests sent while the session moved to another shard follow it.
+    fn elsewhere(&self, msg: &ServerMessage) -> Option<usize> {
+        let id = msg.session()?;
+        if self.sessions.contains_key(&id) {
+            return None;
+        }
+        self.directory()
+            .shard(id)
+            .filter(|shard| *shard != self.shard)
+    }
+
+    /// Nickname of the session
+    fn name(&self, id: usize) -> &str {
+        self.sessions
+            .get(&id)
+            .map(|s| s.name.as_str())
+            .unwrap_or("")
+    }
 
-                // send id back
-                rt::spawn(async move {
-                    let _ = sender.send(ClientMessage::Id(id)).await;
-                });
+    /// Remove session from all rooms, returns names of the rooms it left
+    ///
+    /// Rooms left empty are removed, rooms left by their owner get a new one.
+    fn leave_rooms(&mut self, id: usize) -> Vec<String> {
+        let mut rooms = Vec::new();
+        let mut owners = Vec::new();
+        for (name, room) in &mut self.rooms {
+            if !room.members.remove(&id) {
+                continue;
+            }
+            if room.owner == Some(id) {
+                // the earliest session gets the room
+                room.owner = room.members.iter().min().copied();
+                if let Some(owner) = room.owner {
+                    owners.push((owner, name.to_owned()));
+                }
+            }
+            rooms.push(name.to_owned());
+        }
+        for name in &rooms {
+            if name != MAIN_ROOM && self.rooms[name].members.is_empty() {
+                self.rooms.remove(name);
+                self.directory().remove_room(name);
             }
+        }
 
-            // Handler for Disconnect message.
-            ServerMessage::Disconnect(id) => {
-                println!("Someone disconnected");
+        for (owner, room) in owners {
+            let msg = format!("you are now the owner of {}", room);
+            self.send(owner, ClientMessage::Notice(msg));
+        }
+        rooms
+    }
 
-                let mut rooms: Vec<String> = Vec::new();
+    /// Check whether the session may join a room of this shard
+    fn admit(
+        &self,
+        id: usize,
+        nick: &str,
+        name: &str,
+        password: Option<&str>,
+    ) -> Result<(), String> {
+        let room = match self.rooms.get(name) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let nick = nick.to_lowercase();
+        if room.banned.contains(&id) || room.banned_names.contains(&nick) {
+            return Err(format!("you are banned from {}", name));
+        }
+        if room.owner == Some(id) || room.invited.contains(&nick) {
+            return Ok(());
+        }
+        if room.invite_only {
+            return Err(format!("{} is invite-only", name));
+        }
+        match (&room.password, password) {
+            (None, _) => Ok(()),
+            (Some(expected), Some(password)) if expected == password => Ok(()),
+            (Some(_), Some(_)) => Err(format!("wrong password for {}", name)),
+            (Some(_), None) => Err(format!("{} requires a password", name)),
+        }
+    }
+
+    /// Add session to a room of this shard, whoever creates the room owns it
+    fn join_room(&mut self, id: usize, name: &str) {
+        if !self.rooms.contains_key(name) {
+            self.rooms.insert(
+                name.to_owned(),
+                Room {
+                    owner: Some(id),
+                    ..Room::default()
+                },
+            );
+            self.directory().add_room(name);
+        }
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.members.insert(id);
+        }
+
+        let msg = format!("{} joined", self.name(id));
+        self.send_message(name, &msg, Some(id));
+        self.replay(id, name);
+    }
 
-                // remove address
-                if self.sessions.remove(&id).is_some() {
-                    // remove session from all rooms
-                    for (name, sessions) in &mut self.rooms {
-                        if sessions.remove(&id) {
-                            rooms.push(name.to_owned());
-                        }
+    /// Room of the session, if the session owns it
+    fn owned_room(&self, id: usize) -> Result<String, String> {
+        let name = self.rooms_of(id).next().unwrap_or(MAIN_ROOM);
+        match self.rooms.get(name) {
+            Some(room) if room.owner == Some(id) => Ok(name.to_owned()),
+            _ => Err(format!("only the owner of {} can do that", name)),
+        }
+    }
+
+    /// Kick or ban a member of the session's room
+    fn moderate(&mut self, id: usize, name: &str, ban: bool) -> Result<(), String> {
+        let room = self.owned_room(id)?;
+        let target = self.directory().find(name);
+        if target == Some(id) {
+            return Err("you can not remove yourself".to_owned());
+        }
+        let member = target.filter(|target| self.rooms[&room].members.contains(target));
+        if member.is_none() && !ban {
+            return Err(format!("{} is not in {}", name, room));
+        }
+
+        if ban {
+            // banned sessions can not come back, even under another name
+            if let Some(r) = self.rooms.get_mut(&room) {
+                let nick = name.to_lowercase();
+                r.invited.remove(&nick);
+                r.banned_names.insert(nick);
+                r.banned.extend(target);
+            }
+        }
+        let action = if ban { "banned" } else { "kicked" };
+        match member {
+            Some(member) => self.expel(&room, member, action, id),
+            None => {
+                let msg = format!("{} is banned from {}", name, room);
+                self.send(id, ClientMessage::Notice(msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Move a member of the room back to the main room
+    fn expel(&mut self, name: &str, id: usize, action: &str, by: usize) {
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.members.remove(&id);
+        }
+        let msg = format!("{} was {} by {}", self.name(id), action, self.name(by));
+        self.send_message(name, &msg, Some(id));
+        let msg = format!("you were {} from {} by {}", action, name, self.name(by));
+        self.send(id, ClientMessage::Notice(msg));
+
+        let main = shard_of(MAIN_ROOM, self.shards.len());
+        if main == self.shard {
+            self.join_room(id, MAIN_ROOM);
+        } else if let Some(session) = self.sessions.remove(&id) {
+            // the main room admits everyone
+            self.directory().move_to(id, main);
+            let enter = ServerMessage::Enter {
+                id,
+                room: MAIN_ROOM.to_owned(),
+                password: None,
+                session,
+                from: None,
+            };
+            self.forward(main, id, enter);
+        }
+    }
+
+    /// Latest `count` messages of the room
+    fn recent(&self, room: &str, count: usize) -> Vec<Entry> {
+        self.history.recent(room, count).unwrap_or_else(|e| {
+            println!("Cannot load history of {}: {}", room, e);
+            Vec::new()
+        })
+    }
+
+    /// Send latest messages of the room to a session that joined it
+    fn replay(&self, id: usize, room: &str) {
+        let entries = self.recent(room, REPLAY_MESSAGES);
+        if !entries.is_empty() {
+            self.send(id, ClientMessage::History(entries));
+        }
+    }
+
+    /// Rooms the session is a member of
+    fn rooms_of(&self, id: usize) -> impl Iterator<Item = &str> {
+        self.rooms
+            .iter()
+            .filter(move |(_, room)| room.members.contains(&id))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Remove session, returns `false` if this shard does not hold it
+    fn disconnect(&mut self, id: usize) -> bool {
+        self.moving.remove(&id);
+        let session = match self.sessions.remove(&id) {
+            Some(session) => session,
+            None => return false,
+        };
+
+        // removing the address closes the session queue
+        self.directory().remove(id);
+        let rooms = self.leave_rooms(id);
+        println!("{} disconnected", session.name);
+        if session.addr.dropped() > 0 {
+            println!("{} lost {} messages", session.name, session.addr.dropped());
+        }
+
+        // send message to other users
+        let msg = format!("{} disconnected", session.name);
+        for room in rooms {
+            self.send_message(&room, &msg, Some(id));
+        }
+        true
+    }
+
+    /// Handler for server messages.
+    pub fn handle(&mut self, msg: ServerMessage) {
+        // sessions move between shards, requests that arrive late follow them
+        if let Some(shard) = self.elsewhere(&msg) {
+            let _ = self.shards[shard].unbounded_send(msg);
+            return;
+        }
+        self.dispatch(msg);
+
+        // notices about the disconnects can overflow other queues
+        loop {
+            let id = match self.lagging.borrow_mut().pop() {
+                Some(id) => id,
+                None => break,
+            };
+            if !self.disconnect(id) {
+                // direct message recipient on another shard
+                match self.directory().shard(id) {
+                    Some(shard) => {
+                        let _ = self.shards[shard]
+                            .unbounded_send(ServerMessage::Disconnect(id));
                     }
+                    None => continue,
                 }
-                // send message to other users
-                for room in rooms {
-                    self.send_message(&room, "Someone disconnected", 0);
+            }
+            self.shared
+                .metrics
+                .disconnected
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn dispatch(&mut self, msg: ServerMessage) {
+        match msg {
+            // Register new session and assign unique id to this session
+            ServerMessage::Connect(tx) => {
+                let (addr, rx) = queue::channel(self.shared.queue.capacity);
+                let (id, name, shard) =
+                    self.directory().register(addr.clone(), self.shard);
+                if tx.send(Connected { id, rx, shard }).is_err() {
+                    // connection is already closed
+                    self.directory().remove(id);
+                    return;
                 }
+                println!("{} joined", name);
+                self.sessions.insert(
+                    id,
+                    Session {
+                        addr,
+                        name: name.clone(),
+                    },
+                );
+
+                // send name back
+                self.send(id, ClientMessage::Nick(name));
+
+                // auto join session to Main room
+                self.join_room(id, MAIN_ROOM);
+            }
+
+            // Handler for Disconnect message.
+            ServerMessage::Disconnect(id) => {
+                self.disconnect(id);
             }
 
             // Handler for Message message.
-            ServerMessage::Message { id, msg, room } => {
-                self.send_message(&room, msg.as_str(), id);
+            ServerMessage::Message { id, msg } => {
+                // session could have been disconnected by the server
+                let room = match self.rooms_of(id).next() {
+                    Some(room) => room.to_owned(),
+                    None => return,
+                };
+                let entry = Entry {
+                    id: self.shared.last_message.fetch_add(1, Ordering::Relaxed) + 1,
+                    room,
+                    sender: self.name(id).to_owned(),
+                    text: msg,
+                    time: now(),
+                };
+                if let Err(e) = self.history.append(&entry) {
+                    println!("Cannot store message: {}", e);
+                }
+                self.broadcast(
+                    &entry.room,
+                    ClientMessage::Message(entry.clone()),
+                    Some(id),
+                );
             }
 
             // Handler for `ListRooms` message.
             ServerMessage::ListRooms(id) => {
-                let mut rooms = Vec::new();
+                let rooms = self.directory().rooms();
+                self.send(id, ClientMessage::Rooms(rooms));
+            }
+
+            // Join room, send leave message to old room
+            // send join message to new room
+            ServerMessage::Join { id, name, password } => {
+                let nick = match self.sessions.get(&id) {
+                    Some(session) => session.name.clone(),
+                    None => return,
+                };
+                if self.rooms_of(id).any(|room| room == name) {
+                    let error = format!("you are already in {}", name);
+                    self.send(id, ClientMessage::Error(error));
+                    return;
+                }
+                if self.moving.contains(&id) {
+                    let error = "you are already joining a room".to_owned();
+                    self.send(id, ClientMessage::Error(error));
+                    return;
+                }
 
-                for key in self.rooms.keys() {
-                    rooms.push(key.to_owned())
+                let shard = shard_of(&name, self.shards.len());
+                if shard != self.shard {
+                    // the shard of the room decides, the session stays here
+                    // until it is admitted
+                    let session = Session {
+                        addr: self.sessions[&id].addr.clone(),
+                        name: nick,
+                    };
+                    self.moving.insert(id);
+                    let enter = ServerMessage::Enter {
+                        id,
+                        room: name,
+                        password,
+                        session,
+                        from: Some(self.shard),
+                    };
+                    self.forward(shard, id, enter);
+                    return;
                 }
 
-                if let Some(addr) = self.sessions.get(&id) {
-                    let mut addr = addr.clone();
-                    rt::spawn(async move {
-                        let _ = addr.send(ClientMessage::Rooms(rooms)).await;
-                    });
+                if let Err(error) = self.admit(id, &nick, &name, password.as_deref()) {
+                    self.send(id, ClientMessage::Error(error));
+                    return;
+                }
+                let msg = format!("{} left", nick);
+                for room in self.leave_rooms(id) {
+                    self.send_message(&room, &msg, Some(id));
                 }
+                self.join_room(id, &name);
             }
 
-            // Join room, send disconnect message to old room
-            // send join message to new room
-            ServerMessage::Join { id, name } => {
-                let mut rooms = Vec::new();
+            // Session comes from another shard
+            ServerMessage::Enter {
+                id,
+                room,
+                password,
+                mut session,
+                from,
+            } => {
+                // the session could have been closed on the way, and renamed
+                match self.directory().get(id) {
+                    Some(member) => session.name = member.name.clone(),
+                    None => return,
+                }
+                let admitted = self.admit(id, &session.name, &room, password.as_deref());
+                if let Some(from) = from {
+                    let moved = ServerMessage::Moved {
+                        id,
+                        accepted: admitted.is_ok(),
+                    };
+                    let _ = self.shards[from].unbounded_send(moved);
+                }
+                if let Err(error) = admitted {
+                    self.send(id, ClientMessage::Error(error));
+                    return;
+                }
+                self.sessions.insert(id, session);
+                self.directory().move_to(id, self.shard);
+                self.join_room(id, &room);
+            }
+
+            // Another shard admitted the session, or not
+            ServerMessage::Moved { id, accepted } => {
+                if !self.moving.remove(&id) || !accepted {
+                    return;
+                }
+                if let Some(session) = self.sessions.remove(&id) {
+                    let msg = format!("{} left", session.name);
+                    for room in self.leave_rooms(id) {
+                        self.send_message(&room, &msg, Some(id));
+                    }
+                }
+            }
 
-                // remove session from all rooms
-                for (n, sessions) in &mut self.rooms {
-                    if sessions.remove(&id) {
-                        rooms.push(n.to_owned());
+            // Rename session, nicknames must stay unique
+            ServerMessage::Nick { id, name } => {
+                let old = match self.sessions.get(&id) {
+                    Some(session) => session.name.clone(),
+                    None => return,
+                };
+                if let Err(error) = self.directory().rename(id, &name) {
+                    self.send(id, ClientMessage::Error(error));
+                    return;
+                }
+                if let Some(session) = self.sessions.get_mut(&id) {
+                    session.name = name.clone();
+                }
+                self.send(id, ClientMessage::Nick(name.clone()));
+
+                if name != old {
+                    let msg = format!("{} is now known as {}", old, name);
+                    let rooms: Vec<String> =
+                        self.rooms_of(id).map(|r| r.to_owned()).collect();
+                    for room in rooms {
+                        self.send_message(&room, &msg, Some(id));
                     }
                 }
-                // send message to other users
+            }
+
+            // Nicknames of everyone in the session's room
+            ServerMessage::Who(id) => {
+                let mut members: Vec<String> = self
+                    .rooms_of(id)
+                    .flat_map(|room| &self.rooms[room].members)
+                    .map(|id| self.name(*id).to_owned())
+                    .collect();
+                members.sort();
+                self.send(id, ClientMessage::Members(members));
+            }
+
+            // Latest messages of the session's room
+            ServerMessage::History { id, count } => {
+                let rooms: Vec<String> =
+                    self.rooms_of(id).map(|r| r.to_owned()).collect();
                 for room in rooms {
-                    self.send_message(&room, "Someone disconnected", 0);
+                    self.send(id, ClientMessage::History(self.recent(&room, count)));
+                }
+            }
+
+            // Direct message, the recipient must be online
+            ServerMessage::Direct { id, to, msg } => {
+                if !self.sessions.contains_key(&id) {
+                    return;
+                }
+                let recipient = {
+                    let directory = self.directory();
+                    directory
+                        .find(&to)
+                        .and_then(|r| directory.get(r).map(|m| (r, m.name.clone())))
+                };
+                let (recipient, to) = match recipient {
+                    Some(recipient) => recipient,
+                    None => {
+                        self.send(
+                            id,
+                            ClientMessage::Error(format!("{} is offline", to)),
+                        );
+                        return;
+                    }
+                };
+                let dm = DirectMessage {
+                    id: self.shared.last_message.fetch_add(1, Ordering::Relaxed) + 1,
+                    from: self.name(id).to_owned(),
+                    to,
+                    text: msg,
+                    time: now(),
+                };
+                self.directory().expect_receipt(dm.id, id, recipient);
+                self.send(id, ClientMessage::Sent(dm.clone()));
+                self.send(recipient, ClientMessage::Direct(dm));
+            }
+
+            // Tell the sender its direct message was delivered
+            ServerMessage::Delivered { id, message } => {
+                let receipt = {
+                    let mut directory = self.directory();
+                    directory.receipt(message, id).and_then(|sender| {
+                        directory.get(id).map(|m| (sender, m.name.clone()))
+                    })
+                };
+                if let Some((sender, to)) = receipt {
+                    self.send(sender, ClientMessage::Delivered { id: message, to });
                 }
+            }
 
-                self.rooms
-                    .entry(name.clone())
-                    .or_insert_with(HashSet::new)
-                    .insert(id);
+            // Moderation of the session's room
+            ServerMessage::Kick { id, name } => {
+                if let Err(error) = self.moderate(id, &name, false) {
+                    self.send(id, ClientMessage::Error(error));
+                }
+            }
 
-                self.send_message(&name, "Someone connected", id);
+            ServerMessage::Ban { id, name } => {
+                if let Err(error) = self.moderate(id, &name, true) {
+                    self.send(id, ClientMessage::Error(error));
+                }
+            }
+
+            ServerMessage::Invite { id, name } => {
+                let room = match self.owned_room(id) {
+                    Ok(room) => room,
+                    Err(error) => {
+                        self.send(id, ClientMessage::Error(error));
+                        return;
+                    }
+                };
+                let target = self.directory().find(&name);
+                if let Some(r) = self.rooms.get_mut(&room) {
+                    // an invite lifts the ban
+                    r.banned_names.remove(&name.to_lowercase());
+                    if let Some(target) = target {
+                        r.banned.remove(&target);
+                    }
+                    r.invited.insert(name.to_lowercase());
+                }
+                if let Some(target) = target {
+                    let msg = format!("{} invited you to {}", self.name(id), room);
+                    self.send(target, ClientMessage::Notice(msg));
+                }
+                let msg = format!("{} is invited to {}", name, room);
+                self.send(id, ClientMessage::Notice(msg));
+            }
+
+            ServerMessage::Password { id, password } => {
+                let room = match self.owned_room(id) {
+                    Ok(room) => room,
+                    Err(error) => {
+                        self.send(id, ClientMessage::Error(error));
+                        return;
+                    }
+                };
+                let msg = match password {
+                    Some(_) => format!("{} is protected by a password", room),
+                    None => format!("{} is not protected by a password", room),
+                };
+                if let Some(r) = self.rooms.get_mut(&room) {
+                    r.password = password;
+                }
+                self.send(id, ClientMessage::Notice(msg));
+            }
+
+            ServerMessage::InviteOnly { id, on } => {
+                let room = match self.owned_room(id) {
+                    Ok(room) => room,
+                    Err(error) => {
+                        self.send(id, ClientMessage::Error(error));
+                        return;
+                    }
+                };
+                if let Some(r) = self.rooms.get_mut(&room) {
+                    r.invite_only = on;
+                }
+                let msg = match on {
+                    true => format!("{} is now invite-only", room),
+                    false => format!("{} is now open", room),
+                };
+                self.send_message(&room, &msg, None);
+            }
+
+            ServerMessage::Tagged { id, tag, msg } => {
+                self.reply = Some((id, tag));
+                self.dispatch(*msg);
+                self.reply = None;
             }
         }
     }
 }
 
-pub fn start() -> UnboundedSender<ServerMessage> {
-    let (tx, mut rx) = mpsc::unbounded();
+/// Start `shards` chat server shards, every one on its own arbiter
+///
+/// Shards share the history, the messages of a room stay in its bucket
+/// whatever the number of shards.
+///
+/// # Panics
+///
+/// If `shards` is 0.
+pub fn start(
+    shards: usize,
+    history: History,
+    queue: QueueConfig,
+    metrics: Arc<Metrics>,
+) -> Chat {
+    assert!(shards > 0, "chat server needs at least one shard");
+    let last_message = history.last_id().unwrap_or_else(|e| {
+        println!("Cannot load history: {}", e);
+        0
+    });
+    let shared = Arc::new(Shared::new(last_message, queue, metrics));
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        (0..shards).map(|_| mpsc::unbounded()).unzip();
+
+    for (shard, mut rx) in receivers.into_iter().enumerate() {
+        let shards = senders.clone();
+        let shared = shared.clone();
+        let history = history.clone();
+
+        // chat server is not `Send`, it gets created on the arbiter thread
+        let arbiter = rt::Arbiter::new();
+        arbiter.handle().spawn(async move {
+            rt::spawn(async move {
+                let mut srv = ChatServer::new(shard, shards, shared, history);
+
+                while let Some(msg) = rx.next().await {
+                    srv.handle(msg);
+                }
+
+                rt::Arbiter::current().stop();
+            });
+        });
+    }
+
+    Chat(Arc::new(Shards(senders)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+
+    fn server(queue: QueueConfig) -> ChatServer {
+        let history = History::memory(1, 100).unwrap();
+        let shared = Shared::new(0, queue, Arc::new(Metrics::default()));
+        let (tx, _) = mpsc::unbounded();
+        ChatServer::new(0, vec![tx], Arc::new(shared), history)
+    }
+
+    fn connect(srv: &mut ChatServer) -> (usize, queue::Receiver<ClientMessage>) {
+        let (tx, mut rx) = oneshot::channel();
+        srv.handle(ServerMessage::Connect(tx));
+        let connected = rx.try_recv().unwrap().unwrap();
+        (connected.id, connected.rx)
+    }
+
+    /// Shards of a chat server, messages between them are passed on by `settle`
+    struct Cluster {
+        shards: Vec<ChatServer>,
+        rx: Vec<mpsc::UnboundedReceiver<ServerMessage>>,
+    }
+
+    impl Cluster {
+        fn new(shards: usize) -> Self {
+            let metrics = Arc::new(Metrics::default());
+            let shared = Arc::new(Shared::new(0, QueueConfig::default(), metrics));
+            let history = History::memory(4, 100).unwrap();
+            let (tx, rx): (Vec<_>, Vec<_>) =
+                (0..shards).map(|_| mpsc::unbounded()).unzip();
+            let shards = (0..shards)
+                .map(|shard| {
+                    ChatServer::new(shard, tx.clone(), shared.clone(), history.clone())
+                })
+                .collect();
+            Cluster { shards, rx }
+        }
+
+        fn shard(&self, id: usize) -> Option<usize> {
+            self.shards[0].directory().shard(id)
+        }
 
-    rt::Arbiter::new().exec_fn(move || {
-        rt::spawn(async move {
-            let mut srv = ChatServer::default();
+        fn connect(&mut self) -> (usize, queue::Receiver<ClientMessage>) {
+            let main = shard_of(MAIN_ROOM, self.shards.len());
+            connect(&mut self.shards[main])
+        }
+
+        /// Handle request on the shard holding the session
+        fn handle(&mut self, msg: ServerMessage) {
+            let shard = msg.session().and_then(|id| self.shard(id)).unwrap_or(0);
+            self.shards[shard].handle(msg);
+            self.settle();
+        }
 
-            while let Some(msg) = rx.next().await {
-                srv.handle(msg);
+        /// Handle messages between the shards, until there are none
+        fn settle(&mut self) {
+            loop {
+                let mut idle = true;
+                for (srv, rx) in self.shards.iter_mut().zip(&mut self.rx) {
+                    while let Ok(msg) = rx.try_recv() {
+                        srv.handle(msg);
+                        idle = false;
+                    }
+                }
+                if idle {
+                    return;
+                }
             }
+        }
+    }
 
-            rt::Arbiter::current().stop();
+    fn received(rx: &mut queue::Receiver<ClientMessage>) -> Vec<ClientMessage> {
+        let mut messages = Vec::new();
+        while let Some(msg) = rx.try_recv() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    fn text(msg: &str) -> ClientMessage {
+        ClientMessage::Notice(msg.to_owned())
+    }
+
+    #[test]
+    fn test_nicknames() {
+        let mut srv = server(QueueConfig::default());
+        let (alice, mut alice_rx) = connect(&mut srv);
+        assert_eq!(
+            received(&mut alice_rx),
+            [ClientMessage::Nick("guest-1".into())]
+        );
+
+        let (bob, mut bob_rx) = connect(&mut srv);
+        assert_eq!(
+            received(&mut bob_rx),
+            [ClientMessage::Nick("guest-2".into())]
+        );
+        assert_eq!(received(&mut alice_rx), [text("guest-2 joined")]);
+
+        let nick = |id, name: &str| ServerMessage::Nick {
+            id,
+            name: name.to_owned(),
+        };
+        srv.handle(nick(alice, "alice"));
+        assert_eq!(
+            received(&mut alice_rx),
+            [ClientMessage::Nick("alice".into())]
+        );
+        assert_eq!(
+            received(&mut bob_rx),
+            [text("guest-1 is now known as alice")]
+        );
+
+        // names are unique, regardless of case
+        srv.handle(nick(bob, "Alice"));
+        assert_eq!(
+            received(&mut bob_rx),
+            [ClientMessage::Error(
+                "nickname Alice is already taken".into()
+            )]
+        );
+        srv.handle(nick(bob, "b o b"));
+        assert!(matches!(
+            &received(&mut bob_rx)[..],
+            [ClientMessage::Error(_)]
+        ));
+        srv.handle(nick(bob, "bob"));
+        assert_eq!(
+            received(&mut alice_rx),
+            [text("guest-2 is now known as bob")]
+        );
+        received(&mut bob_rx);
+
+        // guest names skip taken ones
+        srv.handle(nick(alice, "guest-3"));
+        received(&mut bob_rx);
+        let (_, mut rx) = connect(&mut srv);
+        assert_eq!(received(&mut rx), [ClientMessage::Nick("guest-4".into())]);
+        received(&mut alice_rx);
+        received(&mut bob_rx);
+
+        srv.handle(ServerMessage::Message {
+            id: bob,
+            msg: "hi".into(),
         });
-    });
+        match &received(&mut alice_rx)[..] {
+            [ClientMessage::Message(entry)] => {
+                assert_eq!((&*entry.sender, &*entry.text), ("bob", "hi"));
+                assert_eq!((entry.id, &*entry.room), (1, "Main"));
+            }
+            messages => panic!("unexpected messages: {:?}", messages),
+        }
+        assert!(received(&mut bob_rx).is_empty());
+    }
+
+    #[test]
+    fn test_presence() {
+        let mut srv = server(QueueConfig::default());
+        let (alice, mut alice_rx) = connect(&mut srv);
+        let (bob, mut bob_rx) = connect(&mut srv);
+        let (carol, _carol_rx) = connect(&mut srv);
+        received(&mut alice_rx);
+        received(&mut bob_rx);
+
+        srv.handle(ServerMessage::Who(alice));
+        assert_eq!(
+            received(&mut alice_rx),
+            [ClientMessage::Members(vec![
+                "guest-1".into(),
+                "guest-2".into(),
+                "guest-3".into()
+            ])]
+        );
+
+        srv.handle(ServerMessage::Join {
+            id: bob,
+            name: "Rust".into(),
+            password: None,
+        });
+        assert_eq!(received(&mut alice_rx), [text("guest-2 left")]);
+        srv.handle(ServerMessage::Who(bob));
+        assert_eq!(
+            received(&mut bob_rx),
+            [ClientMessage::Members(vec!["guest-2".into()])]
+        );
+
+        srv.handle(ServerMessage::Join {
+            id: alice,
+            name: "Rust".into(),
+            password: None,
+        });
+        assert_eq!(received(&mut bob_rx), [text("guest-1 joined")]);
+
+        srv.handle(ServerMessage::Disconnect(alice));
+        assert_eq!(received(&mut bob_rx), [text("guest-1 disconnected")]);
+
+        // the name is free again
+        srv.handle(ServerMessage::Nick {
+            id: carol,
+            name: "guest-1".into(),
+        });
+        srv.handle(ServerMessage::Who(bob));
+        assert_eq!(
+            received(&mut bob_rx),
+            [ClientMessage::Members(vec!["guest-2".into()])]
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let mut srv = server(QueueConfig::default());
+        let (alice, mut alice_rx) = connect(&mut srv);
+        for i in 0..REPLAY_MESSAGES + 5 {
+            srv.handle(ServerMessage::Message {
+                id: alice,
+                msg: i.to_string(),
+            });
+        }
+        received(&mut alice_rx);
+
+        // latest messages are replayed on join
+        let (bob, mut bob_rx) = connect(&mut srv);
+        let history = match &received(&mut bob_rx)[..] {
+            [ClientMessage::Nick(_), ClientMessage::History(history)] => history.clone(),
+            messages => panic!("unexpected messages: {:?}", messages),
+        };
+        assert_eq!(history.len(), REPLAY_MESSAGES);
+        assert_eq!(history[0].text, "5");
+        assert_eq!(history[0].id, 6);
+        assert_eq!(history[0].sender, "guest-1");
+
+        srv.handle(ServerMessage::Join {
+            id: bob,
+            name: "Rust".into(),
+            password: None,
+        });
+        assert!(received(&mut bob_rx).is_empty());
+        srv.handle(ServerMessage::Join {
+            id: bob,
+            name: "Main".into(),
+            password: None,
+        });
+        assert!(matches!(
+            &received(&mut bob_rx)[..],
+            [ClientMessage::History(h)] if h.len() == REPLAY_MESSAGES
+        ));
+
+        srv.handle(ServerMessage::History { id: bob, count: 50 });
+        match &received(&mut bob_rx)[..] {
+            [ClientMessage::History(history)] => {
+                assert_eq!(history.len(), REPLAY_MESSAGES + 5);
+                assert_eq!(history[0].text, "0");
+            }
+            messages => panic!("unexpected messages: {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn test_direct() {
+        let mut srv = server(QueueConfig::default());
+        let (alice, mut alice_rx) = connect(&mut srv);
+        let (bob, mut bob_rx) = connect(&mut srv);
+        received(&mut alice_rx);
+        received(&mut bob_rx);
+
+        let direct = |id, to: &str| ServerMessage::Direct {
+            id,
+            to: to.to_owned(),
+            msg: "psst".to_owned(),
+        };
+        srv.handle(direct(alice, "GUEST-2"));
+        let dm = match &received(&mut bob_rx)[..] {
+            [ClientMessage::Direct(dm)] => dm.clone(),
+            messages => panic!("unexpected messages: {:?}", messages),
+        };
+        assert_eq!(
+            (&*dm.from, &*dm.to, &*dm.text),
+            ("guest-1", "guest-2", "psst")
+        );
+        assert_eq!(received(&mut alice_rx), [ClientMessage::Sent(dm.clone())]);
+
+        // only the recipient acknowledges delivery, once
+        srv.handle(ServerMessage::Delivered {
+            id: alice,
+            message: dm.id,
+        });
+        assert!(received(&mut alice_rx).is_empty());
+        for _ in 0..2 {
+            srv.handle(ServerMessage::Delivered {
+                id: bob,
+                message: dm.id,
+            });
+        }
+        assert_eq!(
+            received(&mut alice_rx),
+            [ClientMessage::Delivered {
+                id: dm.id,
+                to: "guest-2".into()
+            }]
+        );
+
+        srv.handle(direct(alice, "carol"));
+        assert_eq!(
+            received(&mut alice_rx),
+            [ClientMessage::Error("carol is offline".into())]
+        );
+
+        // no receipt from a recipient that is gone
+        srv.handle(direct(alice, "guest-2"));
+        received(&mut alice_rx);
+        srv.handle(ServerMessage::Disconnect(bob));
+        assert!(srv.directory().pending_receipts() == 0);
+        srv.handle(direct(alice, "guest-2"));
+        assert_eq!(
+            received(&mut alice_rx),
+            [
+                ClientMessage::Notice("guest-2 disconnected".into()),
+                ClientMessage::Error("guest-2 is offline".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut srv = server(QueueConfig::default());
+        let (alice, mut alice_rx) = connect(&mut srv);
+        let (bob, mut bob_rx) = connect(&mut srv);
+        let (carol, mut carol_rx) = connect(&mut srv);
+        let join = |id, name: &str, password: Option<&str>| ServerMessage::Join {
+            id,
+            name: name.to_owned(),
+            password: password.map(str::to_owned),
+        };
+        let error = |msg: &str| ClientMessage::Error(msg.to_owned());
+
+        // creator owns the room
+        srv.handle(join(alice, "Rust", None));
+        srv.handle(ServerMessage::Password {
+            id: alice,
+            password: Some("crab".into()),
+        });
+        srv.handle(ServerMessage::Password {
+            id: bob,
+            password: None,
+        });
+        assert_eq!(
+            received(&mut bob_rx).pop().unwrap(),
+            error("only the owner of Main can do that")
+        );
+        srv.handle(join(bob, "Rust", None));
+        srv.handle(join(bob, "Rust", Some("shrimp")));
+        assert_eq!(
+            received(&mut bob_rx),
+            [
+                error("Rust requires a password"),
+                error("wrong password for Rust")
+            ]
+        );
+        srv.handle(join(bob, "Rust", Some("crab")));
+        srv.handle(join(carol, "Rust", Some("crab")));
+        received(&mut alice_rx);
+        received(&mut bob_rx);
+        received(&mut carol_rx);
+
+        // kicked members go back to the main room, banned ones can not return
+        srv.handle(ServerMessage::Kick {
+            id: bob,
+            name: "guest-1".into(),
+        });
+        assert_eq!(
+            received(&mut bob_rx),
+            [error("only the owner of Rust can do that")]
+        );
+        srv.handle(ServerMessage::Kick {
+            id: alice,
+            name: "guest-2".into(),
+        });
+        assert_eq!(
+            received(&mut bob_rx),
+            [text("you were kicked from Rust by guest-1")]
+        );
+        assert_eq!(
+            received(&mut carol_rx),
+            [text("guest-2 was kicked by guest-1")]
+        );
+        srv.handle(ServerMessage::Ban {
+            id: alice,
+            name: "guest-3".into(),
+        });
+        assert_eq!(
+            received(&mut carol_rx),
+            [text("you were banned from Rust by guest-1")]
+        );
+        srv.handle(ServerMessage::Nick {
+            id: carol,
+            name: "carol".into(),
+        });
+        srv.handle(join(carol, "Rust", Some("crab")));
+        assert_eq!(
+            received(&mut carol_rx).pop().unwrap(),
+            error("you are banned from Rust")
+        );
+
+        // invites get past the password and lift bans
+        srv.handle(ServerMessage::InviteOnly {
+            id: alice,
+            on: true,
+        });
+        srv.handle(join(bob, "Rust", Some("crab")));
+        assert_eq!(
+            received(&mut bob_rx).pop().unwrap(),
+            error("Rust is invite-only")
+        );
+        srv.handle(ServerMessage::Invite {
+            id: alice,
+            name: "guest-2".into(),
+        });
+        assert_eq!(received(&mut bob_rx), [text("guest-1 invited you to Rust")]);
+        srv.handle(join(bob, "Rust", None));
+        srv.handle(ServerMessage::Who(bob));
+        assert_eq!(
+            received(&mut bob_rx).pop().unwrap(),
+            ClientMessage::Members(vec!["guest-1".into(), "guest-2".into()])
+        );
+
+        // ownership passes on, empty rooms are removed
+        srv.handle(ServerMessage::Disconnect(alice));
+        assert_eq!(
+            received(&mut bob_rx),
+            [
+                text("you are now the owner of Rust"),
+                text("guest-1 disconnected")
+            ]
+        );
+        srv.handle(join(bob, "Main", None));
+        assert!(!srv.rooms.contains_key("Rust"));
+        srv.handle(join(carol, "Rust", None));
+        srv.handle(ServerMessage::Who(carol));
+        assert_eq!(
+            received(&mut carol_rx).pop().unwrap(),
+            ClientMessage::Members(vec!["carol".into()])
+        );
+        assert_eq!(srv.rooms[MAIN_ROOM].members.len(), 1);
+    }
+
+    #[test]
+    fn test_shards() {
+        let mut cluster = Cluster::new(2);
+        let main = shard_of(MAIN_ROOM, 2);
+        let other = 1 - main;
+        let room = (0..)
+            .map(|i| format!("room-{}", i))
+            .find(|room| shard_of(room, 2) == other)
+            .unwrap();
+        let join = |id, password: Option<&str>| ServerMessage::Join {
+            id,
+            name: room.clone(),
+            password: password.map(str::to_owned),
+        };
+        let (alice, mut alice_rx) = cluster.connect();
+        let (bob, mut bob_rx) = cluster.connect();
+        let (carol, mut carol_rx) = cluster.connect();
+        received(&mut alice_rx);
+        received(&mut bob_rx);
+        received(&mut carol_rx);
+
+        // sessions move to the shard of the room they join
+        cluster.handle(join(alice, None));
+        assert_eq!(cluster.shard(alice), Some(other));
+        assert!(!cluster.shards[main].sessions.contains_key(&alice));
+        assert_eq!(received(&mut bob_rx), [text("guest-1 left")]);
+        received(&mut carol_rx);
+        cluster.handle(ServerMessage::Password {
+            id: alice,
+            password: Some("crab".into()),
+        });
+        received(&mut alice_rx);
 
-    tx
+        // and stay where they are if the room does not admit them
+        cluster.handle(ServerMessage::Tagged {
+            id: bob,
+            tag: 5,
+            msg: Box::new(join(bob, None)),
+        });
+        assert_eq!(
+            received(&mut bob_rx),
+            [ClientMessage::Reply(
+                5,
+                Box::new(ClientMessage::Error(format!(
+                    "{} requires a password",
+                    room
+                )))
+            )]
+        );
+        assert_eq!(cluster.shard(bob), Some(main));
+        cluster.handle(join(bob, Some("crab")));
+        assert_eq!(received(&mut alice_rx), [text("guest-2 joined")]);
+        assert_eq!(received(&mut carol_rx), [text("guest-2 left")]);
+
+        // room messages do not lock the directory shared by the shards
+        let shared = cluster.shards[0].shared.clone();
+        let locks = &shared.locks;
+        let before = locks.load(Ordering::Relaxed);
+        cluster.shards[other].handle(ServerMessage::Message {
+            id: bob,
+            msg: "hi".into(),
+        });
+        assert_eq!(locks.load(Ordering::Relaxed), before);
+        match &received(&mut alice_rx)[..] {
+            [ClientMessage::Message(entry)] => assert_eq!(entry.room, room),
+            messages => panic!("unexpected messages: {:?}", messages),
+        }
+        cluster.handle(ServerMessage::ListRooms(carol));
+        assert_eq!(
+            received(&mut carol_rx),
+            [ClientMessage::Rooms(vec![MAIN_ROOM.into(), room.clone()])]
+        );
+
+        // direct messages and receipts cross shards
+        cluster.handle(ServerMessage::Direct {
+            id: alice,
+            to: "guest-3".into(),
+            msg: "psst".into(),
+        });
+        let dm = match &received(&mut carol_rx)[..] {
+            [ClientMessage::Direct(dm)] => dm.clone(),
+            messages => panic!("unexpected messages: {:?}", messages),
+        };
+        cluster.handle(ServerMessage::Delivered {
+            id: carol,
+            message: dm.id,
+        });
+        assert_eq!(
+            received(&mut alice_rx),
+            [
+                ClientMessage::Sent(dm.clone()),
+                ClientMessage::Delivered {
+                    id: dm.id,
+                    to: "guest-3".into()
+                }
+            ]
+        );
+
+        // kicked sessions go back to the shard of the main room
+        cluster.handle(ServerMessage::Kick {
+            id: alice,
+            name: "guest-2".into(),
+        });
+        assert_eq!(cluster.shard(bob), Some(main));
+        assert_eq!(received(&mut carol_rx), [text("guest-2 joined")]);
+
+        // requests that reach the old shard follow the session
+        cluster.shards[other].handle(ServerMessage::Who(bob));
+        cluster.settle();
+        assert_eq!(
+            received(&mut bob_rx).pop().unwrap(),
+            ClientMessage::Members(vec!["guest-2".into(), "guest-3".into()])
+        );
+
+        cluster.handle(ServerMessage::Disconnect(alice));
+        assert!(cluster.shards[other].rooms.is_empty());
+        cluster.handle(ServerMessage::ListRooms(carol));
+        assert_eq!(
+            received(&mut carol_rx),
+            [ClientMessage::Rooms(vec![MAIN_ROOM.into()])]
+        );
+    }
+
+    /// Room message throughput with 1, 2 and 4 shards, run with
+    /// `cargo test --release bench_throughput -- --ignored --nocapture`
+    ///
+    /// With 4 cpus or more, 4 shards must beat a single one.
+    #[ntex::test]
+    #[ignore]
+    async fn bench_throughput() {
+        const ROOMS: usize = 16;
+        const MEMBERS: usize = 4;
+        const MESSAGES: usize = 4000;
+
+        let mut rates = Vec::new();
+        for shards in [1, 2, 4] {
+            let history = History::memory(16, 100).unwrap();
+            let queue = QueueConfig {
+                capacity: MESSAGES,
+                overflow: Overflow::DropNew,
+            };
+            let metrics = Arc::new(Metrics::default());
+            let chat = start(shards, history, queue, metrics.clone());
+
+            let mut sessions = Vec::new();
+            for i in 0..ROOMS * MEMBERS {
+                let session = chat.connect().await.unwrap();
+                let name = format!("room-{}", i % ROOMS);
+                let shard = shard_of(&name, shards);
+                let id = session.id;
+                chat.send(
+                    &session.shard,
+                    ServerMessage::Join {
+                        id,
+                        name,
+                        password: None,
+                    },
+                );
+                sessions.push((session, shard));
+            }
+            while sessions
+                .iter()
+                .any(|(s, shard)| s.shard.load(Ordering::Acquire) != *shard)
+            {
+                ntex::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+
+            let queued = metrics.queued.load(Ordering::Relaxed);
+            let expected = queued + (MESSAGES * (MEMBERS - 1)) as u64;
+            let start = std::time::Instant::now();
+            for i in 0..MESSAGES {
+                let (session, _) = &sessions[i % ROOMS];
+                let msg = ServerMessage::Message {
+                    id: session.id,
+                    msg: i.to_string(),
+                };
+                chat.send(&session.shard, msg);
+            }
+            while metrics.queued.load(Ordering::Relaxed) < expected {
+                ntex::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            assert_eq!(metrics.dropped.load(Ordering::Relaxed), 0);
+            println!(
+                "{} shards: {:.0} messages/s, {:.0} deliveries/s",
+                shards,
+                MESSAGES as f64 / elapsed,
+                (MESSAGES * (MEMBERS - 1)) as f64 / elapsed
+            );
+            rates.push(MESSAGES as f64 / elapsed);
+        }
+
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if cpus >= 4 {
+            assert!(
+                rates[2] > rates[0],
+                "4 shards do not beat 1 on {} cpus: {:?}",
+                cpus,
+                rates
+            );
+        }
+    }
+
+    #[test]
+    fn test_overflow() {
+        let say = |id, msg: &str| ServerMessage::Message {
+            id,
+            msg: msg.to_owned(),
+        };
+        let texts = |messages: Vec<ClientMessage>| -> Vec<String> {
+            messages
+                .into_iter()
+                .map(|msg| match msg {
+                    ClientMessage::Message(entry) => entry.text,
+                    msg => panic!("unexpected message: {:?}", msg),
+                })
+                .collect()
+        };
+
+        for overflow in [Overflow::DropOldest, Overflow::DropNew] {
+            let mut srv = server(QueueConfig {
+                capacity: 2,
+                overflow,
+            });
+            let (alice, _alice_rx) = connect(&mut srv);
+            let (_, mut bob_rx) = connect(&mut srv);
+            received(&mut bob_rx);
+            for i in 0..4 {
+                srv.handle(say(alice, &i.to_string()));
+            }
+            let expected = match overflow {
+                Overflow::DropOldest => ["2", "3"],
+                _ => ["0", "1"],
+            };
+            assert_eq!(texts(received(&mut bob_rx)), expected);
+            assert_eq!(srv.shared.metrics.dropped.load(Ordering::Relaxed), 2);
+            assert_eq!(srv.shared.metrics.disconnected.load(Ordering::Relaxed), 0);
+        }
+
+        let mut srv = server(QueueConfig {
+            capacity: 2,
+            overflow: Overflow::Disconnect,
+        });
+        let (alice, mut alice_rx) = connect(&mut srv);
+        let (bob, mut bob_rx) = connect(&mut srv);
+        received(&mut alice_rx);
+        received(&mut bob_rx);
+        for i in 0..3 {
+            srv.handle(say(alice, &i.to_string()));
+        }
+        assert_eq!(texts(received(&mut bob_rx)), ["0", "1"]);
+        assert_eq!(srv.shared.metrics.disconnected.load(Ordering::Relaxed), 1);
+        assert_eq!(received(&mut alice_rx), [text("guest-2 disconnected")]);
+
+        // the queue is closed, later requests of the session are ignored
+        assert!(futures::executor::block_on(bob_rx.next()).is_none());
+        srv.handle(say(bob, "hi"));
+        srv.handle(ServerMessage::Disconnect(bob));
+        assert!(received(&mut alice_rx).is_empty());
+        assert_eq!(srv.shared.metrics.disconnected.load(Ordering::Relaxed), 1);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Connect,
+        Disconnect(Index),
+        /// Move the id counter back to the id of a live session, as if it
+        /// wrapped around
+        Rewind(Index),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            2 => Just(Op::Connect),
+            1 => any::<Index>().prop_map(Op::Disconnect),
+            1 => any::<Index>().prop_map(Op::Rewind),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_session_ids(
+            start in prop_oneof![Just(usize::MAX - 2), any::<usize>()],
+            ops in prop::collection::vec(op(), 1..50),
+        ) {
+            let mut srv = server(QueueConfig::default());
+            srv.directory().set_next_id(start);
+            let mut live: Vec<usize> = Vec::new();
+            for op in ops {
+                match op {
+                    Op::Connect => {
+                        let (id, _) = connect(&mut srv);
+                        prop_assert!(!live.contains(&id));
+                        live.push(id);
+                    }
+                    Op::Disconnect(index) if !live.is_empty() => {
+                        let id = live.remove(index.index(live.len()));
+                        srv.handle(ServerMessage::Disconnect(id));
+                    }
+                    Op::Rewind(index) if !live.is_empty() => {
+                        srv.directory().set_next_id(live[index.index(live.len())]);
+                    }
+                    _ => (),
+                }
+                prop_assert_eq!(srv.sessions.len(), live.len());
+            }
+        }
+    }
 }
@@ -21,8 +21,10 @@ async def start_client(url, loop):
             loop.stop()
         else:
             # Queue.put is a coroutine, so you can't call it directly.
-            asyncio.ensure_future(queue.put(ws.send_str(name + ': ' + line)))
+            msg = {'cmd': 'Message', 'data': line.rstrip('\n')}
+            asyncio.ensure_future(queue.put(ws.send_json(msg)))
 
+    await ws.send_json({'cmd': 'Name', 'data': name})
     loop.add_reader(sys.stdin, stdin_callback)
 
     async def dispatch():
@@ -21,6 +21,7 @@ async fn main() -> Result<(), std::io::Error> {
         .unwrap();
 
     println!("Tcp connection is established: {:?}", io);
+    let codec = ClientChatCodec::default();
 
     let (mut tx, mut rx) = mpsc::unbounded();
 
@@ -42,44 +43,9 @@ async fn main() -> Result<(), std::io::Error> {
     let ioref = io.get_ref();
     rt::spawn(async move {
         while let Some(msg) = rx.next().await {
-            if msg.starts_with('/') {
-                let v: Vec<&str> = msg.splitn(2, ' ').collect();
-                match v[0] {
-                    "/list" => {
-                        // Send ListRooms message to chat server and wait for
-                        // response
-                        println!("List rooms");
-                        ioref.encode(ChatRequest::List, &ClientChatCodec).unwrap();
-                    }
-                    "/join" => {
-                        if v.len() == 2 {
-                            let room = v[1].to_owned();
-                            ioref
-                                .encode(ChatRequest::Join(room), &ClientChatCodec)
-                                .unwrap();
-                        } else {
-                            println!("!!! room name is required")
-                        }
-                    }
-                    "/name" => {
-                        if v.len() == 2 {
-                            ioref
-                                .encode(
-                                    ChatRequest::Name(v[1].to_owned()),
-                                    &ClientChatCodec,
-                                )
-                                .unwrap();
-                        } else {
-                            println!("!!! name is required")
-                        }
-                    }
-                    _ => println!("!!! unknown command: {:?}", msg),
-                }
-            } else {
-                // send message to chat server
-                ioref
-                    .encode(ChatRequest::Message(msg), &ClientChatCodec)
-                    .unwrap();
+            match ChatRequest::parse(&msg) {
+                Ok(req) => ioref.encode(req, &codec).unwrap(),
+                Err(e) => println!("!!! {}", e),
             }
         }
     });
@@ -93,7 +59,7 @@ async fn main() -> Result<(), std::io::Error> {
             {
                 util::Either::Left(_) => {
                     // heartbeat
-                    let _ = ioref.encode(ChatRequest::Ping, &ClientChatCodec);
+                    let _ = ioref.encode(ChatRequest::Ping, &codec);
                 }
                 util::Either::Right(_) => {
                     println!("Connection is dropped, stop heartbeat task");
@@ -104,15 +70,16 @@ async fn main() -> Result<(), std::io::Error> {
     });
 
     // input dispatcher
-    loop {
-        match io.recv(&ClientChatCodec).await {
-            Ok(Some(msg)) => match msg {
-                ChatResponse::Ping => {}
-                ChatResponse::Rooms(rooms) => println!("Available rooms: {:?}", rooms),
-                ChatResponse::Joined(name) => println!("You joined {} room", name),
-                ChatResponse::Message(msg) => println!("{}", msg),
-            },
-            Err(_) | Ok(None) => break,
+    while let Ok(Some(msg)) = io.recv(&codec).await {
+        match msg {
+            ChatResponse::Ping => {}
+            ChatResponse::Rooms(rooms) => println!("Available rooms: {:?}", rooms),
+            ChatResponse::Joined(name) => println!("You joined {} room", name),
+            ChatResponse::Message { name, text } => {
+                println!("{}: {}", name.as_deref().unwrap_or("anonymous"), text)
+            }
+            ChatResponse::Notice(msg) => println!("*** {}", msg),
+            ChatResponse::Error(msg) => println!("!!! {}", msg),
         }
     }
     // stop heartbeat
@@ -1,8 +1,12 @@
 //! Simple websocket client.
-use std::{convert::TryFrom, io, thread, time::Duration};
+use std::{io, thread, time::Duration};
 
 use futures::{channel::mpsc, SinkExt, StreamExt};
-use ntex::{rt, time, util::ByteString, util::Bytes, ws, SharedCfg};
+use ntex::{rt, time, util::Bytes, ws, SharedCfg};
+use serde_json as json;
+
+mod codec;
+use self::codec::{ChatRequest, ChatResponse};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -33,9 +37,15 @@ async fn main() -> Result<(), io::Error> {
             return;
         }
 
-        // send text to server
-        if futures::executor::block_on(tx.send(ws::Message::Text(cmd.into()))).is_err() {
-            return;
+        // send request to server
+        match ChatRequest::parse(&cmd) {
+            Ok(req) => {
+                let msg = ws::Message::Text(json::to_string(&req).unwrap().into());
+                if futures::executor::block_on(tx.send(msg)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => println!("!!! {}", e),
         }
     });
 
@@ -66,9 +76,19 @@ async fn main() -> Result<(), io::Error> {
 
     while let Some(frame) = rx.next().await {
         match frame {
-            Ok(ws::Frame::Text(text)) => {
-                println!("Server: {}", ByteString::try_from(text).unwrap());
-            }
+            Ok(ws::Frame::Text(text)) => match json::from_slice(&text) {
+                Ok(ChatResponse::Ping) => (),
+                Ok(ChatResponse::Rooms(rooms)) => {
+                    println!("Available rooms: {:?}", rooms)
+                }
+                Ok(ChatResponse::Joined(name)) => println!("You joined {} room", name),
+                Ok(ChatResponse::Message { name, text }) => {
+                    println!("{}: {}", name.as_deref().unwrap_or("anonymous"), text)
+                }
+                Ok(ChatResponse::Notice(msg)) => println!("*** {}", msg),
+                Ok(ChatResponse::Error(msg)) => println!("!!! {}", msg),
+                Err(e) => println!("Invalid server message: {}", e),
+            },
             Ok(ws::Frame::Ping(msg)) => {
                 // send pong response
                 println!("Got server ping: {:?}", msg);
@@ -1,14 +1,53 @@
 #![allow(dead_code)]
-use std::io;
+//! Length prefixed JSON codecs of the tcp transport.
+//!
+//! Every frame is a big endian `u32` length followed by that many bytes of
+//! JSON. Frames larger than the max frame size of the codec are rejected, both
+//! when decoding and encoding.
+use std::{error, fmt};
 
 use byteorder::{BigEndian, ByteOrder};
 use ntex::codec::{Decoder, Encoder};
 use ntex::util::{BufMut, BytesMut};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json as json;
 
+/// Length of the frame size prefix
+const PREFIX_LEN: usize = 4;
+
+/// Default max frame size, 1 MiB
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// Frame is larger than the max frame size
+    FrameTooLarge { size: usize, max: usize },
+    /// Frame is not a valid message
+    Json(json::Error),
+}
+
+impl error::Error for CodecError {}
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::FrameTooLarge { size, max } => {
+                write!(f, "frame of {} bytes exceeds max frame size {}", size, max)
+            }
+            CodecError::Json(e) => write!(f, "invalid message: {}", e),
+        }
+    }
+}
+
+impl From<json::Error> for CodecError {
+    fn from(e: json::Error) -> Self {
+        CodecError::Json(e)
+    }
+}
+
 /// Client request
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Tcp clients send it length prefixed, websocket clients as a text frame.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(tag = "cmd", content = "data")]
 pub enum ChatRequest {
     /// List rooms
@@ -23,8 +62,31 @@ pub enum ChatRequest {
     Ping,
 }
 
+impl ChatRequest {
+    /// Parse line typed by the user, `/list`, `/join room`, `/name name` or
+    /// a message
+    pub fn parse(line: &str) -> Result<ChatRequest, String> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if !line.starts_with('/') {
+            return Ok(ChatRequest::Message(line.to_owned()));
+        }
+
+        let mut v = line.splitn(2, ' ');
+        let cmd = v.next().unwrap_or_default();
+        let arg = v.next().map(str::trim).filter(|arg| !arg.is_empty());
+        match (cmd, arg) {
+            ("/list", _) => Ok(ChatRequest::List),
+            ("/join", Some(room)) => Ok(ChatRequest::Join(room.to_owned())),
+            ("/join", None) => Err("room name is required".to_owned()),
+            ("/name", Some(name)) => Ok(ChatRequest::Name(name.to_owned())),
+            ("/name", None) => Err("name is required".to_owned()),
+            _ => Err(format!("unknown command: {:?}", line)),
+        }
+    }
+}
+
 /// Server response
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(tag = "cmd", content = "data")]
 pub enum ChatResponse {
     Ping,
@@ -35,88 +97,231 @@ pub enum ChatResponse {
     /// Joined
     Joined(String),
 
-    /// Message
-    Message(String),
+    /// Message of a room member, sessions without a name are anonymous
+    Message {
+        name: Option<String>,
+        text: String,
+    },
+
+    /// Someone joined or left the room
+    Notice(String),
+
+    /// Request is invalid
+    Error(String),
+}
+
+/// Decode frame, if the buffer holds all of it
+fn decode<T: DeserializeOwned>(
+    src: &mut BytesMut,
+    max: usize,
+) -> Result<Option<T>, CodecError> {
+    if src.len() < PREFIX_LEN {
+        return Ok(None);
+    }
+    let size = BigEndian::read_u32(src.as_ref()) as usize;
+    if size > max {
+        return Err(CodecError::FrameTooLarge { size, max });
+    }
+
+    if src.len() >= size + PREFIX_LEN {
+        let _ = src.split_to(PREFIX_LEN);
+        let buf = src.split_to(size);
+        Ok(Some(json::from_slice::<T>(&buf)?))
+    } else {
+        // make room for the rest of the frame
+        src.reserve(size + PREFIX_LEN - src.len());
+        Ok(None)
+    }
+}
+
+fn encode<T: Serialize>(
+    msg: &T,
+    max: usize,
+    dst: &mut BytesMut,
+) -> Result<(), CodecError> {
+    let msg = json::to_vec(msg)?;
+    if msg.len() > max {
+        return Err(CodecError::FrameTooLarge {
+            size: msg.len(),
+            max,
+        });
+    }
+    let msg_ref: &[u8] = msg.as_ref();
+
+    dst.reserve(msg_ref.len() + PREFIX_LEN);
+    dst.put_u32(msg_ref.len() as u32);
+    dst.put(msg_ref);
+
+    Ok(())
 }
 
 /// Codec for Client -> Server transport
-pub struct ChatCodec;
+#[derive(Clone, Copy, Debug)]
+pub struct ChatCodec {
+    max_frame_size: usize,
+}
+
+impl ChatCodec {
+    /// Codec for frames of up to `max_frame_size` bytes
+    pub fn new(max_frame_size: usize) -> Self {
+        ChatCodec {
+            max_frame_size: max_frame_size.min(u32::MAX as usize),
+        }
+    }
+}
+
+impl Default for ChatCodec {
+    fn default() -> Self {
+        ChatCodec::new(MAX_FRAME_SIZE)
+    }
+}
 
 impl Decoder for ChatCodec {
     type Item = ChatRequest;
-    type Error = io::Error;
+    type Error = CodecError;
 
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            let _ = src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatRequest>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        decode(src, self.max_frame_size)
     }
 }
 
 impl Encoder for ChatCodec {
     type Item = ChatResponse;
-    type Error = io::Error;
+    type Error = CodecError;
 
     fn encode(&self, msg: ChatResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+        encode(&msg, self.max_frame_size, dst)
+    }
+}
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
-        dst.put(msg_ref);
+/// Codec for Server -> Client transport
+#[derive(Clone, Copy, Debug)]
+pub struct ClientChatCodec {
+    max_frame_size: usize,
+}
 
-        Ok(())
+impl ClientChatCodec {
+    /// Codec for frames of up to `max_frame_size` bytes
+    pub fn new(max_frame_size: usize) -> Self {
+        ClientChatCodec {
+            max_frame_size: max_frame_size.min(u32::MAX as usize),
+        }
     }
 }
 
-/// Codec for Server -> Client transport
-pub struct ClientChatCodec;
+impl Default for ClientChatCodec {
+    fn default() -> Self {
+        ClientChatCodec::new(MAX_FRAME_SIZE)
+    }
+}
 
 impl Decoder for ClientChatCodec {
     type Item = ChatResponse;
-    type Error = io::Error;
+    type Error = CodecError;
 
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            let _ = src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatResponse>(&buf)?))
-        } else {
-            Ok(None)
-        }
+        decode(src, self.max_frame_size)
     }
 }
 
 impl Encoder for ClientChatCodec {
     type Item = ChatRequest;
-    type Error = io::Error;
+    type Error = CodecError;
 
     fn encode(&self, msg: ChatRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+        encode(&msg, self.max_frame_size, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn response() -> impl Strategy<Value = ChatResponse> {
+        // frames over 64 KiB, cheap to generate
+        let text = prop_oneof![".{0,32}", (0..200_000usize).prop_map(|n| "x".repeat(n))];
+        prop_oneof![
+            Just(ChatResponse::Ping),
+            prop::collection::vec(".{0,16}", 0..4).prop_map(ChatResponse::Rooms),
+            ".{0,16}".prop_map(ChatResponse::Joined),
+            (prop::option::of(".{0,16}"), text)
+                .prop_map(|(name, text)| ChatResponse::Message { name, text }),
+            ".{0,32}".prop_map(ChatResponse::Notice),
+        ]
+    }
+
+    proptest! {
+        /// Frames split at any points decode to the encoded messages
+        #[test]
+        fn test_partial_frames(
+            messages in prop::collection::vec(response(), 1..8),
+            chunks in prop::collection::vec(prop_oneof![1..8usize, 1..100_000usize], 1..32),
+        ) {
+            let mut buf = BytesMut::new();
+            for msg in &messages {
+                encode(msg, MAX_FRAME_SIZE, &mut buf).unwrap();
+            }
+
+            let codec = ClientChatCodec::default();
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            let mut chunks = chunks.into_iter().cycle();
+            while !buf.is_empty() {
+                let n = chunks.next().unwrap().min(buf.len());
+                src.extend_from_slice(&buf.split_to(n));
+                while let Some(msg) = codec.decode(&mut src).unwrap() {
+                    decoded.push(msg);
+                }
+            }
+            prop_assert_eq!(decoded, messages);
+            prop_assert!(src.is_empty());
+        }
+
+        /// Frames over the max frame size are rejected once the prefix is read
+        #[test]
+        fn test_oversized_frames(
+            max in 0..1024usize,
+            extra in 1..=u32::MAX as usize,
+            payload in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let size = (max + extra).min(u32::MAX as usize);
+            prop_assume!(size > max);
+            let mut src = BytesMut::new();
+            src.put_u32(size as u32);
+            src.extend_from_slice(&payload);
+
+            match ChatCodec::new(max).decode(&mut src) {
+                Err(CodecError::FrameTooLarge { size: s, max: m }) => {
+                    prop_assert_eq!((s, m), (size, max));
+                }
+                res => prop_assert!(false, "unexpected result: {:?}", res),
+            }
+        }
+
+        /// Decoding arbitrary bytes fails or waits for more, it never panics
+        #[test]
+        fn test_garbage(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let mut src = BytesMut::new();
+            src.extend_from_slice(&bytes);
+            let codec = ChatCodec::new(128);
+            while let Ok(Some(_)) = codec.decode(&mut src) {}
+        }
+    }
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
-        dst.put(msg_ref);
+    #[test]
+    fn test_encode_limit() {
+        let mut dst = BytesMut::new();
+        encode(&ChatRequest::Message("hi".into()), 32, &mut dst).unwrap();
+        assert_eq!(BigEndian::read_u32(&dst) as usize, dst.len() - PREFIX_LEN);
 
-        Ok(())
+        // oversized messages are not written
+        let mut dst = BytesMut::new();
+        let res = encode(&ChatRequest::Message("x".repeat(16)), 32, &mut dst);
+        assert!(matches!(
+            res,
+            Err(CodecError::FrameTooLarge { size: 43, max: 32 })
+        ));
+        assert!(dst.is_empty());
     }
 }
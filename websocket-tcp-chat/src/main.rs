@@ -1,5 +1,6 @@
 mod codec;
 mod server;
+mod session;
 mod tcp;
 mod web;
 
@@ -27,3 +28,132 @@ async fn main() -> std::io::Result<()> {
         .run()
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use ntex::{io::Io, ws, SharedCfg};
+    use serde_json as json;
+
+    use crate::codec::{ChatRequest, ChatResponse, ClientChatCodec};
+
+    /// Next tcp response, pings are skipped
+    async fn recv_tcp(io: &Io) -> ChatResponse {
+        loop {
+            match io.recv(&ClientChatCodec::default()).await {
+                Ok(Some(ChatResponse::Ping)) => continue,
+                Ok(Some(msg)) => return msg,
+                res => panic!("unexpected response: {:?}", res),
+            }
+        }
+    }
+
+    /// Next websocket response, pings are skipped
+    async fn recv_ws<E: std::fmt::Debug>(
+        rx: &mut (impl futures::Stream<Item = Result<ws::Frame, E>> + Unpin),
+    ) -> ChatResponse {
+        loop {
+            match rx.next().await {
+                Some(Ok(ws::Frame::Text(text))) => {
+                    match json::from_slice(&text).unwrap() {
+                        ChatResponse::Ping => continue,
+                        msg => return msg,
+                    }
+                }
+                Some(Ok(ws::Frame::Ping(_))) => continue,
+                frame => panic!("unexpected frame: {:?}", frame),
+            }
+        }
+    }
+
+    #[ntex::test]
+    async fn test_bridge() {
+        let server = server::start();
+        let tcp_srv = server.clone();
+        let tcp = ntex::server::test_server(async move || tcp::server(tcp_srv.clone()));
+        let web = ntex::server::test_server(async move || web::server(server.clone()));
+
+        let io = ntex::rt::tcp_connect(tcp.addr(), SharedCfg::default())
+            .await
+            .unwrap();
+        io.send(
+            ChatRequest::Name("alice".into()),
+            &ClientChatCodec::default(),
+        )
+        .await
+        .unwrap();
+        io.send(
+            ChatRequest::Join("Rust".into()),
+            &ClientChatCodec::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(recv_tcp(&io).await, ChatResponse::Joined("Rust".into()));
+
+        let con = ws::WsClient::builder(format!("http://{}/ws/", web.addr()))
+            .build(SharedCfg::default())
+            .await
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let (sink, mut rx) = (con.sink(), con.seal().receiver());
+        let send = async |req: ChatRequest| {
+            let msg = ws::Message::Text(json::to_string(&req).unwrap().into());
+            sink.send(msg).await.unwrap();
+        };
+        send(ChatRequest::Name("bob".into())).await;
+        send(ChatRequest::Join("Rust".into())).await;
+        assert_eq!(recv_ws(&mut rx).await, ChatResponse::Joined("Rust".into()));
+        assert_eq!(
+            recv_tcp(&io).await,
+            ChatResponse::Notice("Someone connected".into())
+        );
+
+        // messages cross transports with the name of the sender
+        send(ChatRequest::Message("hi".into())).await;
+        assert_eq!(
+            recv_tcp(&io).await,
+            ChatResponse::Message {
+                name: Some("bob".into()),
+                text: "hi".into()
+            }
+        );
+        io.send(
+            ChatRequest::Message("hello".into()),
+            &ClientChatCodec::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            recv_ws(&mut rx).await,
+            ChatResponse::Message {
+                name: Some("alice".into()),
+                text: "hello".into()
+            }
+        );
+
+        send(ChatRequest::List).await;
+        match recv_ws(&mut rx).await {
+            ChatResponse::Rooms(mut rooms) => {
+                rooms.sort();
+                assert_eq!(rooms, ["Main", "Rust"]);
+            }
+            msg => panic!("unexpected response: {:?}", msg),
+        }
+
+        // requests must be json
+        sink.send(ws::Message::Text("/list".into())).await.unwrap();
+        match recv_ws(&mut rx).await {
+            ChatResponse::Error(e) => assert!(e.starts_with("invalid request")),
+            msg => panic!("unexpected response: {:?}", msg),
+        }
+
+        drop(io);
+        assert_eq!(
+            recv_ws(&mut rx).await,
+            ChatResponse::Notice("Someone disconnected".into())
+        );
+    }
+}
@@ -3,18 +3,19 @@
 //! room through `ChatServer`.
 
 use futures::channel::mpsc::{self, UnboundedSender};
-use futures::{SinkExt, StreamExt};
+use futures::StreamExt;
 use rand::{self, rngs::ThreadRng, Rng};
 
 use ntex::rt;
 use ntex::util::{HashMap, HashSet};
 
+use crate::codec::ChatResponse;
+
 /// Chat server sends this messages to session
 #[derive(Debug)]
 pub enum ClientMessage {
     Id(usize),
-    Message(String),
-    Rooms(Vec<String>),
+    Response(ChatResponse),
 }
 
 /// Message for chat server communications
@@ -27,6 +28,8 @@ pub enum ServerMessage {
     Message {
         /// Id of the client session
         id: usize,
+        /// Name of the peer, if it set one
+        name: Option<String>,
         /// Peer message
         msg: String,
         /// Room name
@@ -66,18 +69,21 @@ impl Default for ChatServer {
 }
 
 impl ChatServer {
+    /// Send response to the session
+    ///
+    /// Channels are unbounded, sending right away keeps responses in order.
+    fn send(&self, id: usize, msg: ChatResponse) {
+        if let Some(addr) = self.sessions.get(&id) {
+            let _ = addr.unbounded_send(ClientMessage::Response(msg));
+        }
+    }
+
     /// Send message to all users in the room
-    fn send_message(&mut self, room: &str, message: &str, skip_id: usize) {
+    fn send_message(&self, room: &str, message: ChatResponse, skip_id: usize) {
         if let Some(sessions) = self.rooms.get(room) {
             for id in sessions {
                 if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        let msg = message.to_owned();
-                        let mut addr = addr.clone();
-                        rt::spawn(async move {
-                            let _ = addr.send(ClientMessage::Message(msg)).await;
-                        });
-                    }
+                    self.send(*id, message.clone());
                 }
             }
         }
@@ -87,11 +93,11 @@ impl ChatServer {
     fn handle(&mut self, msg: ServerMessage) {
         match msg {
             // Register new session and assign unique id to this session
-            ServerMessage::Connect(mut sender) => {
+            ServerMessage::Connect(sender) => {
                 println!("Someone joined");
 
                 // notify all users in same room
-                self.send_message("Main", "Someone joined", 0);
+                self.send_message("Main", notice("Someone joined"), 0);
 
                 // register session with random id
                 let id = self.rng.gen::<usize>();
@@ -101,9 +107,7 @@ impl ChatServer {
                 self.rooms.entry("Main".to_owned()).or_default().insert(id);
 
                 // send id back
-                rt::spawn(async move {
-                    let _ = sender.send(ClientMessage::Id(id)).await;
-                });
+                let _ = sender.unbounded_send(ClientMessage::Id(id));
             }
 
             // Handler for Disconnect message.
@@ -123,13 +127,19 @@ impl ChatServer {
                 }
                 // send message to other users
                 for room in rooms {
-                    self.send_message(&room, "Someone disconnected", 0);
+                    self.send_message(&room, notice("Someone disconnected"), 0);
                 }
             }
 
             // Handler for Message message.
-            ServerMessage::Message { id, msg, room } => {
-                self.send_message(&room, msg.as_str(), id);
+            ServerMessage::Message {
+                id,
+                name,
+                msg,
+                room,
+            } => {
+                let msg = ChatResponse::Message { name, text: msg };
+                self.send_message(&room, msg, id);
             }
 
             // Handler for `ListRooms` message.
@@ -140,12 +150,7 @@ impl ChatServer {
                     rooms.push(key.to_owned())
                 }
 
-                if let Some(addr) = self.sessions.get(&id) {
-                    let mut addr = addr.clone();
-                    rt::spawn(async move {
-                        let _ = addr.send(ClientMessage::Rooms(rooms)).await;
-                    });
-                }
+                self.send(id, ChatResponse::Rooms(rooms));
             }
 
             // Join room, send disconnect message to old room
@@ -161,21 +166,28 @@ impl ChatServer {
                 }
                 // send message to other users
                 for room in rooms {
-                    self.send_message(&room, "Someone disconnected", 0);
+                    self.send_message(&room, notice("Someone disconnected"), 0);
                 }
 
                 self.rooms.entry(name.clone()).or_default().insert(id);
 
-                self.send_message(&name, "Someone connected", id);
+                self.send_message(&name, notice("Someone connected"), id);
+                self.send(id, ChatResponse::Joined(name));
             }
         }
     }
 }
 
+fn notice(text: &str) -> ChatResponse {
+    ChatResponse::Notice(text.to_owned())
+}
+
 pub fn start() -> UnboundedSender<ServerMessage> {
     let (tx, mut rx) = mpsc::unbounded();
 
-    rt::Arbiter::new().exec_fn(move || {
+    // chat server is not `Send`, it gets created on the arbiter thread
+    let arbiter = rt::Arbiter::new();
+    arbiter.handle().spawn(async move {
         rt::spawn(async move {
             let mut srv = ChatServer::default();
 
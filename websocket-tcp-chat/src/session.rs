@@ -0,0 +1,100 @@
+//! Chat session, shared by tcp and websocket connections.
+//!
+//! Both transports carry the same `ChatRequest`/`ChatResponse` messages, the
+//! session turns requests into chat server messages.
+use std::time::Instant;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+
+use crate::codec::{ChatRequest, ChatResponse};
+use crate::server::{ClientMessage, ServerMessage};
+
+pub struct ChatSession {
+    /// unique session id
+    pub id: usize,
+    /// Client must send ping at least once per 10 seconds, otherwise we drop
+    /// connection.
+    pub hb: Instant,
+    /// joined room
+    room: String,
+    /// peer name
+    name: Option<String>,
+    /// server connection
+    server: UnboundedSender<ServerMessage>,
+}
+
+impl ChatSession {
+    /// Register session in chat server, returns the session and its messages
+    pub async fn connect(
+        server: UnboundedSender<ServerMessage>,
+    ) -> (ChatSession, UnboundedReceiver<ClientMessage>) {
+        let (tx, mut rx) = mpsc::unbounded();
+        server.unbounded_send(ServerMessage::Connect(tx)).unwrap();
+
+        // read first message from server, it should contain session id
+        let id = if let Some(ClientMessage::Id(id)) = rx.next().await {
+            id
+        } else {
+            panic!();
+        };
+
+        let session = ChatSession {
+            id,
+            hb: Instant::now(),
+            room: "Main".to_owned(),
+            name: None,
+            server,
+        };
+        (session, rx)
+    }
+
+    /// Notify chat server
+    pub fn send(&self, msg: ServerMessage) {
+        let _ = self.server.unbounded_send(msg);
+    }
+
+    /// Handle client request, returns response to send back right away
+    pub fn handle(&mut self, req: ChatRequest) -> Option<ChatResponse> {
+        match req {
+            ChatRequest::List => {
+                println!("List rooms");
+                self.send(ServerMessage::ListRooms(self.id));
+                None
+            }
+            // chat server acknowledges with `Joined`
+            ChatRequest::Join(room) => {
+                self.room.clone_from(&room);
+                self.send(ServerMessage::Join {
+                    id: self.id,
+                    name: room,
+                });
+                None
+            }
+            ChatRequest::Name(name) => {
+                self.name = Some(name);
+                None
+            }
+            ChatRequest::Message(msg) => {
+                self.send(ServerMessage::Message {
+                    id: self.id,
+                    name: self.name.clone(),
+                    msg,
+                    room: self.room.clone(),
+                });
+                None
+            }
+            ChatRequest::Ping => {
+                self.hb = Instant::now();
+                Some(ChatResponse::Ping)
+            }
+        }
+    }
+}
+
+impl Drop for ChatSession {
+    fn drop(&mut self) {
+        // notify chat server
+        self.send(ServerMessage::Disconnect(self.id));
+    }
+}
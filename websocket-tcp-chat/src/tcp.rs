@@ -1,53 +1,35 @@
 //! chat tcp server
 use std::{cell::RefCell, rc::Rc, time::Duration, time::Instant};
 
-use futures::channel::mpsc::UnboundedSender;
-use futures::{channel::mpsc, SinkExt, StreamExt};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::StreamExt;
 use ntex::service::{cfg::SharedCfg, fn_service, ServiceFactory};
 use ntex::{channel::oneshot, io::Io, io::IoRef, rt, time, util};
 
-use crate::codec::{ChatCodec, ChatRequest, ChatResponse};
+use crate::codec::{ChatCodec, ChatResponse};
 use crate::server::{ClientMessage, ServerMessage};
+use crate::session::ChatSession;
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// `ChatSession` actor is responsible for tcp peer communications.
-struct ChatSession {
-    /// unique session id
-    id: usize,
-    /// Client must send ping at least once per 10 seconds, otherwise we drop
-    /// connection.
-    hb: Instant,
-    /// joined room
-    room: String,
-    /// peer name
-    name: Option<String>,
-    /// server connectino
-    server: mpsc::UnboundedSender<ServerMessage>,
-}
-
-impl Drop for ChatSession {
-    fn drop(&mut self) {
-        // notify chat server
-        let _ = self.server.send(ServerMessage::Disconnect(self.id));
-    }
-}
-
 /// Handle messages from chat server, we simply send it to the peer tcp connection
-async fn messages(sink: IoRef, mut server: mpsc::UnboundedReceiver<ClientMessage>) {
+async fn messages(
+    sink: IoRef,
+    codec: ChatCodec,
+    mut server: mpsc::UnboundedReceiver<ClientMessage>,
+) {
     while let Some(msg) = server.next().await {
         println!("GOT chat server message: {:?}", msg);
         match msg {
             ClientMessage::Id(_) => (),
-            ClientMessage::Message(text) => {
-                sink.encode(ChatResponse::Message(text), &ChatCodec)
-                    .unwrap();
-            }
-            ClientMessage::Rooms(rooms) => {
-                sink.encode(ChatResponse::Rooms(rooms), &ChatCodec).unwrap();
+            ClientMessage::Response(msg) => {
+                // the peer would reject oversized frames, skip them
+                if let Err(e) = sink.encode(msg, &codec) {
+                    println!("Cannot send chat server message: {}", e);
+                }
             }
         }
     }
@@ -59,6 +41,7 @@ async fn messages(sink: IoRef, mut server: mpsc::UnboundedReceiver<ClientMessage
 async fn heartbeat(
     state: Rc<RefCell<ChatSession>>,
     sink: IoRef,
+    codec: ChatCodec,
     mut rx: oneshot::Receiver<()>,
 ) {
     loop {
@@ -74,7 +57,7 @@ async fn heartbeat(
                     return;
                 } else {
                     // send ping
-                    let _ = sink.encode(ChatResponse::Ping, &ChatCodec);
+                    let _ = sink.encode(ChatResponse::Ping, &codec);
                 }
             }
             util::Either::Right(_) => {
@@ -90,75 +73,25 @@ pub fn server(
     server: UnboundedSender<ServerMessage>,
 ) -> impl ServiceFactory<Io, SharedCfg, Response = (), Error = (), InitError = ()> {
     fn_service(move |io: Io| {
-        let mut server = server.clone();
+        let server = server.clone();
         async move {
-            let (tx, mut rx) = mpsc::unbounded();
-
-            // register self in chat server.
-            server.send(ServerMessage::Connect(tx)).await.unwrap();
-
-            // read first message from server, it shoould contain session id
-            let id = if let Some(ClientMessage::Id(id)) = rx.next().await {
-                id
-            } else {
-                panic!();
-            };
-
-            // create chat session
-            let state = Rc::new(RefCell::new(ChatSession {
-                id,
-                hb: Instant::now(),
-                server: server.clone(),
-                room: "Main".to_owned(),
-                name: None,
-            }));
+            // register self in chat server, create chat session
+            let (session, rx) = ChatSession::connect(server).await;
+            let state = Rc::new(RefCell::new(session));
+            let codec = ChatCodec::default();
 
             // start server messages handler, it reads chat messages and sends to the peer
-            rt::spawn(messages(io.get_ref(), rx));
+            rt::spawn(messages(io.get_ref(), codec, rx));
 
             // start heartbeat task
             let (tx, rx) = oneshot::channel();
-            rt::spawn(heartbeat(state.clone(), io.get_ref(), rx));
+            rt::spawn(heartbeat(state.clone(), io.get_ref(), codec, rx));
 
             loop {
-                match io.recv(&ChatCodec).await {
+                match io.recv(&codec).await {
                     Ok(Some(msg)) => {
-                        match msg {
-                            ChatRequest::List => {
-                                // Send ListRooms message to chat server and wait for
-                                // response
-                                println!("List rooms");
-                                let mut srv = server.clone();
-                                rt::spawn(async move {
-                                    let _ = srv.send(ServerMessage::ListRooms(id)).await;
-                                });
-                            }
-                            ChatRequest::Join(room) => {
-                                state.borrow_mut().room.clone_from(&room);
-                                let mut srv = server.clone();
-                                rt::spawn(async move {
-                                    let _ = srv
-                                        .send(ServerMessage::Join { id, name: room })
-                                        .await;
-                                });
-                            }
-                            ChatRequest::Name(name) => {
-                                state.borrow_mut().name = Some(name);
-                            }
-                            ChatRequest::Message(msg) => {
-                                // send message to chat server
-                                let mut srv = server.clone();
-                                let msg = ServerMessage::Message {
-                                    id,
-                                    msg,
-                                    room: state.borrow().room.clone(),
-                                };
-                                rt::spawn(async move { srv.send(msg).await });
-                            }
-                            ChatRequest::Ping => {
-                                state.borrow_mut().hb = Instant::now();
-                                let _ = io.encode(ChatResponse::Ping, &ChatCodec);
-                            }
+                        if let Some(res) = state.borrow_mut().handle(msg) {
+                            let _ = io.encode(res, &codec);
                         }
                     }
                     Ok(None) => {
@@ -1,19 +1,20 @@
 use std::{cell::RefCell, io, rc::Rc, time::Duration, time::Instant};
 
 use futures::channel::mpsc::{self, UnboundedSender};
-use futures::{future::ready, SinkExt, StreamExt};
+use futures::{future::ready, StreamExt};
 
 use ntex::service::{
     cfg::SharedCfg, chain, fn_factory_with_config, fn_service, fn_shutdown, map_config,
     Service, ServiceFactory,
 };
 use ntex::web::{self, ws, App, Error, HttpRequest, HttpResponse};
-use ntex::{
-    channel::oneshot, http, io::Io, rt, time, util, util::ByteString, util::Bytes,
-};
+use ntex::{channel::oneshot, http, io::Io, rt, time, util, util::Bytes};
 use ntex_files as fs;
+use serde_json as json;
 
+use super::codec::{ChatRequest, ChatResponse};
 use super::server::{ClientMessage, ServerMessage};
+use super::session::ChatSession;
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -37,61 +38,23 @@ async fn chat_route(
     .await
 }
 
-struct WsChatSession {
-    /// unique session id
-    id: usize,
-    /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
-    /// otherwise we drop connection.
-    hb: Instant,
-    /// joined room
-    room: String,
-    /// peer name
-    name: Option<String>,
-    /// server connectino
-    server: mpsc::UnboundedSender<ServerMessage>,
-}
-
-impl Drop for WsChatSession {
-    fn drop(&mut self) {
-        // notify chat server
-        let _ = self.server.send(ServerMessage::Disconnect(self.id));
-    }
-}
-
 /// WebSockets service factory
 async fn ws_service(
-    (sink, mut server): (ws::WsSink, mpsc::UnboundedSender<ServerMessage>),
+    (sink, server): (ws::WsSink, mpsc::UnboundedSender<ServerMessage>),
 ) -> Result<
     impl Service<ws::Frame, Response = Option<ws::Message>, Error = io::Error>,
     web::Error,
 > {
-    let (tx, mut rx) = mpsc::unbounded();
-
-    // register self in chat server.
-    server.send(ServerMessage::Connect(tx)).await.unwrap();
-
-    // read first message from server, it shoould contain session id
-    let id = if let Some(ClientMessage::Id(id)) = rx.next().await {
-        id
-    } else {
-        panic!();
-    };
-
-    // create chat session
-    let state = Rc::new(RefCell::new(WsChatSession {
-        id,
-        hb: Instant::now(),
-        server: server.clone(),
-        room: "Main".to_owned(),
-        name: None,
-    }));
+    // register self in chat server, create chat session
+    let (session, rx) = ChatSession::connect(server).await;
+    let state = Rc::new(RefCell::new(session));
 
     // start server messages handler, it reads chat messages and sends to the peer
     rt::spawn(messages(sink.clone(), rx));
 
     // start heartbeat task
     let (tx, rx) = oneshot::channel();
-    rt::spawn(heartbeat(state.clone(), sink.clone(), server.clone(), rx));
+    rt::spawn(heartbeat(state.clone(), sink.clone(), rx));
 
     // handler service for incoming websockets frames
     let service = fn_service(move |frame| {
@@ -107,70 +70,15 @@ async fn ws_service(
                 state.borrow_mut().hb = Instant::now();
                 None
             }
+            // text frames carry json encoded requests
             ws::Frame::Text(text) => {
-                let m = String::from_utf8(Vec::from(&text[..])).unwrap();
-
-                // we check for `/sss` type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            let mut srv = server.clone();
-                            rt::spawn(async move {
-                                let _ = srv.send(ServerMessage::ListRooms(id)).await;
-                            });
-                            None
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                let room = v[1].to_owned();
-                                state.borrow_mut().room.clone_from(&room);
-                                let mut srv = server.clone();
-                                rt::spawn(async move {
-                                    let _ = srv
-                                        .send(ServerMessage::Join { id, name: room })
-                                        .await;
-                                });
-                                None
-                            } else {
-                                Some(ws::Message::Text(ByteString::from_static(
-                                    "!!! room name is required",
-                                )))
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                state.borrow_mut().name = Some(v[1].to_owned());
-                                None
-                            } else {
-                                Some(ws::Message::Text(ByteString::from_static(
-                                    "!!! name is required",
-                                )))
-                            }
-                        }
-                        _ => Some(ws::Message::Text(
-                            format!("!!! unknown command: {:?}", m).into(),
-                        )),
+                let res = match json::from_slice::<ChatRequest>(&text) {
+                    Ok(req) => state.borrow_mut().handle(req),
+                    Err(e) => {
+                        Some(ChatResponse::Error(format!("invalid request: {}", e)))
                     }
-                } else {
-                    let msg = if let Some(ref name) = state.borrow().name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m
-                    };
-                    // send message to chat server
-                    let mut srv = server.clone();
-                    let msg = ServerMessage::Message {
-                        id,
-                        msg,
-                        room: state.borrow().room.clone(),
-                    };
-                    rt::spawn(async move { srv.send(msg).await });
-                    None
-                }
+                };
+                res.map(encode)
             }
             ws::Frame::Binary(_) => None,
             ws::Frame::Close(reason) => Some(ws::Message::Close(reason)),
@@ -188,19 +96,19 @@ async fn ws_service(
     Ok(chain(service).and_then(on_shutdown))
 }
 
+/// Json text frame of the response
+fn encode(msg: ChatResponse) -> ws::Message {
+    ws::Message::Text(json::to_string(&msg).unwrap().into())
+}
+
 /// Handle messages from chat server, we simply send it to the peer websocket connection
 async fn messages(sink: ws::WsSink, mut server: mpsc::UnboundedReceiver<ClientMessage>) {
     while let Some(msg) = server.next().await {
         println!("GOT chat server message: {:?}", msg);
         match msg {
             ClientMessage::Id(_) => (),
-            ClientMessage::Message(text) => {
-                let _ = sink.send(ws::Message::Text(text.into())).await;
-            }
-            ClientMessage::Rooms(rooms) => {
-                for room in rooms {
-                    let _ = sink.send(ws::Message::Text(room.into())).await;
-                }
+            ClientMessage::Response(msg) => {
+                let _ = sink.send(encode(msg)).await;
             }
         }
     }
@@ -210,9 +118,8 @@ async fn messages(sink: ws::WsSink, mut server: mpsc::UnboundedReceiver<ClientMe
 ///
 /// also this method checks heartbeats from client
 async fn heartbeat(
-    state: Rc<RefCell<WsChatSession>>,
+    state: Rc<RefCell<ChatSession>>,
     sink: ws::WsSink,
-    mut server: mpsc::UnboundedSender<ServerMessage>,
     mut rx: oneshot::Receiver<()>,
 ) {
     loop {
@@ -224,7 +131,8 @@ async fn heartbeat(
                     println!("Websocket Client heartbeat failed, disconnecting!");
 
                     // notify chat server
-                    let _ = server.send(ServerMessage::Disconnect(state.borrow().id));
+                    let state = state.borrow();
+                    state.send(ServerMessage::Disconnect(state.id));
 
                     // disconnect connection
                     // let _ = sink.close();